@@ -5,6 +5,39 @@ out vec4 out_Color;
 
 uniform sampler2D _Tex;
 
+// Keep in sync with tonemap::ToneMap
+uniform float _Exposure;
+uniform int _Operator; // 0: clamp, 1: reinhard, 2: aces
+uniform bool _Srgb;
+
+float luminance(vec3 c) {
+    return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+vec3 srgb_oetf(vec3 c) {
+    vec3 lo = 12.92 * c;
+    vec3 hi = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(hi, lo, lessThanEqual(c, vec3(0.0031308)));
+}
+
 void main() {
-    out_Color = vec4(texture2D(_Tex, fs_TexCoord).rgb, 1.0);
+    vec3 color = texture(_Tex, fs_TexCoord).rgb * exp2(_Exposure);
+
+    if (_Operator == 1) {
+        float lum = luminance(color);
+        if (lum > 0.0) {
+            color /= 1.0 + lum;
+        }
+    } else if (_Operator == 2) {
+        color = max(color, vec3(0.0));
+        color = (color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14);
+    }
+
+    color = clamp(color, 0.0, 1.0);
+
+    if (_Srgb) {
+        color = srgb_oetf(color);
+    }
+
+    out_Color = vec4(color, 1.0);
 }
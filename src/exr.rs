@@ -0,0 +1,653 @@
+//! A minimal OpenEXR codec. Renders are written as single
+//! part, scanline, uncompressed images with any number of RGB
+//! layers, and textures and environment maps are read from
+//! the same kind of files, also ZIP compressed.
+
+use std::io::{Read, Write};
+
+use vek::rgb::Rgb;
+
+/// How the channels are stored in the file.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelType {
+    Half,
+    Float,
+}
+
+impl PixelType {
+    fn id(self) -> i32 {
+        match self {
+            PixelType::Half => 1,
+            PixelType::Float => 2,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PixelType::Half => 2,
+            PixelType::Float => 4,
+        }
+    }
+}
+
+// Values of the `compression` attribute
+const NO_COMPRESSION: u8 = 0;
+const ZIPS_COMPRESSION: u8 = 2;
+const ZIP_COMPRESSION: u8 = 3;
+
+/// A named set of R, G and B channels. The layer with an
+/// empty name is the main image; any other layer is written
+/// with its channels prefixed, e.g. `albedo.R`.
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub pixels: &'a [Rgb<f32>],
+}
+
+struct Channel<'a> {
+    name: String,
+    layer: &'a [Rgb<f32>],
+    component: usize,
+}
+
+/// Writes the layers, in row-major order with the first
+/// row at the top, as an OpenEXR image.
+pub fn write<W: Write>(
+    w: &mut W,
+    width: usize,
+    height: usize,
+    layers: &[Layer],
+    pixel_type: PixelType,
+) -> std::io::Result<()> {
+    let mut channels = Vec::new();
+    for layer in layers.iter() {
+        assert_eq!(layer.pixels.len(), width * height);
+
+        for (component, c) in ["R", "G", "B"].iter().enumerate() {
+            let name = if layer.name.is_empty() {
+                c.to_string()
+            } else {
+                format!("{}.{}", layer.name, c)
+            };
+            channels.push(Channel {
+                name,
+                layer: layer.pixels,
+                component,
+            });
+        }
+    }
+
+    // The spec requires the channel list to be sorted by name
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = Vec::new();
+
+    // Magic number and version 2, with no flags set
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+    let mut chlist = Vec::new();
+    for ch in channels.iter() {
+        chlist.extend_from_slice(ch.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&pixel_type.id().to_le_bytes());
+        // pLinear and three reserved bytes
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        // x and y sampling
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let mut window = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    attribute(&mut header, "channels", "chlist", &chlist);
+    attribute(&mut header, "compression", "compression", &[NO_COMPRESSION]);
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    // INCREASING_Y
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    w.write_all(&header)?;
+
+    // Uncompressed files store one scanline per chunk, so the
+    // offset table has one entry per row.
+    let line_size = width * channels.len() * pixel_type.size();
+    let chunk_size = 8 + line_size;
+    let first_chunk = header.len() + 8 * height;
+
+    for y in 0..height {
+        let offset = (first_chunk + y * chunk_size) as u64;
+        w.write_all(&offset.to_le_bytes())?;
+    }
+
+    let mut line = Vec::with_capacity(line_size);
+    for y in 0..height {
+        line.clear();
+
+        for ch in channels.iter() {
+            for x in 0..width {
+                let v = ch.layer[y * width + x][ch.component];
+                match pixel_type {
+                    PixelType::Half => line.extend_from_slice(&f32_to_f16(v).to_le_bytes()),
+                    PixelType::Float => line.extend_from_slice(&v.to_le_bytes()),
+                }
+            }
+        }
+
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&(line_size as i32).to_le_bytes())?;
+        w.write_all(&line)?;
+    }
+
+    Ok(())
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Converts to IEEE 754 half precision, rounding to nearest even.
+pub fn f32_to_f16(v: f32) -> u16 {
+    let bits = v.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    if exp == 0xff {
+        // Infinity stays infinity, NaN stays a (quiet) NaN
+        let nan = if mantissa != 0 { 0x0200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exp = exp - 127 + 15;
+
+    if exp >= 0x1f {
+        // Too large, becomes infinity
+        sign | 0x7c00
+    } else if exp <= 0 {
+        if exp < -10 {
+            // Too small even for a subnormal
+            return sign;
+        }
+
+        // Subnormal: shift the mantissa with its implicit bit
+        let mantissa = mantissa | 0x0080_0000;
+        let shift = (14 - exp) as u32;
+        let half = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+
+        let round = rest > halfway || (rest == halfway && half & 1 == 1);
+        sign | (half as u16 + round as u16)
+    } else {
+        let half = ((exp as u32) << 10) | (mantissa >> 13);
+        let rest = mantissa & 0x1fff;
+
+        // Rounding may carry into the exponent, which is
+        // exactly what should happen.
+        let round = rest > 0x1000 || (rest == 0x1000 && half & 1 == 1);
+        sign | (half + round as u32) as u16
+    }
+}
+
+/// Reads the R, G and B channels (or the Y channel of
+/// grayscale images) of a scanline OpenEXR image, returning
+/// its size and its pixels in the same order `write` takes.
+///
+/// Only uncompressed and ZIP compressed images are supported.
+pub fn read<R: Read>(r: &mut R) -> Result<(usize, usize, Vec<Rgb<f32>>), String> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data).map_err(|e| e.to_string())?;
+    let mut input = Input {
+        data: &data,
+        pos: 0,
+    };
+
+    if input.bytes(4)? != [0x76, 0x2f, 0x31, 0x01] {
+        return Err("not an OpenEXR file".to_string());
+    }
+    let version = input.i32()?;
+    // Tiled, deep and multi-part files
+    if version & 0xff != 2 || version & (0x200 | 0x800 | 0x1000) != 0 {
+        return Err("only single part scanline images are supported".to_string());
+    }
+
+    let mut channels: Vec<(String, i32)> = Vec::new();
+    let mut compression = NO_COMPRESSION;
+    let mut window = None;
+
+    loop {
+        let name = input.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let kind = input.string()?;
+        let size = input.i32()?.max(0) as usize;
+        let mut value = Input {
+            data: input.bytes(size)?,
+            pos: 0,
+        };
+
+        match (name.as_str(), kind.as_str()) {
+            ("channels", "chlist") => loop {
+                let channel = value.string()?;
+                if channel.is_empty() {
+                    break;
+                }
+                let pixel_type = value.i32()?;
+                value.bytes(4)?;
+                if value.i32()? != 1 || value.i32()? != 1 {
+                    return Err("subsampled channels are not supported".to_string());
+                }
+                channels.push((channel, pixel_type));
+            },
+            ("compression", "compression") => compression = value.bytes(1)?[0],
+            ("dataWindow", "box2i") => {
+                let (x0, y0, x1, y1) = (value.i32()?, value.i32()?, value.i32()?, value.i32()?);
+                window = Some((x0, y0, x1, y1));
+            }
+            _ => {}
+        }
+    }
+
+    let (x0, y0, x1, y1) = window.ok_or("missing data window")?;
+    if x1 < x0 || y1 < y0 {
+        return Err("empty data window".to_string());
+    }
+    let width = (x1 - x0 + 1) as usize;
+    let height = (y1 - y0 + 1) as usize;
+
+    let lines_per_chunk = match compression {
+        NO_COMPRESSION | ZIPS_COMPRESSION => 1,
+        ZIP_COMPRESSION => 16,
+        _ => return Err("only uncompressed and ZIP compressed images are supported".to_string()),
+    };
+
+    // Where each channel ends up, if anywhere
+    let find = |name: &str| channels.iter().position(|(n, _)| n == name);
+    let targets: Vec<usize> = match (find("R"), find("G"), find("B"), find("Y")) {
+        (Some(r), Some(g), Some(b), _) => vec![r, g, b],
+        (_, _, _, Some(y)) => vec![y, y, y],
+        _ => return Err("no RGB or Y channels".to_string()),
+    };
+
+    let sizes = channels
+        .iter()
+        .map(|(_, t)| match t {
+            0 | 2 => Ok(4),
+            1 => Ok(2),
+            _ => Err(format!("unknown pixel type {}", t)),
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    let line_size = width * sizes.iter().sum::<usize>();
+
+    let n_chunks = height.div_ceil(lines_per_chunk);
+    let offsets = (0..n_chunks)
+        .map(|_| {
+            Ok(input
+                .bytes(8)?
+                .iter()
+                .rev()
+                .fold(0usize, |acc, b| acc << 8 | *b as usize))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+
+    let mut pixels = vec![Rgb::zero(); width * height];
+
+    for offset in offsets {
+        let mut chunk = Input {
+            data: &data,
+            pos: offset,
+        };
+        let first_line = chunk.i32()? - y0;
+        let size = chunk.i32()?.max(0) as usize;
+        let packed = chunk.bytes(size)?;
+
+        if first_line < 0 || first_line as usize >= height {
+            return Err("scanline out of the data window".to_string());
+        }
+        let first_line = first_line as usize;
+        let n_lines = lines_per_chunk.min(height - first_line);
+        let expected = n_lines * line_size;
+
+        // Chunks that wouldn't get any smaller are stored as is
+        let unpacked = if compression == NO_COMPRESSION || size == expected {
+            packed.to_vec()
+        } else {
+            unzip(packed)?
+        };
+        if unpacked.len() != expected {
+            return Err("corrupted scanline data".to_string());
+        }
+
+        let mut pos = 0;
+        for y in first_line..first_line + n_lines {
+            for (c, (_, pixel_type)) in channels.iter().enumerate() {
+                for x in 0..width {
+                    let bytes = &unpacked[pos..pos + sizes[c]];
+                    pos += sizes[c];
+
+                    let v = match pixel_type {
+                        0 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32,
+                        1 => f16_to_f32(u16::from_le_bytes([bytes[0], bytes[1]])),
+                        _ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                    };
+
+                    for (component, target) in targets.iter().enumerate() {
+                        if *target == c {
+                            pixels[y * width + x][component] = v;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((width, height, pixels))
+}
+
+/// Undoes the ZIP compression: zlib, on top of a delta
+/// encoding of the bytes, split in odd and even halves.
+fn unzip(packed: &[u8]) -> Result<Vec<u8>, String> {
+    let mut t = inflate::inflate_bytes_zlib(packed)?;
+
+    for i in 1..t.len() {
+        t[i] = t[i - 1].wrapping_add(t[i]).wrapping_sub(128);
+    }
+
+    let half = t.len().div_ceil(2);
+    let mut out = Vec::with_capacity(t.len());
+    for i in 0..half {
+        out.push(t[i]);
+        if half + i < t.len() {
+            out.push(t[half + i]);
+        }
+    }
+
+    Ok(out)
+}
+
+/// A cursor over the bytes of a file.
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err("unexpected end of file".to_string());
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        let b = self.bytes(4)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or("unexpected end of file")?;
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += len + 1;
+        Ok(s)
+    }
+}
+
+/// Converts from IEEE 754 half precision.
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x03ff) as u32;
+
+    let bits = if exp == 0x1f {
+        sign | 0x7f80_0000 | (mantissa << 13)
+    } else if exp != 0 {
+        sign | ((exp + 127 - 15) << 23) | (mantissa << 13)
+    } else if mantissa == 0 {
+        sign
+    } else {
+        // Subnormal: normalize the mantissa
+        let shift = mantissa.leading_zeros() - 21;
+        let mantissa = (mantissa << shift) & 0x03ff;
+        sign | ((127 - 15 + 1 - shift) << 23) | (mantissa << 13)
+    };
+
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The uncompressed file `write` makes of the image.
+    fn written(width: usize, height: usize, layers: &[Layer], pixel_type: PixelType) -> Vec<u8> {
+        let mut file = Vec::new();
+        write(&mut file, width, height, layers, pixel_type).unwrap();
+        file
+    }
+
+    /// Some pixels that are exact in half precision, and
+    /// different in every channel.
+    fn gradient(width: usize, height: usize) -> Vec<Rgb<f32>> {
+        (0..width * height)
+            .map(|i| Rgb::new(i as f32 / 8.0, -(i as f32), 1.0 + (i % 3) as f32 * 0.25))
+            .collect()
+    }
+
+    /// Compresses like the ZIP compression of OpenEXR, with a
+    /// zlib stream of stored (uncompressed) deflate blocks.
+    fn zip(raw: &[u8]) -> Vec<u8> {
+        let mut t: Vec<u8> = raw.iter().step_by(2).cloned().collect();
+        t.extend(raw.iter().skip(1).step_by(2));
+        for i in (1..t.len()).rev() {
+            t[i] = t[i].wrapping_sub(t[i - 1]).wrapping_add(128);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let blocks: Vec<&[u8]> = t.chunks(0xffff).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            zlib.push((i + 1 == blocks.len()) as u8);
+            zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in t.iter() {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        zlib
+    }
+
+    #[test]
+    fn float_to_half() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+
+        // Subnormals, down to the smallest one
+        assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_f16(1023.0 * 2f32.powi(-24)), 0x03ff);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(2f32.powi(-26)), 0x0000);
+
+        // Overflow
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1e10), 0x7c00);
+        assert_eq!(f32_to_f16(-1e10), 0xfc00);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+
+        let nan = f32_to_f16(f32::NAN);
+        assert!(nan & 0x7c00 == 0x7c00 && nan & 0x03ff != 0);
+    }
+
+    #[test]
+    fn rounds_to_nearest_even() {
+        let ulp = 2f32.powi(-10);
+
+        // Halfway cases go to the even neighbor
+        assert_eq!(f32_to_f16(1.0 + 0.5 * ulp), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 1.5 * ulp), 0x3c02);
+        assert_eq!(f32_to_f16(2f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_f16(3.0 * 2f32.powi(-25)), 0x0002);
+
+        // Anything else to the nearest one
+        assert_eq!(f32_to_f16(1.0 + 0.51 * ulp), 0x3c01);
+        assert_eq!(f32_to_f16(1.0 + 1.49 * ulp), 0x3c01);
+
+        // Carrying into the exponent
+        assert_eq!(f32_to_f16(2.0 - 0.25 * ulp), 0x4000);
+    }
+
+    #[test]
+    fn write_then_read() {
+        let (width, height) = (5, 3);
+        let pixels = gradient(width, height);
+        let albedo = vec![Rgb::new(0.5, 0.25, 0.125); width * height];
+        let layers = [
+            Layer {
+                name: "",
+                pixels: &pixels,
+            },
+            Layer {
+                name: "albedo",
+                pixels: &albedo,
+            },
+        ];
+
+        for &pixel_type in [PixelType::Half, PixelType::Float].iter() {
+            let file = written(width, height, &layers, pixel_type);
+            let (w, h, read_pixels) = read(&mut file.as_slice()).unwrap();
+            assert_eq!((w, h), (width, height));
+            assert_eq!(read_pixels, pixels, "{:?}", pixel_type);
+        }
+
+        // Floats keep what halves can't
+        let precise = vec![Rgb::new(0.1, 1e-6, 1e6); width * height];
+        let layers = [Layer {
+            name: "",
+            pixels: &precise,
+        }];
+        let file = written(width, height, &layers, PixelType::Float);
+        assert_eq!(read(&mut file.as_slice()).unwrap().2, precise);
+    }
+
+    #[test]
+    fn half_to_float() {
+        assert_eq!(f16_to_f32(0x0000), 0.0);
+        assert!(f16_to_f32(0x8000).is_sign_negative());
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0x0400), 2f32.powi(-14));
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x03ff), 1023.0 * 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(0x7e00).is_nan());
+    }
+
+    #[test]
+    fn halves_survive_a_round_trip() {
+        for h in 0..=u16::MAX {
+            if h & 0x7c00 == 0x7c00 && h & 0x03ff != 0 {
+                assert!(f16_to_f32(h).is_nan());
+            } else {
+                assert_eq!(f32_to_f16(f16_to_f32(h)), h, "{:#06x}", h);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_zip_compression() {
+        let (width, height) = (7, 20);
+        let pixels = gradient(width, height);
+        let layers = [Layer {
+            name: "",
+            pixels: &pixels,
+        }];
+        let plain = written(width, height, &layers, PixelType::Half);
+
+        // Everything after the header is the offset table and
+        // one chunk per line
+        let line_size = width * 3 * 2;
+        let header_size = plain.len() - height * (8 + 8 + line_size);
+        let first_chunk = header_size + 8 * height;
+        let mut header = plain[..header_size].to_vec();
+
+        let attribute = b"compression\0compression\0\x01\0\0\0";
+        let at = header
+            .windows(attribute.len())
+            .position(|w| w == attribute)
+            .unwrap();
+        header[at + attribute.len()] = ZIP_COMPRESSION;
+
+        // Chunks of 16 lines, without their headers
+        let lines: Vec<&[u8]> = plain[first_chunk..]
+            .chunks(8 + line_size)
+            .map(|chunk| &chunk[8..])
+            .collect();
+        let chunks: Vec<Vec<u8>> = lines.chunks(16).map(|ls| zip(&ls.concat())).collect();
+
+        let mut offset = header.len() + 8 * chunks.len();
+        let mut file = header;
+        for chunk in chunks.iter() {
+            file.extend_from_slice(&(offset as u64).to_le_bytes());
+            offset += 8 + chunk.len();
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            file.extend_from_slice(&(16 * i as i32).to_le_bytes());
+            file.extend_from_slice(&(chunk.len() as i32).to_le_bytes());
+            file.extend_from_slice(chunk);
+        }
+
+        let (w, h, read_pixels) = read(&mut file.as_slice()).unwrap();
+        assert_eq!((w, h), (width, height));
+        assert_eq!(read_pixels, pixels);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(read(&mut &b"\x89PNG\r\n\x1a\n"[..]).is_err());
+
+        let pixels = gradient(2, 2);
+        let layers = [Layer {
+            name: "",
+            pixels: &pixels,
+        }];
+        let file = written(2, 2, &layers, PixelType::Float);
+        assert!(read(&mut &file[..file.len() - 1]).is_err());
+    }
+}
@@ -7,13 +7,12 @@ pub struct GlShader {
 }
 
 impl GlShader {
-    pub fn new(files: Vec<Box<(GLenum, String)>>) -> Result<GlShader, String> {
+    pub fn new(files: Vec<(GLenum, String)>) -> Result<GlShader, String> {
         unsafe {
             let handle = gl::CreateProgram();
 
-            for b in files.into_iter() {
-                let (shader_type, file_name) = *b;
-                if !vec![
+            for (shader_type, file_name) in files.into_iter() {
+                if ![
                     gl::VERTEX_SHADER,
                     gl::FRAGMENT_SHADER,
                     gl::COMPUTE_SHADER,
@@ -24,8 +23,8 @@ impl GlShader {
                     return Err(format!("Shader type for {} isn\'t supported!", file_name));
                 }
 
-                if let Ok(src) = std::fs::read_to_string(file_name.to_owned()) {
-                    let c_src = std::ffi::CString::new((&src).as_bytes()).unwrap();
+                if let Ok(src) = std::fs::read_to_string(&file_name) {
+                    let c_src = std::ffi::CString::new(src.as_bytes()).unwrap();
 
                     let shader_handle = gl::CreateShader(shader_type);
 
@@ -39,8 +38,7 @@ impl GlShader {
                         let mut len = 0;
                         gl::GetShaderiv(shader_handle, gl::INFO_LOG_LENGTH, &mut len);
 
-                        let mut buf = Vec::with_capacity(len as usize);
-                        buf.set_len((len as usize) - 1);
+                        let mut buf = vec![0u8; len as usize];
                         gl::GetShaderInfoLog(
                             shader_handle,
                             len,
@@ -51,7 +49,7 @@ impl GlShader {
                         return Err(format!(
                             "Couldnt compile {}: {}",
                             file_name,
-                            std::str::from_utf8(&buf).unwrap()
+                            std::str::from_utf8(&buf[..(len as usize) - 1]).unwrap()
                         ));
                     }
 
@@ -70,8 +68,7 @@ impl GlShader {
                 let mut len = 0;
                 gl::GetProgramiv(handle, gl::INFO_LOG_LENGTH, &mut len);
 
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1);
+                let mut buf = vec![0u8; len as usize];
                 gl::GetProgramInfoLog(
                     handle,
                     len,
@@ -80,11 +77,11 @@ impl GlShader {
                 );
                 return Err(format!(
                     "Link failed: {}",
-                    std::str::from_utf8(&buf).unwrap()
+                    std::str::from_utf8(&buf[..(len as usize) - 1]).unwrap()
                 ));
             }
 
-            Ok(GlShader { handle: handle })
+            Ok(GlShader { handle })
         }
     }
 
@@ -106,4 +103,26 @@ impl GlShader {
             gl::Uniform1i(location, slot as i32);
         }
     }
+
+    pub fn uniform_float(&self, name: String, value: f32) {
+        unsafe {
+            let location = gl::GetUniformLocation(
+                self.handle,
+                std::ffi::CString::new(name.as_bytes()).unwrap().as_ptr(),
+            );
+
+            gl::Uniform1f(location, value);
+        }
+    }
+
+    pub fn uniform_int(&self, name: String, value: i32) {
+        unsafe {
+            let location = gl::GetUniformLocation(
+                self.handle,
+                std::ffi::CString::new(name.as_bytes()).unwrap().as_ptr(),
+            );
+
+            gl::Uniform1i(location, value);
+        }
+    }
 }
@@ -27,7 +27,7 @@ impl GlTexture {
             gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
-                gl::RGB as i32,
+                gl::RGB32F as i32,
                 width,
                 height,
                 0,
@@ -58,6 +58,10 @@ impl GlTexture {
         let u = u as gl::types::GLint;
         let v = v as gl::types::GLint;
 
+        if u >= self.width || v >= self.height {
+            return;
+        }
+
         self.bind();
 
         let data = rgb.into_array();
@@ -1,150 +1,124 @@
-use std::sync::Arc;
-
 use clap::App;
 
-use vek::rgb::Rgb;
-use vek::vec::{Vec2, Vec3};
-
+mod exr;
 mod gl_shader;
 mod gl_texture;
+mod obj;
+mod output;
+mod scene;
+mod tonemap;
 mod tracer;
 mod visualizer;
-
-use tracer::camera::MtxCamera;
-use tracer::light::{AreaLight, PointLight};
-use tracer::material::{Material, BRDF};
-use tracer::render_context::RenderContext;
-use tracer::shape::Triangle;
-use tracer::shape::{Plane, Shape, Sphere};
-use tracer::volume::Volume;
+mod vol;
 
 fn main() {
     let yaml = clap::load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    let w: usize = matches.value_of("width").unwrap_or("640").parse().unwrap();
-    let h: usize = matches.value_of("height").unwrap_or("320").parse().unwrap();
-
-    let samples: u16 = matches.value_of("samples").unwrap_or("1").parse().unwrap();
-
-    let n_threads: u16 = matches.value_of("threads").unwrap_or("1").parse().unwrap();
+    let overrides = match overrides(&matches) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Running on {} threads, with {} samples", n_threads, samples);
+    let scene_file = matches
+        .value_of("scene")
+        .unwrap_or("res/scenes/default.yml");
 
-    let (tx, rx) = std::sync::mpsc::channel();
+    let output_path = matches.value_of("output").map(String::from);
 
-    //let camera = PerspCamera::new(
-    //Vec3::new(0.0, 10.0, -5.0), // position of the camera
-    //Vec3::new(0.0, 10.0, 1.0), // position of the target
-    //std::f32::consts::FRAC_PI_4, // field of view in radians
-    //(w as f32) / (h as f32), // aspect ratio (width/height)
-    //(0f32).to_radians(), // camera roll
-    //w as u32, // width
-    //h as u32, // height
-    //);
-
-    let camera = MtxCamera::new(
-        Vec3::new(10.0, 10.0, 10.0),
-        Vec3::new(0.0, 0.0, 0.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        std::f32::consts::FRAC_PI_3,
-        w,
-        h,
-    );
+    if let Some(Err(e)) = output_path.as_ref().map(|path| output::format_of(path)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let triangle = Triangle {
-        vertices: [
-            Vec3::new(-4.0, 0.0, 0.0),
-            Vec3::new(4.0, 0.0, 0.0),
-            Vec3::new(0.0, 0.0, 4.0),
-        ],
-        normals: None,
-        tex_coords: None,
+    let scene = match scene::load(scene_file, &overrides) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
 
-    let red = Material {
-        emittance: Rgb::zero(),
-        brdf: BRDF::Lambertian(0.9),
-        albedo: Rgb::new(1.0, 0.0, 0.0),
-    };
+    let ctx = scene.ctx;
 
-    let green = Material {
-        emittance: Rgb::zero(),
-        brdf: BRDF::Lambertian(0.6),
-        albedo: Rgb::new(0.0, 1.0, 0.0),
+    let save_options = output::SaveOptions {
+        exr_type: match matches.value_of("exr_type") {
+            Some("float") => exr::PixelType::Float,
+            _ => exr::PixelType::Half,
+        },
+        tonemap: scene.tonemap,
     };
 
-    let blue = Material {
-        emittance: Rgb::zero(),
-        brdf: BRDF::Lambertian(0.8),
-        albedo: Rgb::new(0.0, 0.0, 1.0),
-    };
+    println!(
+        "Running on {} threads, with {} samples",
+        ctx.n_threads, ctx.samples
+    );
 
-    let purple = Material {
-        emittance: Rgb::new(1.0, 1.0, 1.0),
-        brdf: BRDF::BlackBody,
-        albedo: Rgb::new(0.0, 0.0, 0.0),
-    };
+    let (w, h, samples) = (ctx.width, ctx.height, ctx.samples);
+    let (tx, rx) = std::sync::mpsc::channel();
 
-    let white = Material {
-        emittance: Rgb::new(0.0, 0.0, 0.0),
-        brdf: BRDF::Lambertian(1.0),
-        albedo: Rgb::new(0.4, 0.0, 0.4),
-    };
+    tracer::render(tx, ctx);
 
-    // Volume
-    //let k_b = 1.38e-23;
-    //let k_b = 1.38e-10;
-    let mist_radius = 1.0f32;
-    let mist_volume = (4.0/3.0) * std::f32::consts::PI * mist_radius.powi(2);
-    let mist_pressure = 101325.0;
-    let mist_temperature = 273.0;
-    //let mist_density = mist_pressure / (k_b * mist_temperature);
-    let mist_density = 10.0;
-    let mist_carrier = Sphere::new(Vec3::zero(), 1.0, white);
-
-    let ctx = RenderContext {
-        width: w,
-        height: h,
-        samples: samples,
-        n_threads: n_threads,
-        objects: Arc::new(vec![
-            Arc::new(Sphere::new(
-                Vec3::new(2.0, 2.0, 0.0),
-                1.0,
-                blue,
-            )),
-            Arc::new(Sphere::new(
-                Vec3::new(-2.0, 2.0, 0.0),
-                1.0,
-                blue,
-            )),
-            Arc::new(Sphere::new(
-                Vec3::new(0.0, 2.0, 2.0),
-                1.0,
-                blue,
-            )),
-            Arc::new(Plane::new(
-                Vec3::new(0.0, 0.0, 0.0),
-                Vec3::new(0.0, 1.0, 0.0),
-                white,
-            )),
-            Arc::new(Volume::new(mist_carrier, mist_density)),
-        ]),
-        lights: Arc::new(vec![
-            Arc::new(AreaLight {
-                position: Vec3::new(-5.0, 5.0, -5.0),
-                radius: 5.0,
-            }),
-            //Arc::new(PointLight {
-                //position: Vec3::new(-5.0, 5.0, -5.0),
-            //}),
-        ]),
-        ambient: Rgb::new(0.1, 0.1, 0.1),
-        camera,
-    };
+    if matches.is_present("headless") {
+        let start = std::time::Instant::now();
+        let fb = output::Framebuffer::collect(rx, w, h);
+        let elapsed = start.elapsed().as_secs_f64();
+
+        println!(
+            "Rendered in {:.2}s ({:.3} Msamples/s)",
+            elapsed,
+            (w * h * samples as usize) as f64 / elapsed / 1e6
+        );
+
+        let path = output_path.unwrap();
+
+        if let Err(e) = fb.save(&path, &save_options) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        println!("Saved {}", path);
+    } else {
+        let output = output_path.map(|path| (path, save_options));
+        visualizer::Visualizer::run(rx, w, h, scene.tonemap, output);
+    }
+}
 
-    tracer::render(tx, ctx);
+/// The settings given on the command line that take
+/// precedence over the scene file.
+fn overrides(matches: &clap::ArgMatches) -> Result<scene::Overrides, String> {
+    Ok(scene::Overrides {
+        width: parse_arg(matches, "width")?,
+        height: parse_arg(matches, "height")?,
+        samples: parse_arg(matches, "samples")?,
+        n_threads: parse_arg(matches, "threads")?,
+        exposure: parse_arg(matches, "exposure")?,
+        tonemap: matches
+            .value_of("tonemap")
+            .and_then(tonemap::Operator::from_name),
+        srgb: if matches.is_present("linear") {
+            Some(false)
+        } else {
+            None
+        },
+        brute_force: matches.is_present("brute_force"),
+    })
+}
 
-    let _ = visualizer::Visualizer::new(rx, w, h);
+/// Parses the value of an option, if it was given.
+fn parse_arg<T: std::str::FromStr>(
+    matches: &clap::ArgMatches,
+    name: &str,
+) -> Result<Option<T>, String> {
+    matches
+        .value_of(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("invalid --{} `{}`", name, value))
+        })
+        .transpose()
 }
@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use std::sync::Arc;
+
+use crate::tracer::bsdf::{BlackBody, Dielectric, Glossy, Lambertian};
+use crate::tracer::material::{BsdfModel, Material, NormalMap};
+use crate::tracer::shape::{Mesh, Triangle};
+use crate::tracer::texture::{ConstantTexture, ImageTexture, Texture, Wrap};
+
+/// MTL materials with a specular exponent at least this
+/// high are treated as perfect mirrors.
+const MIRROR_NS: f32 = 900.0;
+
+/// Faces are grouped by their group and material name.
+type MeshKey = (String, Option<String>);
+
+/// Loads a Wavefront OBJ file, with the materials of the MTL
+/// libraries it references. The faces are split into one mesh
+/// per group and material, and n-gons are triangulated as fans.
+///
+/// Faces without a material use `default_material`.
+pub fn load(path: &str, default_material: Material) -> Result<Vec<Mesh>, String> {
+    let src = std::fs::read_to_string(path).map_err(|e| format!("Couldnt open {}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse(&src, path, dir, default_material)
+}
+
+/// Parses the source of an OBJ file, named `path` in the
+/// errors. The MTL libraries are looked up relative to `dir`.
+fn parse(
+    src: &str,
+    path: &str,
+    dir: &Path,
+    default_material: Material,
+) -> Result<Vec<Mesh>, String> {
+    let default_material = Arc::new(default_material);
+
+    let mut positions: Vec<Vec3<f32>> = Vec::new();
+    let mut normals: Vec<Vec3<f32>> = Vec::new();
+    let mut tex_coords: Vec<Vec2<f32>> = Vec::new();
+
+    let mut materials: HashMap<String, Arc<Material>> = HashMap::new();
+
+    let mut group = "default".to_string();
+    let mut material: Option<String> = None;
+
+    // The meshes in the order they first appear
+    let mut meshes: Vec<(MeshKey, Vec<Triangle>)> = Vec::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let err = |msg: &str| format!("{}:{}: {}", path, i + 1, msg);
+
+        let line = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line,
+        };
+        let mut words = line.split_whitespace();
+
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+
+        match keyword {
+            "v" => positions.push(parse_vec3(&args).map_err(|e| err(&e))?),
+            "vn" => normals.push(parse_vec3(&args).map_err(|e| err(&e))?.normalized()),
+            "vt" => {
+                let uv = parse_floats(&args, 1).map_err(|e| err(&e))?;
+                let v = if uv.len() > 1 { uv[1] } else { 0.0 };
+                tex_coords.push(Vec2::new(uv[0], v));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err("a face needs at least 3 vertices"));
+                }
+
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    corners.push(
+                        parse_corner(arg, positions.len(), tex_coords.len(), normals.len())
+                            .map_err(|e| err(&e))?,
+                    );
+                }
+
+                let mat = match material {
+                    Some(ref name) => materials
+                        .get(name)
+                        .ok_or_else(|| err(&format!("unknown material `{}`", name)))?,
+                    None => &default_material,
+                };
+
+                let key = (group.clone(), material.clone());
+                let idx = match meshes.iter().position(|(k, _)| *k == key) {
+                    Some(idx) => idx,
+                    None => {
+                        meshes.push((key, Vec::new()));
+                        meshes.len() - 1
+                    }
+                };
+
+                for k in 1..corners.len() - 1 {
+                    let c = [corners[0], corners[k], corners[k + 1]];
+
+                    let mut triangle = Triangle::new(
+                        [positions[c[0].0], positions[c[1].0], positions[c[2].0]],
+                        Arc::clone(mat),
+                    );
+
+                    if let (Some(a), Some(b), Some(c)) = (c[0].2, c[1].2, c[2].2) {
+                        triangle.normals = Some([normals[a], normals[b], normals[c]]);
+                    }
+                    if let (Some(a), Some(b), Some(c)) = (c[0].1, c[1].1, c[2].1) {
+                        triangle.tex_coords = Some([tex_coords[a], tex_coords[b], tex_coords[c]]);
+                    }
+
+                    meshes[idx].1.push(triangle);
+                }
+            }
+            "g" | "o" => {
+                group = if args.is_empty() {
+                    "default".to_string()
+                } else {
+                    args.join(" ")
+                };
+            }
+            "usemtl" => {
+                if args.is_empty() {
+                    return Err(err("usemtl needs a material name"));
+                }
+                material = Some(args.join(" "));
+            }
+            "mtllib" => {
+                for lib in args.iter() {
+                    let lib_path = dir.join(lib);
+                    let lib = load_mtl(&lib_path.to_string_lossy())?;
+                    materials.extend(lib.into_iter().map(|(name, m)| (name, Arc::new(m))));
+                }
+            }
+            // Smoothing groups, lines, points and the rest are ignored
+            _ => {}
+        }
+    }
+
+    Ok(meshes
+        .into_iter()
+        .map(|((group, mtl), triangles)| {
+            let mat = match mtl {
+                Some(ref name) => materials[name].clone(),
+                None => default_material.clone(),
+            };
+            let name = match mtl {
+                Some(name) => format!("{}/{}", group, name),
+                None => group,
+            };
+            Mesh::new(name, triangles, mat)
+        })
+        .collect())
+}
+
+/// Loads the materials of an MTL library.
+///
+/// `Kd` becomes the albedo, replaced by the image `map_Kd`
+/// if there is one, and `Ke` the emittance. Emissive
+/// materials are black bodies, transparent ones (with a
+/// dissolve `d`, or `1 - Tr`, below 1) are dielectrics with
+/// the index of refraction `Ni` tinted by `Tf`, materials with
+/// an `Ns` above `MIRROR_NS` are glossy, and everything else
+/// is lambertian. A bump map `bump` or `map_Bump`, with its
+/// `-bm` height, or a normal map `norm` adds details.
+pub fn load_mtl(path: &str) -> Result<HashMap<String, Material>, String> {
+    let src = std::fs::read_to_string(path).map_err(|e| format!("Couldnt open {}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse_mtl(&src, path, dir)
+}
+
+/// Parses the source of an MTL library, named `path` in the
+/// errors. Texture maps are looked up relative to `dir`.
+fn parse_mtl(src: &str, path: &str, dir: &Path) -> Result<HashMap<String, Material>, String> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (i, line) in src.lines().enumerate() {
+        let err = |msg: &str| format!("{}:{}: {}", path, i + 1, msg);
+
+        let line = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line,
+        };
+        let mut words = line.split_whitespace();
+
+        let keyword = match words.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+
+        if keyword == "newmtl" {
+            if let Some((name, mtl)) = current.take() {
+                materials.insert(name, mtl.to_material(dir)?);
+            }
+            if args.is_empty() {
+                return Err(err("newmtl needs a material name"));
+            }
+            current = Some((args.join(" "), MtlMaterial::default()));
+            continue;
+        }
+
+        let mtl = match current {
+            Some((_, ref mut mtl)) => mtl,
+            None => continue,
+        };
+
+        match keyword {
+            "Kd" => mtl.kd = parse_rgb(&args).map_err(|e| err(&e))?,
+            "Ke" => mtl.ke = parse_rgb(&args).map_err(|e| err(&e))?,
+            "Ns" => mtl.ns = parse_floats(&args, 1).map_err(|e| err(&e))?[0],
+            "d" => mtl.d = parse_floats(&args, 1).map_err(|e| err(&e))?[0],
+            "Tr" => mtl.d = 1.0 - parse_floats(&args, 1).map_err(|e| err(&e))?[0],
+            "Ni" => mtl.ni = Some(parse_floats(&args, 1).map_err(|e| err(&e))?[0]),
+            "Tf" => mtl.tf = parse_rgb(&args).map_err(|e| err(&e))?,
+            // Options like `-s` come before the file name
+            "map_Kd" => mtl.map_kd = Some(map_file(&args).map_err(|e| err(&e))?),
+            "bump" | "map_Bump" => {
+                let height = match args.iter().position(|a| *a == "-bm") {
+                    Some(i) => {
+                        let value = &args[i + 1..args.len().min(i + 2)];
+                        parse_floats(value, 1).map_err(|e| err(&e))?[0]
+                    }
+                    None => 1.0,
+                };
+                mtl.bump = Some((map_file(&args).map_err(|e| err(&e))?, height));
+            }
+            "norm" => mtl.norm = Some(map_file(&args).map_err(|e| err(&e))?),
+            _ => {}
+        }
+    }
+
+    if let Some((name, mtl)) = current.take() {
+        materials.insert(name, mtl.to_material(dir)?);
+    }
+
+    Ok(materials)
+}
+
+struct MtlMaterial {
+    kd: Rgb<f32>,
+    ke: Rgb<f32>,
+    ns: f32,
+    d: f32,
+    ni: Option<f32>,
+    tf: Rgb<f32>,
+    map_kd: Option<String>,
+    bump: Option<(String, f32)>,
+    norm: Option<String>,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> MtlMaterial {
+        MtlMaterial {
+            kd: Rgb::broadcast(0.8),
+            ke: Rgb::zero(),
+            ns: 0.0,
+            d: 1.0,
+            ni: None,
+            tf: Rgb::one(),
+            map_kd: None,
+            bump: None,
+            norm: None,
+        }
+    }
+}
+
+impl MtlMaterial {
+    fn to_material(&self, dir: &Path) -> Result<Material, String> {
+        let (bsdf, albedo): (Arc<dyn BsdfModel>, _) = if self.ke != Rgb::zero() {
+            (Arc::new(BlackBody), self.kd)
+        } else if self.d < 1.0 {
+            (
+                Arc::new(Dielectric::new(self.ni.unwrap_or(1.5), Rgb::zero())),
+                self.tf,
+            )
+        } else if self.ns >= MIRROR_NS {
+            (Arc::new(Glossy), self.kd)
+        } else {
+            (Arc::new(Lambertian::new(1.0)), self.kd)
+        };
+
+        let load = |file: &str, srgb: bool| -> Result<Arc<dyn Texture>, String> {
+            let path = dir.join(file);
+            let image =
+                ImageTexture::load(&path.to_string_lossy(), srgb, Wrap::Repeat, Vec2::one())?;
+            Ok(Arc::new(image))
+        };
+
+        let albedo = match self.map_kd {
+            Some(ref file) if self.d >= 1.0 => load(file, true)?,
+            _ => Arc::new(ConstantTexture(albedo)),
+        };
+        let normal_map = match (&self.norm, &self.bump) {
+            (Some(file), _) => Some(NormalMap::Normal(load(file, false)?)),
+            (None, Some((file, height))) => Some(NormalMap::Bump(load(file, false)?, *height)),
+            (None, None) => None,
+        };
+
+        Ok(Material {
+            albedo,
+            emittance: self.ke,
+            bsdf,
+            normal_map,
+        })
+    }
+}
+
+/// Parses one `v/vt/vn` corner of a face into zero-based
+/// indices. Negative indices count back from the last element.
+fn parse_corner(
+    corner: &str,
+    n_positions: usize,
+    n_tex_coords: usize,
+    n_normals: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = corner.split('/');
+
+    let position = match parts.next() {
+        Some(idx) => resolve_index(idx, n_positions)?,
+        None => return Err(format!("invalid face vertex `{}`", corner)),
+    };
+    let tex_coord = match parts.next() {
+        Some("") | None => None,
+        Some(idx) => Some(resolve_index(idx, n_tex_coords)?),
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(idx) => Some(resolve_index(idx, n_normals)?),
+    };
+
+    Ok((position, tex_coord, normal))
+}
+
+fn resolve_index(idx: &str, len: usize) -> Result<usize, String> {
+    let i: i64 = idx
+        .parse()
+        .map_err(|_| format!("invalid index `{}`", idx))?;
+
+    let resolved = if i > 0 { i - 1 } else { len as i64 + i };
+
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        Err(format!("index {} is out of range", i))
+    } else {
+        Ok(resolved as usize)
+    }
+}
+
+/// The file of a texture map, which comes after its options.
+fn map_file(args: &[&str]) -> Result<String, String> {
+    match args.last() {
+        Some(file) => Ok(file.to_string()),
+        None => Err("expected a file name".to_string()),
+    }
+}
+
+fn parse_floats(args: &[&str], min: usize) -> Result<Vec<f32>, String> {
+    if args.len() < min {
+        return Err(format!("expected at least {} numbers", min));
+    }
+
+    args.iter()
+        .map(|a| a.parse().map_err(|_| format!("invalid number `{}`", a)))
+        .collect()
+}
+
+fn parse_vec3(args: &[&str]) -> Result<Vec3<f32>, String> {
+    let v = parse_floats(args, 3)?;
+    Ok(Vec3::new(v[0], v[1], v[2]))
+}
+
+fn parse_rgb(args: &[&str]) -> Result<Rgb<f32>, String> {
+    let v = parse_floats(args, 3)?;
+    Ok(Rgb::new(v[0], v[1], v[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_obj(src: &str) -> Vec<Mesh> {
+        parse(src, "test.obj", Path::new(""), Material::default()).unwrap()
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn triangulates_ngons_as_fans() {
+        let meshes = parse_obj("v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\n");
+        assert_eq!(meshes.len(), 1);
+
+        let v = |x: f32, y: f32| Vec3::new(x, y, 0.0);
+        let triangles: Vec<_> = meshes[0].triangles.iter().map(|t| t.vertices).collect();
+        assert_eq!(
+            triangles,
+            vec![
+                [v(0.0, 0.0), v(1.0, 0.0), v(2.0, 1.0)],
+                [v(0.0, 0.0), v(2.0, 1.0), v(1.0, 2.0)],
+                [v(0.0, 0.0), v(1.0, 2.0), v(0.0, 1.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_negative_indices() {
+        let meshes = parse_obj(&format!("{}f -4 -3 -1\nv 5 5 5\nf -1 -2 -3\n", SQUARE));
+        let triangles = &meshes[0].triangles;
+
+        assert_eq!(
+            triangles[0].vertices,
+            [Vec3::zero(), Vec3::unit_x(), Vec3::unit_y()]
+        );
+        assert_eq!(
+            triangles[1].vertices,
+            [
+                Vec3::broadcast(5.0),
+                Vec3::unit_y(),
+                Vec3::new(1.0, 1.0, 0.0)
+            ]
+        );
+
+        let err = parse(
+            &format!("{}f 1 2 -5\n", SQUARE),
+            "test.obj",
+            Path::new(""),
+            Material::default(),
+        );
+        assert_eq!(err.err().unwrap(), "test.obj:5: index -5 is out of range");
+    }
+
+    #[test]
+    fn reads_texture_coordinates_and_normals() {
+        let src = format!(
+            "{}vt 0 0\nvt 1 0\nvt 1 1\nvn 0 0 2\nvn 0 1 1\n\
+             f 1/1/1 2/2/1 3/3/2\nf 1//2 3//2 4//1\nf 1/3 3/2 4/1\n",
+            SQUARE
+        );
+        let meshes = parse_obj(&src);
+        let triangles = &meshes[0].triangles;
+
+        let up = Vec3::unit_z();
+        let tilted = Vec3::new(0.0, 1.0, 1.0).normalized();
+
+        assert_eq!(
+            triangles[0].tex_coords,
+            Some([Vec2::zero(), Vec2::unit_x(), Vec2::one()])
+        );
+        assert_eq!(triangles[0].normals, Some([up, up, tilted]));
+
+        assert_eq!(triangles[1].tex_coords, None);
+        assert_eq!(triangles[1].normals, Some([tilted, tilted, up]));
+
+        assert_eq!(
+            triangles[2].tex_coords,
+            Some([Vec2::one(), Vec2::unit_x(), Vec2::zero()])
+        );
+        assert_eq!(triangles[2].normals, None);
+    }
+
+    #[test]
+    fn maps_mtl_materials() {
+        let src = "newmtl matte\nKd 0.2 0.4 0.6\n\
+                   newmtl lamp\nKe 5 4 3\n\
+                   newmtl glass\nd 0.5\nNi 1.33\nTf 0.9 1 0.9\n\
+                   newmtl mirror\nNs 1000\n";
+        let materials = parse_mtl(src, "test.mtl", Path::new("")).unwrap();
+        assert_eq!(materials.len(), 4);
+
+        let (uv, point) = (Vec2::zero(), Vec3::zero());
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let u = Vec2::new(0.99, 0.5);
+
+        let matte = &materials["matte"];
+        assert_eq!(matte.albedo.color(uv, point), Rgb::new(0.2, 0.4, 0.6));
+        assert_eq!(matte.emittance, Rgb::zero());
+        let bsdf = matte.bsdf.at(uv, point);
+        assert!(bsdf.eval(wo, Vec3::unit_z()).r > 0.0);
+
+        let lamp = &materials["lamp"];
+        assert_eq!(lamp.emittance, Rgb::new(5.0, 4.0, 3.0));
+        assert!(lamp.bsdf.at(uv, point).sample(wo, u).is_none());
+
+        // Refracted by the index of Ni, and tinted by Tf
+        let glass = &materials["glass"];
+        assert_eq!(glass.albedo.color(uv, point), Rgb::new(0.9, 1.0, 0.9));
+        let sample = glass.bsdf.at(uv, point).sample(wo, u).unwrap();
+        assert!(sample.specular && sample.wi.z < 0.0);
+        assert!((sample.wi.x + 0.6 / 1.33).abs() < 1e-5);
+
+        let mirror = &materials["mirror"];
+        let sample = mirror.bsdf.at(uv, point).sample(wo, u).unwrap();
+        assert!(sample.specular);
+        assert!((sample.wi - Vec3::new(-0.6, 0.0, 0.8)).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn splits_groups_and_materials() {
+        let src = format!(
+            "{}f 1 2 3\ng top\nf 1 3 4\nusemtl unknown\nf 1 2 4\n",
+            SQUARE
+        );
+        let err = parse(&src, "test.obj", Path::new(""), Material::default());
+        assert_eq!(err.err().unwrap(), "test.obj:9: unknown material `unknown`");
+
+        let meshes = parse_obj(&format!("{}f 1 2 3\ng top\nf 1 3 4\ng\nf 1 2 4\n", SQUARE));
+        let names: Vec<_> = meshes.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["default", "top"]);
+        assert_eq!(meshes[0].triangles.len(), 2);
+
+        // Every triangle of a mesh shares its material
+        assert!(meshes[0]
+            .triangles
+            .iter()
+            .all(|t| Arc::ptr_eq(&t.material, &meshes[0].material)));
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use vek::rgb::Rgb;
+use vek::vec::Vec2;
+
+use crate::exr;
+use crate::tonemap::ToneMap;
+
+type Receiver = std::sync::mpsc::Receiver<(Vec2<usize>, Rgb<f32>)>;
+
+/// The formats `Framebuffer::save` knows how to write.
+const FORMATS: [&str; 6] = ["png", "jpg", "jpeg", "bmp", "hdr", "exr"];
+
+/// Settings for the file writers.
+#[derive(Copy, Clone)]
+pub struct SaveOptions {
+    /// How to store the channels of OpenEXR images.
+    pub exr_type: exr::PixelType,
+
+    /// The display transform for 8 bit formats.
+    pub tonemap: ToneMap,
+}
+
+impl Default for SaveOptions {
+    fn default() -> SaveOptions {
+        SaveOptions {
+            exr_type: exr::PixelType::Half,
+            tonemap: ToneMap::default(),
+        }
+    }
+}
+
+/// Stores the pixels produced by the tracer, in row-major
+/// order with the first row at the top of the image.
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Rgb<f32>>,
+    received: usize,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Rgb::zero(); width * height],
+            received: 0,
+        }
+    }
+
+    /// Blocks until every pixel of a `width * height` image
+    /// arrived through the receiver, or the tracer hung up.
+    pub fn collect(receiver: Receiver, width: usize, height: usize) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height);
+
+        for (pos, rgb) in receiver.iter() {
+            fb.set_pixel(pos, rgb);
+
+            if fb.is_complete() {
+                break;
+            }
+        }
+
+        fb
+    }
+
+    /// Stores a pixel in the tracer's screen coordinates,
+    /// which have their origin at the bottom left.
+    pub fn set_pixel(&mut self, pos: Vec2<usize>, rgb: Rgb<f32>) {
+        if pos.x >= self.width || pos.y >= self.height {
+            return;
+        }
+
+        let row = self.height - 1 - pos.y;
+        self.pixels[row * self.width + pos.x] = rgb;
+        self.received += 1;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received >= self.width * self.height
+    }
+
+    /// Tone maps and quantizes the pixels to 8 bits per channel.
+    fn to_rgb8(&self, tonemap: &ToneMap) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .map(|p| tonemap.apply(*p))
+            .flat_map(|p| vec![p.r, p.g, p.b])
+            .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect()
+    }
+
+    /// Writes the image to disk, choosing the encoder
+    /// from the extension of the path.
+    ///
+    /// `.hdr` and `.exr` files keep the linear, unclamped
+    /// radiance; every other format is tone mapped and
+    /// quantized to 8 bits.
+    pub fn save(&self, path: &str, options: &SaveOptions) -> Result<(), String> {
+        let ext = format_of(path)?;
+
+        let file = File::create(path).map_err(|e| format!("Couldnt create {}: {}", path, e))?;
+        let mut file = BufWriter::new(file);
+
+        let (w, h) = (self.width as u32, self.height as u32);
+        let color = image::ColorType::RGB(8);
+
+        let ldr = || self.to_rgb8(&options.tonemap);
+
+        let res = match ext.as_str() {
+            "png" => image::png::PNGEncoder::new(file).encode(&ldr(), w, h, color),
+            "jpg" | "jpeg" => image::jpeg::JPEGEncoder::new(&mut file).encode(&ldr(), w, h, color),
+            "bmp" => image::bmp::BMPEncoder::new(&mut file).encode(&ldr(), w, h, color),
+            "hdr" => {
+                let pixels = self
+                    .pixels
+                    .iter()
+                    .map(|p| image::Rgb([p.r, p.g, p.b]))
+                    .collect::<Vec<_>>();
+                image::hdr::HDREncoder::new(file).encode(&pixels, self.width, self.height)
+            }
+            _ => {
+                let layers = [exr::Layer {
+                    name: "",
+                    pixels: &self.pixels,
+                }];
+                exr::write(
+                    &mut file,
+                    self.width,
+                    self.height,
+                    &layers,
+                    options.exr_type,
+                )
+            }
+        };
+
+        res.map_err(|e| format!("Couldnt save {}: {}", path, e))
+    }
+}
+
+/// Returns the lowercase extension of an output path,
+/// or an error if no encoder exists for it.
+pub fn format_of(path: &str) -> Result<String, String> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    if FORMATS.contains(&ext.as_str()) {
+        Ok(ext)
+    } else {
+        Err(format!(
+            "Couldnt save {}: unsupported image format `{}`",
+            path, ext
+        ))
+    }
+}
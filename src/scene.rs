@@ -0,0 +1,1124 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::obj;
+use crate::tonemap::{Operator, ToneMap};
+use crate::tracer::background::{
+    BackgroundLight, ConstantBackground, EnvironmentMap, GradientBackground, Sky,
+};
+use crate::tracer::bsdf::{BlackBody, Conductor, Dielectric, Glossy, Lambertian, Principled};
+use crate::tracer::bvh::Bvh;
+use crate::tracer::camera::MtxCamera;
+use crate::tracer::grid::Grid;
+use crate::tracer::light::{
+    AreaLight, DirectionalLight, LightSampler, PointLight, ShapeLight, SpotLight,
+};
+use crate::tracer::material::{BsdfModel, ConductorModel, Material, NormalMap, PrincipledModel};
+use crate::tracer::render_context::RenderContext;
+use crate::tracer::shape::{Carrier, Plane, Shape, Sphere, Triangle};
+use crate::tracer::texture::{
+    CheckerTexture, ConstantTexture, ImageTexture, NoiseTexture, Texture, Wrap,
+};
+use crate::tracer::volume::{
+    ExponentialMedium, Fog, GridMedium, HomogeneousMedium, Medium, Volume,
+};
+use crate::vol;
+
+/// Render settings given on the command line. Whatever
+/// is set here takes precedence over the scene file.
+#[derive(Default)]
+pub struct Overrides {
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub samples: Option<u16>,
+    pub n_threads: Option<u16>,
+    pub exposure: Option<f32>,
+    pub tonemap: Option<Operator>,
+    pub srgb: Option<bool>,
+
+    /// Skips building the bounding volume hierarchy.
+    pub brute_force: bool,
+}
+
+/// Everything described by a scene file.
+pub struct Scene {
+    pub ctx: RenderContext<MtxCamera>,
+
+    /// How the rendered image should be displayed.
+    pub tonemap: ToneMap,
+}
+
+/// Loads a scene description file.
+pub fn load(path: &str, overrides: &Overrides) -> Result<Scene, String> {
+    let src = std::fs::read_to_string(path).map_err(|e| format!("Couldnt open {}: {}", path, e))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse(&src, dir, overrides).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Parses the YAML source of a scene description.
+///
+/// A scene is a mapping with the keys `render`, `display`,
+/// `camera`, `textures`, `materials`, `objects`, `lights`,
+/// `background` and `fog`. See `res/scenes/default.yml` for an
+/// example of every one of them.
+///
+/// Files referenced by the scene, like meshes and images,
+/// are looked up relative to `dir`.
+pub fn parse(src: &str, dir: &Path, overrides: &Overrides) -> Result<Scene, String> {
+    let docs = YamlLoader::load_from_str(src).map_err(|e| e.to_string())?;
+    let doc = match docs.first() {
+        Some(doc) => Node::root(doc),
+        None => return Err("the scene file is empty".to_string()),
+    };
+    doc.as_hash()?;
+
+    let render = doc.get("render");
+    let width = match overrides.width {
+        Some(w) => w,
+        None => opt(render.as_ref(), "width", Node::as_usize)?.unwrap_or(640),
+    };
+    let height = match overrides.height {
+        Some(h) => h,
+        None => opt(render.as_ref(), "height", Node::as_usize)?.unwrap_or(320),
+    };
+    let samples = match overrides.samples {
+        Some(s) => s,
+        None => opt(render.as_ref(), "samples", Node::as_u16)?.unwrap_or(1),
+    };
+    let n_threads = match overrides.n_threads {
+        Some(n) => n,
+        None => match opt(render.as_ref(), "threads", Node::as_u16)? {
+            Some(n) => n,
+            // One per core
+            None => std::thread::available_parallelism().map_or(1, |n| n.get().min(65535) as u16),
+        },
+    };
+    // Only the command line can still set these to zero
+    if width == 0 || height == 0 || samples == 0 || n_threads == 0 {
+        return Err("the width, height, samples and threads must be at least 1".to_string());
+    }
+
+    let tonemap = parse_display(doc.get("display").as_ref(), overrides)?;
+
+    let camera = parse_camera(&doc.req("camera")?, width, height)?;
+
+    let mut library = Library {
+        textures: BTreeMap::new(),
+        materials: BTreeMap::new(),
+        dir,
+    };
+    if let Some(node) = doc.get("textures") {
+        for (name, tex) in node.entries()? {
+            let texture = parse_texture(&tex, &library)?;
+            library.textures.insert(name, texture);
+        }
+    }
+    if let Some(node) = doc.get("materials") {
+        for (name, mat) in node.entries()? {
+            let material = parse_material(&mat, &library)?;
+            library.materials.insert(name, material);
+        }
+    }
+
+    let mut objects: Vec<Arc<dyn Shape>> = Vec::new();
+    if let Some(node) = doc.get("objects") {
+        for obj in node.items()? {
+            if obj.req("type")?.as_str()? == "mesh" {
+                objects.extend(parse_mesh(&obj, &library)?);
+                continue;
+            }
+
+            objects.push(parse_object(&obj, &library)?);
+        }
+    }
+
+    // Emissive shapes light the scene like the other lights
+    let mut lights: Vec<Arc<dyn LightSampler>> = Vec::new();
+    for obj in objects.iter() {
+        if let Some(light) = ShapeLight::new(obj.clone()) {
+            lights.push(Arc::new(light));
+        }
+    }
+
+    if let Some(node) = doc.get("lights") {
+        for light in node.items()? {
+            lights.push(parse_light(&light)?);
+        }
+    }
+
+    lights.extend(parse_background(doc.get("background").as_ref(), dir)?);
+
+    let fog = match doc.get("fog") {
+        Some(node) => Some(parse_fog(&node)?),
+        None => None,
+    };
+
+    let bvh = if overrides.brute_force {
+        None
+    } else {
+        let bounds = objects.iter().map(|o| o.bounds()).collect::<Vec<_>>();
+        Some(Bvh::new(&bounds))
+    };
+
+    let ctx = RenderContext {
+        width,
+        height,
+        samples,
+        n_threads,
+        objects: Arc::new(objects),
+        bvh,
+        camera,
+        lights: Arc::new(lights),
+        fog,
+    };
+
+    Ok(Scene { ctx, tonemap })
+}
+
+fn parse_display(node: Option<&Node>, overrides: &Overrides) -> Result<ToneMap, String> {
+    let default = ToneMap::default();
+
+    let exposure = match overrides.exposure {
+        Some(e) => e,
+        None => opt(node, "exposure", Node::as_f32)?.unwrap_or(default.exposure),
+    };
+    let operator = match (overrides.tonemap, node.and_then(|n| n.get("tonemap"))) {
+        (Some(op), _) => op,
+        (None, Some(op)) => {
+            let name = op.as_str()?;
+            Operator::from_name(name)
+                .ok_or_else(|| op.error(&format!("unknown tone mapping operator `{}`", name)))?
+        }
+        (None, None) => default.operator,
+    };
+    let srgb = match overrides.srgb {
+        Some(s) => s,
+        None => opt(node, "srgb", Node::as_bool)?.unwrap_or(default.srgb),
+    };
+
+    Ok(ToneMap {
+        exposure,
+        operator,
+        srgb,
+    })
+}
+
+fn parse_camera(node: &Node, width: usize, height: usize) -> Result<MtxCamera, String> {
+    let position = node.req("position")?.as_vec3()?;
+    let target = node.req("target")?.as_vec3()?;
+    let up = match node.get("up") {
+        Some(up) => up.as_vec3()?,
+        None => Vec3::unit_y(),
+    };
+    let fov = match node.get("fov") {
+        Some(fov) => fov.as_f32()?.to_radians(),
+        None => std::f32::consts::FRAC_PI_3,
+    };
+
+    Ok(MtxCamera::new(position, target, up, fov, width, height))
+}
+
+/// The named textures and materials of a scene, which
+/// materials and objects can refer to.
+struct Library<'a> {
+    textures: BTreeMap<String, Arc<dyn Texture>>,
+    materials: BTreeMap<String, Material>,
+
+    /// Where image files are looked up.
+    dir: &'a Path,
+}
+
+fn parse_material(node: &Node, library: &Library) -> Result<Material, String> {
+    let emittance = match node.get("emittance") {
+        Some(emittance) => emittance.as_rgb()?,
+        None => Rgb::zero(),
+    };
+
+    let brdf = match node.get("brdf") {
+        Some(brdf) => brdf,
+        None => return parse_principled(node, emittance, library),
+    };
+
+    let bsdf: Arc<dyn BsdfModel> = match brdf.as_str()? {
+        "lambertian" => Arc::new(Lambertian::new(
+            opt(Some(node), "rho", Node::as_f32)?.unwrap_or(1.0),
+        )),
+        "glossy" => Arc::new(Glossy),
+        "blackbody" => Arc::new(BlackBody),
+        "dielectric" => Arc::new(Dielectric::new(
+            opt(Some(node), "ior", Node::as_f32)?.unwrap_or(1.5),
+            opt(Some(node), "absorption", Node::as_rgb)?.unwrap_or_else(Rgb::zero),
+        )),
+        "conductor" => parse_conductor(node, library)?,
+        "principled" => return parse_principled(node, emittance, library),
+        other => return Err(brdf.error(&format!("unknown brdf `{}`", other))),
+    };
+
+    // The albedo only tints what the model scatters,
+    // so it is white by default
+    Ok(Material {
+        albedo: texture_param(node, "albedo", Rgb::one(), library)?,
+        emittance,
+        bsdf,
+        normal_map: parse_normal_map(node, library)?,
+    })
+}
+
+/// The principled material is the one used when no `brdf` is
+/// given. Its base color defaults to a light gray, and its
+/// albedo only tints it, like for conductors. All of its
+/// parameters but the index of refraction can be textures.
+fn parse_principled(
+    node: &Node,
+    emittance: Rgb<f32>,
+    library: &Library,
+) -> Result<Material, String> {
+    let defaults = Principled::default();
+    let field =
+        |key: &str, default: f32| texture_param(node, key, Rgb::broadcast(default), library);
+
+    let principled = PrincipledModel {
+        base_color: texture_param(node, "base_color", defaults.base_color, library)?,
+        metallic: field("metallic", defaults.metallic)?,
+        roughness: field("roughness", defaults.roughness)?,
+        specular: field("specular", defaults.specular)?,
+        clearcoat: field("clearcoat", defaults.clearcoat)?,
+        clearcoat_roughness: field("clearcoat_roughness", defaults.clearcoat_roughness)?,
+        sheen: field("sheen", defaults.sheen)?,
+        transmission: field("transmission", defaults.transmission)?,
+        ior: opt(Some(node), "ior", Node::as_f32)?.unwrap_or(defaults.ior),
+    };
+
+    Ok(Material {
+        albedo: texture_param(node, "albedo", Rgb::one(), library)?,
+        emittance,
+        bsdf: Arc::new(principled),
+        normal_map: parse_normal_map(node, library)?,
+    })
+}
+
+/// A metal is either named, or given by its complex index of
+/// refraction `eta + i k`. The roughness is either one number
+/// or texture, or one number along the tangent and one along
+/// the bitangent for brushed metals; the GGX alpha is its square.
+fn parse_conductor(node: &Node, library: &Library) -> Result<Arc<dyn BsdfModel>, String> {
+    let (eta, k) = match node.get("metal") {
+        Some(metal) => {
+            let name = metal.as_str()?;
+            let preset = Conductor::metal(name, Vec2::zero())
+                .ok_or_else(|| metal.error(&format!("unknown metal `{}`", name)))?;
+            (preset.eta, preset.k)
+        }
+        None => (node.req("eta")?.as_rgb()?, node.req("k")?.as_rgb()?),
+    };
+
+    Ok(match node.get("roughness") {
+        Some(ref r) if r.yaml.as_vec().is_some() => {
+            let alpha = r.as_vec2()?.map(|r| r * r);
+            Arc::new(Conductor::new(alpha, eta, k))
+        }
+        Some(ref r) if r.as_f32().is_ok() => {
+            let alpha = Vec2::broadcast(r.as_f32()?.powi(2));
+            Arc::new(Conductor::new(alpha, eta, k))
+        }
+        Some(ref r) => Arc::new(ConductorModel {
+            roughness: parse_texture_param(r, library)?,
+            eta,
+            k,
+        }),
+        None => Arc::new(Conductor::new(Vec2::zero(), eta, k)),
+    })
+}
+
+/// A material has either a `normal_map`, whose images must
+/// not be decoded from sRGB, or a grayscale `bump_map` with
+/// a `bump_height`.
+fn parse_normal_map(node: &Node, library: &Library) -> Result<Option<NormalMap>, String> {
+    match (node.get("normal_map"), node.get("bump_map")) {
+        (Some(_), Some(bump)) => {
+            Err(bump.error("a material can't have both a normal and a bump map"))
+        }
+        (Some(map), None) => Ok(Some(NormalMap::Normal(parse_texture_param(&map, library)?))),
+        (None, Some(map)) => Ok(Some(NormalMap::Bump(
+            parse_texture_param(&map, library)?,
+            opt(Some(node), "bump_height", Node::as_f32)?.unwrap_or(1.0),
+        ))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Reads an optional parameter that can vary over a surface.
+fn texture_param(
+    node: &Node,
+    key: &str,
+    default: Rgb<f32>,
+    library: &Library,
+) -> Result<Arc<dyn Texture>, String> {
+    match node.get(key) {
+        Some(param) => parse_texture_param(&param, library),
+        None => Ok(Arc::new(ConstantTexture(default))),
+    }
+}
+
+/// Textured parameters are either a color or number, the name
+/// of a texture in the `textures` section, or a texture
+/// written inline.
+fn parse_texture_param(node: &Node, library: &Library) -> Result<Arc<dyn Texture>, String> {
+    match node.yaml {
+        Yaml::String(name) => library
+            .textures
+            .get(name)
+            .cloned()
+            .ok_or_else(|| node.error(&format!("unknown texture `{}`", name))),
+        Yaml::Hash(_) => parse_texture(node, library),
+        _ => Ok(Arc::new(ConstantTexture(node.as_rgb()?))),
+    }
+}
+
+/// Image textures repeat over the texture coordinates by
+/// default. Their colors are decoded from sRGB unless `srgb`
+/// is false, which is what non-color data like roughness
+/// maps need; HDR and EXR images are always linear.
+fn parse_texture(node: &Node, library: &Library) -> Result<Arc<dyn Texture>, String> {
+    let kind = node.req("type")?;
+
+    Ok(match kind.as_str()? {
+        "image" => {
+            let file = node.req("file")?;
+            let path = library.dir.join(file.as_str()?);
+            let wrap = match node.get("wrap") {
+                Some(wrap) => {
+                    let name = wrap.as_str()?;
+                    Wrap::from_name(name)
+                        .ok_or_else(|| wrap.error(&format!("unknown wrap mode `{}`", name)))?
+                }
+                None => Wrap::Repeat,
+            };
+            let srgb = opt(Some(node), "srgb", Node::as_bool)?.unwrap_or(true);
+
+            let image = ImageTexture::load(
+                &path.to_string_lossy(),
+                srgb,
+                wrap,
+                opt(Some(node), "scale", Node::as_scale)?.unwrap_or_else(Vec2::one),
+            )
+            .map_err(|e| file.error(&e))?;
+            Arc::new(image)
+        }
+        "checker" => Arc::new(CheckerTexture {
+            even: texture_param(node, "even", Rgb::one(), library)?,
+            odd: texture_param(node, "odd", Rgb::zero(), library)?,
+            scale: opt(Some(node), "scale", Node::as_scale)?
+                .unwrap_or_else(|| Vec2::broadcast(10.0)),
+        }),
+        "noise" => Arc::new(NoiseTexture::new(
+            texture_param(node, "low", Rgb::zero(), library)?,
+            texture_param(node, "high", Rgb::one(), library)?,
+            opt(Some(node), "scale", Node::as_f32)?.unwrap_or(1.0),
+            opt(Some(node), "octaves", Node::as_usize)?.unwrap_or(4),
+        )),
+        other => return Err(kind.error(&format!("unknown texture type `{}`", other))),
+    })
+}
+
+fn parse_object(node: &Node, library: &Library) -> Result<Arc<dyn Shape>, String> {
+    let kind = node.req("type")?;
+    let material = || lookup_material(&node.req("material")?, library);
+
+    Ok(match kind.as_str()? {
+        "sphere" => Arc::new(parse_sphere(node, material()?)?),
+        "plane" => Arc::new(parse_plane(node, material()?)?),
+        "triangle" => Arc::new(parse_triangle(node, library)?),
+        "volume" => {
+            // Carriers are invisible, so they need no material
+            let carrier = node.req("carrier")?;
+            let carrier_kind = carrier.req("type")?;
+            match carrier_kind.as_str()? {
+                "sphere" => {
+                    let sphere = parse_sphere(&carrier, Material::default())?;
+                    Arc::new(parse_volume(node, sphere, library)?)
+                }
+                "plane" => {
+                    let plane = parse_plane(&carrier, Material::default())?;
+                    Arc::new(parse_volume(node, plane, library)?)
+                }
+                other => {
+                    return Err(carrier_kind.error(&format!(
+                        "carriers must be convex, a sphere or a plane, not `{}`",
+                        other
+                    )))
+                }
+            }
+        }
+        other => return Err(kind.error(&format!("unknown object type `{}`", other))),
+    })
+}
+
+fn parse_sphere(node: &Node, material: Material) -> Result<Sphere, String> {
+    Ok(Sphere::new(
+        node.req("center")?.as_vec3()?,
+        node.req("radius")?.as_f32()?,
+        material,
+    ))
+}
+
+fn parse_plane(node: &Node, material: Material) -> Result<Plane, String> {
+    Ok(Plane::new(
+        node.req("point")?.as_vec3()?,
+        node.req("normal")?.as_vec3()?.normalized(),
+        material,
+    ))
+}
+
+/// A volume fills its carrier, which must be convex (see
+/// `Carrier`), with a medium that absorbs and scatters
+/// light, with the given coefficients where its `density`
+/// is 1. The density is either a number, a
+/// texture sampled on a grid over the bounds of the carrier,
+/// with `resolution` cells along its longest side, or a grid
+/// loaded from a `.vol` file (see `vol::load`), placed by its
+/// own bounds, then `scale`d and `translate`d like meshes. The
+/// scattered light is tinted by `color`, and `anisotropy`
+/// goes from -1 (back scattering) to 1 (forward scattering).
+fn parse_volume<S: Carrier>(
+    node: &Node,
+    carrier: S,
+    library: &Library,
+) -> Result<Volume<S>, String> {
+    let (extinction, albedo, anisotropy) = parse_scattering(node)?;
+
+    let density = node.req("density")?;
+    let medium: Box<dyn Medium> = match density.yaml {
+        Yaml::Hash(_) => {
+            let kind = density.req("type")?;
+            let grid = match kind.as_str()? {
+                "texture" => {
+                    let bounds = carrier.bounds().ok_or_else(|| {
+                        density.error("a varying density needs a bounded carrier")
+                    })?;
+                    let texture = parse_texture_param(&density.req("texture")?, library)?;
+                    let resolution =
+                        opt(Some(&density), "resolution", Node::as_usize)?.unwrap_or(32);
+
+                    let extent = bounds.max - bounds.min;
+                    let cell = extent.reduce_partial_max() / resolution.max(1) as f32;
+                    let size = extent.map(|e| (e / cell).ceil().max(1.0) as usize);
+
+                    Grid::from_fn(bounds, size, |p| texture.value(Vec2::zero(), p).max(0.0))
+                }
+                "file" => {
+                    let file = density.req("file")?;
+                    let path = library.dir.join(file.as_str()?);
+                    let scale = opt(Some(&density), "scale", Node::as_f32)?.unwrap_or(1.0);
+                    if scale <= 0.0 {
+                        return Err(density.req("scale")?.error("the scale must be positive"));
+                    }
+                    let translate =
+                        opt(Some(&density), "translate", Node::as_vec3)?.unwrap_or_else(Vec3::zero);
+
+                    vol::load(&path.to_string_lossy())
+                        .map_err(|e| file.error(&e))?
+                        .placed(scale, translate)
+                }
+                other => return Err(kind.error(&format!("unknown density type `{}`", other))),
+            };
+
+            Box::new(GridMedium {
+                density: grid,
+                extinction,
+            })
+        }
+        _ => {
+            let value = density.as_f32()?;
+            if value <= 0.0 {
+                return Err(density.error("the density must be positive"));
+            }
+            Box::new(HomogeneousMedium {
+                extinction: value * extinction,
+            })
+        }
+    };
+
+    Ok(Volume::new(carrier, medium, albedo, anisotropy))
+}
+
+/// Reads how a medium absorbs and scatters light, as for
+/// volumes, into its extinction where the density is 1, its
+/// albedo and its anisotropy.
+fn parse_scattering(node: &Node) -> Result<(f32, Rgb<f32>, f32), String> {
+    let absorption = opt(Some(node), "absorption", Node::as_f32)?.unwrap_or(0.0);
+    let scattering = opt(Some(node), "scattering", Node::as_f32)?.unwrap_or(1.0);
+    let extinction = absorption + scattering;
+    if absorption < 0.0 || scattering < 0.0 || extinction <= 0.0 {
+        return Err(node.error("the absorption and scattering must be positive"));
+    }
+
+    let color = opt(Some(node), "color", Node::as_rgb)?.unwrap_or_else(Rgb::one);
+    let anisotropy = opt(Some(node), "anisotropy", Node::as_f32)?.unwrap_or(0.0);
+    if anisotropy <= -1.0 || anisotropy >= 1.0 {
+        return Err(node
+            .req("anisotropy")?
+            .error("the anisotropy must be between -1 and 1"));
+    }
+
+    Ok((extinction, color * (scattering / extinction), anisotropy))
+}
+
+/// The fog fills the space around the objects with a medium
+/// that scatters like volumes do. It is either `homogeneous`,
+/// or `exponential`, with the given `density` at `height` and
+/// thinning out by a factor of e every `falloff` above it.
+///
+/// Homogeneous fog hides the background, which is infinitely
+/// far, while the exponential one lets it through upwards.
+fn parse_fog(node: &Node) -> Result<Fog, String> {
+    let kind = node.req("type")?;
+    let (extinction, albedo, anisotropy) = parse_scattering(node)?;
+
+    let density = node.req("density")?;
+    let value = density.as_f32()?;
+    if value <= 0.0 {
+        return Err(density.error("the density must be positive"));
+    }
+
+    let medium: Box<dyn Medium> = match kind.as_str()? {
+        "homogeneous" => Box::new(HomogeneousMedium {
+            extinction: value * extinction,
+        }),
+        "exponential" => {
+            let falloff = opt(Some(node), "falloff", Node::as_f32)?.unwrap_or(1.0);
+            if falloff <= 0.0 {
+                return Err(node.req("falloff")?.error("the falloff must be positive"));
+            }
+            Box::new(ExponentialMedium {
+                extinction: value * extinction,
+                height: opt(Some(node), "height", Node::as_f32)?.unwrap_or(0.0),
+                falloff,
+            })
+        }
+        other => return Err(kind.error(&format!("unknown fog type `{}`", other))),
+    };
+
+    Ok(Fog::new(medium, albedo, anisotropy))
+}
+
+fn parse_triangle(node: &Node, library: &Library) -> Result<Triangle, String> {
+    let vertices = node.req("vertices")?.as_triple(Node::as_vec3)?;
+    let material = lookup_material(&node.req("material")?, library)?;
+
+    let mut triangle = Triangle::new(vertices, Arc::new(material));
+
+    if let Some(normals) = node.get("normals") {
+        let normals = normals.as_triple(Node::as_vec3)?;
+        triangle.normals = Some([
+            normals[0].normalized(),
+            normals[1].normalized(),
+            normals[2].normalized(),
+        ]);
+    }
+    if let Some(tex_coords) = node.get("tex_coords") {
+        triangle.tex_coords = Some(tex_coords.as_triple(Node::as_vec2)?);
+    }
+
+    Ok(triangle)
+}
+
+/// Loads the meshes of an OBJ file. The `material`, if given,
+/// is used for the faces that have none in the file, and the
+/// optional `scale` and `translate` place the mesh in the scene.
+fn parse_mesh(node: &Node, library: &Library) -> Result<Vec<Arc<dyn Shape>>, String> {
+    let file = node.req("file")?;
+    let path = library.dir.join(file.as_str()?);
+
+    let material = match node.get("material") {
+        Some(mat) => lookup_material(&mat, library)?,
+        None => Material::default(),
+    };
+    let scale = opt(Some(node), "scale", Node::as_f32)?.unwrap_or(1.0);
+    if scale <= 0.0 {
+        return Err(node.req("scale")?.error("the scale must be positive"));
+    }
+    let translate = opt(Some(node), "translate", Node::as_vec3)?.unwrap_or_else(Vec3::zero);
+
+    let meshes = obj::load(&path.to_string_lossy(), material).map_err(|e| file.error(&e))?;
+
+    Ok(meshes
+        .into_iter()
+        .map(|mesh| Arc::new(mesh.transformed(scale, translate)) as Arc<dyn Shape>)
+        .collect())
+}
+
+/// Materials can either be referenced by their name in
+/// the `materials` section or be written inline.
+fn lookup_material(node: &Node, library: &Library) -> Result<Material, String> {
+    match node.yaml {
+        Yaml::String(name) => library
+            .materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| node.error(&format!("unknown material `{}`", name))),
+        _ => parse_material(node, library),
+    }
+}
+
+/// The background is a light like any other, unless it is
+/// black. Without a `background` section, it is a gradient.
+///
+/// A sky can come with a sun light in the same direction.
+fn parse_background(node: Option<&Node>, dir: &Path) -> Result<Vec<Arc<dyn LightSampler>>, String> {
+    let node = match node {
+        Some(node) => node,
+        None => {
+            return Ok(vec![Arc::new(BackgroundLight(
+                GradientBackground::default(),
+            ))])
+        }
+    };
+    let kind = node.req("type")?;
+
+    Ok(match kind.as_str()? {
+        "color" => {
+            let color = node.req("color")?.as_rgb()?;
+            if color == Rgb::zero() {
+                return Ok(Vec::new());
+            }
+            vec![Arc::new(BackgroundLight(ConstantBackground { color }))]
+        }
+        "gradient" => {
+            let default = GradientBackground::default();
+            vec![Arc::new(BackgroundLight(GradientBackground {
+                bottom: opt(Some(node), "bottom", Node::as_rgb)?.unwrap_or(default.bottom),
+                top: opt(Some(node), "top", Node::as_rgb)?.unwrap_or(default.top),
+            }))]
+        }
+        "map" => {
+            let file = node.req("file")?;
+            let path = dir.join(file.as_str()?);
+            let rotation = opt(Some(node), "rotation", Node::as_f32)?.unwrap_or(0.0);
+            let intensity = opt(Some(node), "intensity", Node::as_f32)?.unwrap_or(1.0);
+
+            let map =
+                EnvironmentMap::load(&path.to_string_lossy(), rotation.to_radians(), intensity)
+                    .map_err(|e| file.error(&e))?;
+            vec![Arc::new(map)]
+        }
+        "sky" => {
+            let sky = Sky::new(
+                node.req("elevation")?.as_f32()?.to_radians(),
+                opt(Some(node), "azimuth", Node::as_f32)?
+                    .unwrap_or(0.0)
+                    .to_radians(),
+                opt(Some(node), "turbidity", Node::as_f32)?.unwrap_or(3.0),
+                opt(Some(node), "intensity", Node::as_f32)?.unwrap_or(1.0),
+            );
+
+            let mut lights: Vec<Arc<dyn LightSampler>> = Vec::new();
+            if let Some(irradiance) = opt(Some(node), "sun_irradiance", Node::as_f32)? {
+                lights.push(Arc::new(DirectionalLight::new(
+                    -sky.sun_direction(),
+                    sky.sun_transmittance() * irradiance,
+                    opt(Some(node), "sun_angle", Node::as_f32)?
+                        .unwrap_or(0.53)
+                        .to_radians(),
+                )));
+            }
+            lights.push(Arc::new(sky));
+            lights
+        }
+        other => return Err(kind.error(&format!("unknown background type `{}`", other))),
+    })
+}
+
+fn parse_light(node: &Node) -> Result<Arc<dyn LightSampler>, String> {
+    let kind = node.req("type")?;
+
+    // The color only sets the hue, so that it can be
+    // picked independently of the brightness
+    let color = match node.get("color") {
+        Some(color) => color.as_rgb()?,
+        None => Rgb::one(),
+    };
+    let power = || -> Result<Rgb<f32>, String> { Ok(color * node.req("power")?.as_f32()?) };
+
+    Ok(match kind.as_str()? {
+        "point" => Arc::new(PointLight::new(node.req("position")?.as_vec3()?, power()?)),
+        "area" => Arc::new(AreaLight::new(
+            node.req("position")?.as_vec3()?,
+            node.req("radius")?.as_f32()?,
+            power()?,
+        )),
+        "sun" => Arc::new(DirectionalLight::new(
+            node.req("direction")?.as_vec3()?,
+            color * node.req("irradiance")?.as_f32()?,
+            opt(Some(node), "angle", Node::as_f32)?
+                .unwrap_or(0.0)
+                .to_radians(),
+        )),
+        "spot" => {
+            let outer = node.req("outer_angle")?.as_f32()?;
+            let inner = opt(Some(node), "inner_angle", Node::as_f32)?.unwrap_or(outer);
+
+            Arc::new(SpotLight::new(
+                node.req("position")?.as_vec3()?,
+                node.req("direction")?.as_vec3()?,
+                power()?,
+                inner.to_radians(),
+                outer.to_radians(),
+            ))
+        }
+        other => return Err(kind.error(&format!("unknown light type `{}`", other))),
+    })
+}
+
+/// Reads an optional field of an optional mapping.
+fn opt<'a, T, F>(node: Option<&Node<'a>>, key: &str, f: F) -> Result<Option<T>, String>
+where
+    F: Fn(&Node<'a>) -> Result<T, String>,
+{
+    match node.and_then(|n| n.get(key)) {
+        Some(field) => f(&field).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// A YAML node together with its path inside the document,
+/// so errors can point at the offending field.
+struct Node<'a> {
+    yaml: &'a Yaml,
+    path: String,
+}
+
+impl<'a> Node<'a> {
+    fn root(yaml: &'a Yaml) -> Node<'a> {
+        Node {
+            yaml,
+            path: String::new(),
+        }
+    }
+
+    fn error(&self, msg: &str) -> String {
+        if self.path.is_empty() {
+            msg.to_string()
+        } else {
+            format!("{}: {}", self.path, msg)
+        }
+    }
+
+    fn expected(&self, what: &str) -> String {
+        let found = match self.yaml {
+            Yaml::Real(_) | Yaml::Integer(_) => "a number".to_string(),
+            Yaml::String(s) => format!("the string `{}`", s),
+            Yaml::Boolean(_) => "a boolean".to_string(),
+            Yaml::Array(items) => format!("a list of {} items", items.len()),
+            Yaml::Hash(_) => "a mapping".to_string(),
+            _ => "nothing".to_string(),
+        };
+        self.error(&format!("expected {}, found {}", what, found))
+    }
+
+    fn child(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn as_hash(&self) -> Result<&'a yaml_rust::yaml::Hash, String> {
+        self.yaml
+            .as_hash()
+            .ok_or_else(|| self.expected("a mapping"))
+    }
+
+    fn get(&self, key: &str) -> Option<Node<'a>> {
+        let yaml = self.yaml.as_hash()?.get(&Yaml::String(key.to_string()))?;
+        Some(Node {
+            yaml,
+            path: self.child(key),
+        })
+    }
+
+    fn req(&self, key: &str) -> Result<Node<'a>, String> {
+        self.as_hash()?;
+        self.get(key)
+            .ok_or_else(|| self.error(&format!("missing field `{}`", key)))
+    }
+
+    fn items(&self) -> Result<Vec<Node<'a>>, String> {
+        let items = self.yaml.as_vec().ok_or_else(|| self.expected("a list"))?;
+        Ok(items
+            .iter()
+            .enumerate()
+            .map(|(i, yaml)| Node {
+                yaml,
+                path: format!("{}[{}]", self.path, i),
+            })
+            .collect())
+    }
+
+    fn entries(&self) -> Result<Vec<(String, Node<'a>)>, String> {
+        let mut entries = Vec::new();
+        for (key, yaml) in self.as_hash()?.iter() {
+            let key = match key {
+                Yaml::String(key) => key.clone(),
+                _ => return Err(self.error("keys must be strings")),
+            };
+            let path = self.child(&key);
+            entries.push((key, Node { yaml, path }));
+        }
+        Ok(entries)
+    }
+
+    fn as_str(&self) -> Result<&'a str, String> {
+        self.yaml.as_str().ok_or_else(|| self.expected("a string"))
+    }
+
+    fn as_bool(&self) -> Result<bool, String> {
+        self.yaml
+            .as_bool()
+            .ok_or_else(|| self.expected("a boolean"))
+    }
+
+    fn as_f32(&self) -> Result<f32, String> {
+        match self.yaml {
+            Yaml::Integer(i) => Ok(*i as f32),
+            Yaml::Real(_) => Ok(self.yaml.as_f64().unwrap() as f32),
+            _ => Err(self.expected("a number")),
+        }
+    }
+
+    fn as_usize(&self) -> Result<usize, String> {
+        match self.yaml {
+            Yaml::Integer(i) if *i > 0 => Ok(*i as usize),
+            _ => Err(self.expected("a positive integer")),
+        }
+    }
+
+    fn as_u16(&self) -> Result<u16, String> {
+        match self.yaml {
+            Yaml::Integer(i) if *i > 0 && *i <= i64::from(u16::MAX) => Ok(*i as u16),
+            _ => Err(self.expected("an integer between 1 and 65535")),
+        }
+    }
+
+    fn as_floats(&self, n: usize) -> Result<Vec<f32>, String> {
+        let what = format!("a list of {} numbers", n);
+        match self.yaml.as_vec() {
+            Some(items) if items.len() == n => self.items()?.iter().map(Node::as_f32).collect(),
+            _ => Err(self.expected(&what)),
+        }
+    }
+
+    fn as_vec2(&self) -> Result<Vec2<f32>, String> {
+        let v = self.as_floats(2)?;
+        Ok(Vec2::new(v[0], v[1]))
+    }
+
+    /// Scales can be written either as `[u, v]` or as a
+    /// single number for both.
+    fn as_scale(&self) -> Result<Vec2<f32>, String> {
+        match self.yaml {
+            Yaml::Integer(_) | Yaml::Real(_) => Ok(Vec2::broadcast(self.as_f32()?)),
+            _ => self.as_vec2(),
+        }
+    }
+
+    fn as_vec3(&self) -> Result<Vec3<f32>, String> {
+        let v = self.as_floats(3)?;
+        Ok(Vec3::new(v[0], v[1], v[2]))
+    }
+
+    /// Reads a list of exactly three items, one per
+    /// vertex of a triangle.
+    fn as_triple<T, F>(&self, f: F) -> Result<[T; 3], String>
+    where
+        F: Fn(&Node<'a>) -> Result<T, String>,
+    {
+        let items = self.items()?;
+        if items.len() != 3 {
+            return Err(self.expected("a list of 3 items"));
+        }
+
+        Ok([f(&items[0])?, f(&items[1])?, f(&items[2])?])
+    }
+
+    /// Colors can be written either as `[r, g, b]` or
+    /// as a single number for a shade of gray.
+    fn as_rgb(&self) -> Result<Rgb<f32>, String> {
+        match self.yaml {
+            Yaml::Integer(_) | Yaml::Real(_) => Ok(Rgb::broadcast(self.as_f32()?)),
+            _ => {
+                let v = self.as_floats(3)?;
+                Ok(Rgb::new(v[0], v[1], v[2]))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "camera: {position: [0, 0, 1], target: [0, 0, 0], fov: 60}\n";
+    const WHITE: &str = "materials:\n  white: {brdf: lambertian, albedo: 0.8}\n";
+
+    fn parse_scene(src: &str) -> Result<Scene, String> {
+        parse(
+            &format!("{}{}", CAMERA, src),
+            Path::new(""),
+            &Overrides::default(),
+        )
+    }
+
+    fn parse_error(src: &str) -> String {
+        match parse_scene(src) {
+            Ok(_) => panic!("the scene should not parse"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn minimal_scene() {
+        let scene = parse_scene(&format!(
+            "{}objects:\n  - {{type: sphere, center: [0, 0, -2], radius: 1, material: white}}\n",
+            WHITE
+        ))
+        .unwrap();
+
+        let ctx = &scene.ctx;
+        assert_eq!((ctx.width, ctx.height, ctx.samples), (640, 320, 1));
+        assert_eq!(ctx.objects.len(), 1);
+        assert!(ctx.bvh.is_some());
+        assert!(ctx.fog.is_none());
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let overrides = Overrides {
+            width: Some(8),
+            samples: Some(3),
+            ..Overrides::default()
+        };
+        let src = format!(
+            "{}render: {{width: 100, height: 50, samples: 16}}\n",
+            CAMERA
+        );
+        let scene = parse(&src, Path::new(""), &overrides).unwrap();
+
+        let ctx = &scene.ctx;
+        assert_eq!((ctx.width, ctx.height, ctx.samples), (8, 50, 3));
+    }
+
+    #[test]
+    fn albedo_defaults_to_white() {
+        let scene = parse_scene(concat!(
+            "materials:\n",
+            "  matte: {brdf: lambertian}\n",
+            "  mirror: {brdf: glossy}\n",
+            "objects:\n",
+            "  - {type: sphere, center: [0, 0, -2], radius: 1, material: matte}\n",
+            "  - {type: sphere, center: [0, 0, 2], radius: 1, material: mirror}\n",
+        ))
+        .unwrap();
+
+        for object in scene.ctx.objects.iter() {
+            let albedo = &object.material().albedo;
+            assert_eq!(albedo.color(Vec2::zero(), Vec3::zero()), Rgb::one());
+        }
+    }
+
+    #[test]
+    fn missing_field() {
+        assert_eq!(
+            parse(
+                "render: {samples: 4}\n",
+                Path::new(""),
+                &Overrides::default()
+            )
+            .err()
+            .unwrap(),
+            "missing field `camera`"
+        );
+        assert_eq!(
+            parse_error(&format!(
+                "{}objects:\n  - {{type: sphere, center: [0, 0, 0], material: white}}\n",
+                WHITE
+            )),
+            "objects[0]: missing field `radius`"
+        );
+    }
+
+    #[test]
+    fn wrong_type() {
+        assert_eq!(
+            parse_error("render: {width: wide}\n"),
+            "render.width: expected a positive integer, found the string `wide`"
+        );
+        assert_eq!(
+            parse_error(&format!(
+                "{}objects:\n  - {{type: sphere, center: [0, 0], radius: 1, material: white}}\n",
+                WHITE
+            )),
+            "objects[0].center: expected a list of 3 numbers, found a list of 2 items"
+        );
+    }
+
+    #[test]
+    fn unknown_type() {
+        assert_eq!(
+            parse_error("objects:\n  - {type: cube}\n"),
+            "objects[0].type: unknown object type `cube`"
+        );
+        assert_eq!(
+            parse_error("background: {type: stars}\n"),
+            "background.type: unknown background type `stars`"
+        );
+        assert_eq!(
+            parse_error("objects:\n  - {type: volume, carrier: {type: mesh}}\n"),
+            "objects[0].carrier.type: carriers must be convex, a sphere or a plane, not `mesh`"
+        );
+    }
+
+    #[test]
+    fn negative_mesh_scale() {
+        assert_eq!(
+            parse_error("objects:\n  - {type: mesh, file: teapot.obj, scale: 0}\n"),
+            "objects[0].scale: the scale must be positive"
+        );
+    }
+
+    #[test]
+    fn negative_grid_scale() {
+        assert_eq!(
+            parse_error(concat!(
+                "objects:\n  - type: volume\n",
+                "    carrier: {type: sphere, center: [0, 0, 0], radius: 1}\n",
+                "    density: {type: file, file: smoke.vol, scale: -2}\n",
+            )),
+            "objects[0].density.scale: the scale must be positive"
+        );
+    }
+
+    #[test]
+    fn zero_counts() {
+        assert_eq!(
+            parse_error("render: {samples: 0}\n"),
+            "render.samples: expected an integer between 1 and 65535, found a number"
+        );
+        assert_eq!(
+            parse_error("render: {height: 0}\n"),
+            "render.height: expected a positive integer, found a number"
+        );
+
+        let overrides = Overrides {
+            width: Some(0),
+            ..Overrides::default()
+        };
+        assert!(parse(CAMERA, Path::new(""), &overrides).is_err());
+    }
+}
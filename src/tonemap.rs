@@ -0,0 +1,224 @@
+use vek::rgb::Rgb;
+
+/// The curves that compress the unbounded radiance
+/// values into the displayable [0, 1] range.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Operator {
+    /// Keeps the values as they are, clipping everything above 1
+    Clamp,
+    /// Reinhard's `L / (1 + L)`, applied to the luminance
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+}
+
+impl Operator {
+    pub fn from_name(name: &str) -> Option<Operator> {
+        match name {
+            "clamp" => Some(Operator::Clamp),
+            "reinhard" => Some(Operator::Reinhard),
+            "aces" => Some(Operator::Aces),
+            _ => None,
+        }
+    }
+
+    /// The value of the `_Operator` uniform of
+    /// `res/show_texture.fs.glsl`.
+    pub fn shader_id(self) -> i32 {
+        match self {
+            Operator::Clamp => 0,
+            Operator::Reinhard => 1,
+            Operator::Aces => 2,
+        }
+    }
+}
+
+/// Turns the linear radiance produced by the tracer into
+/// display values. The visualizer does the same thing in its
+/// fragment shader, so both must be kept in sync.
+#[derive(Copy, Clone, Debug)]
+pub struct ToneMap {
+    /// Exposure adjustment, in stops
+    pub exposure: f32,
+    pub operator: Operator,
+    /// Whether to encode the result with the sRGB transfer function
+    pub srgb: bool,
+}
+
+impl Default for ToneMap {
+    fn default() -> ToneMap {
+        ToneMap {
+            exposure: 0.0,
+            operator: Operator::Clamp,
+            srgb: true,
+        }
+    }
+}
+
+impl ToneMap {
+    pub fn apply(&self, color: Rgb<f32>) -> Rgb<f32> {
+        let color = color * 2f32.powf(self.exposure);
+
+        let mapped = match self.operator {
+            Operator::Clamp => color,
+            Operator::Reinhard => {
+                let lum = luminance(color);
+                if lum > 0.0 {
+                    color * (1.0 / (1.0 + lum))
+                } else {
+                    color
+                }
+            }
+            Operator::Aces => color.map(|c| {
+                let c = c.max(0.0);
+                (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14)
+            }),
+        };
+
+        let mapped = mapped.map(|c| c.clamp(0.0, 1.0));
+
+        if self.srgb {
+            mapped.map(srgb_oetf)
+        } else {
+            mapped
+        }
+    }
+}
+
+/// Relative luminance of a linear Rec. 709 color.
+pub fn luminance(color: Rgb<f32>) -> f32 {
+    0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b
+}
+
+/// The sRGB opto-electronic transfer function.
+pub fn srgb_oetf(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// The inverse of `srgb_oetf`, from sRGB encoded values
+/// back to linear ones.
+pub fn srgb_eotf(c: f32) -> f32 {
+    if c <= 0.040_45 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [Operator; 3] = [Operator::Clamp, Operator::Reinhard, Operator::Aces];
+
+    /// Grays, saturated colors and negative ones, which
+    /// rounding can give.
+    const COLORS: [[f32; 3]; 5] = [
+        [1.0, 1.0, 1.0],
+        [1.0, 0.0, 0.0],
+        [0.1, 0.5, 3.0],
+        [0.0, 0.0, 0.2],
+        [-0.5, 0.2, 1.0],
+    ];
+
+    /// Every operator, with and without sRGB encoding, at a
+    /// few exposures.
+    fn tone_maps() -> impl Iterator<Item = ToneMap> {
+        OPERATORS.iter().flat_map(|&operator| {
+            [false, true].iter().flat_map(move |&srgb| {
+                [-2.0, 0.0, 1.5].iter().map(move |&exposure| ToneMap {
+                    exposure,
+                    operator,
+                    srgb,
+                })
+            })
+        })
+    }
+
+    #[test]
+    fn maps_into_the_unit_range() {
+        for tone_map in tone_maps() {
+            for color in COLORS.iter() {
+                for &scale in &[0.0, 1e-4, 0.01, 0.5, 1.0, 10.0, 1e6] {
+                    let c = tone_map.apply(Rgb::from(*color) * scale);
+                    for &c in &[c.r, c.g, c.b] {
+                        assert!((0.0..=1.0).contains(&c), "{:?} gives {}", tone_map, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn brighter_stays_brighter() {
+        for tone_map in tone_maps() {
+            for color in COLORS.iter() {
+                let mut previous = tone_map.apply(Rgb::zero());
+                for i in 1..=400 {
+                    let scale = 1e-3 * 1.04f32.powi(i);
+                    let c = tone_map.apply(Rgb::from(*color) * scale);
+                    assert!(
+                        c.r >= previous.r && c.g >= previous.g && c.b >= previous.b,
+                        "{:?} darkens {:?} from {:?} to {:?}",
+                        tone_map,
+                        color,
+                        previous,
+                        c
+                    );
+                    previous = c;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn curves_pass_through_known_values() {
+        let map = |operator, value: f32| {
+            let tone_map = ToneMap {
+                exposure: 0.0,
+                operator,
+                srgb: false,
+            };
+            tone_map.apply(Rgb::broadcast(value)).g
+        };
+
+        assert_eq!(map(Operator::Clamp, 0.25), 0.25);
+        assert_eq!(map(Operator::Clamp, 2.0), 1.0);
+        assert!((map(Operator::Reinhard, 1.0) - 0.5).abs() < 1e-6);
+        assert!((map(Operator::Reinhard, 3.0) - 0.75).abs() < 1e-6);
+        assert_eq!(map(Operator::Aces, 0.0), 0.0);
+        assert!(map(Operator::Aces, 1e3) > 0.99);
+
+        // A stop up is twice the light
+        let tone_map = ToneMap {
+            exposure: 1.0,
+            operator: Operator::Clamp,
+            srgb: false,
+        };
+        assert_eq!(tone_map.apply(Rgb::broadcast(0.2)).r, 0.4);
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for i in 0..=1000 {
+            let x = i as f32 / 1000.0;
+            let encoded = srgb_oetf(x);
+            assert!((0.0..=1.0).contains(&encoded));
+            assert!(
+                (srgb_eotf(encoded) - x).abs() <= 1e-5,
+                "{} comes back as {}",
+                x,
+                srgb_eotf(encoded)
+            );
+            assert!((srgb_oetf(srgb_eotf(x)) - x).abs() <= 1e-5);
+        }
+
+        // Both pieces meet at the seam
+        assert!((srgb_oetf(0.003_130_8) - 0.040_45).abs() < 1e-4);
+        assert!((srgb_eotf(0.040_45) - 0.003_130_8).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,649 @@
+use rand::Rng;
+use vek::rgb::Rgb;
+use vek::vec::Vec3;
+
+use crate::tonemap::luminance;
+
+use super::light::{LightHit, LightSample, LightSampler};
+use super::ray::Ray;
+use super::sampling::{random_square, uniform_sphere, uniform_sphere_pdf};
+use super::texture::load_image;
+
+/// The radiance arriving from infinitely far away, which
+/// the rays that escape the scene pick up.
+///
+/// Backgrounds light the scene through a `LightSampler`, so
+/// that light sampling can find them too.
+pub trait Background: Send + Sync {
+    fn radiance(&self, direction: Vec3<f32>) -> Rgb<f32>;
+}
+
+/// The same color in every direction.
+pub struct ConstantBackground {
+    pub color: Rgb<f32>,
+}
+
+impl Background for ConstantBackground {
+    fn radiance(&self, _direction: Vec3<f32>) -> Rgb<f32> {
+        self.color
+    }
+}
+
+/// A vertical blend between two colors.
+pub struct GradientBackground {
+    pub bottom: Rgb<f32>,
+    pub top: Rgb<f32>,
+}
+
+impl Default for GradientBackground {
+    fn default() -> GradientBackground {
+        GradientBackground {
+            bottom: Rgb::new(1.0, 1.0, 1.0),
+            top: Rgb::new(0.5, 0.7, 1.0),
+        }
+    }
+}
+
+impl Background for GradientBackground {
+    fn radiance(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        let t = 0.5 * (direction.normalized().y + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+}
+
+/// Lights the scene with a background that has no better
+/// way of being sampled, picking directions uniformly.
+pub struct BackgroundLight<B: Background>(pub B);
+
+impl<B: Background> LightSampler for BackgroundLight<B> {
+    fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
+        let direction = uniform_sphere(random_square(rng));
+
+        LightSample {
+            distance: f32::MAX,
+            ray: Ray {
+                origin: point,
+                direction,
+            },
+            radiance: self.0.radiance(direction),
+            pdf: uniform_sphere_pdf(),
+            delta: false,
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<LightHit> {
+        Some(LightHit {
+            distance: f32::MAX,
+            radiance: self.0.radiance(ray.direction),
+            pdf: uniform_sphere_pdf(),
+        })
+    }
+}
+
+/// A piecewise constant density over [0, 1).
+struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    fn new(func: Vec<f32>) -> Distribution1D {
+        let n = func.len() as f32;
+
+        let mut cdf = Vec::with_capacity(func.len() + 1);
+        cdf.push(0.0);
+        for f in func.iter() {
+            let last = cdf[cdf.len() - 1];
+            cdf.push(last + f / n);
+        }
+
+        let integral = cdf[func.len()];
+        if integral > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        } else {
+            // Nothing to favor, fall back to uniform
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n;
+            }
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    /// Returns the sampled point and the piece it falls in.
+    fn sample(&self, u: f32) -> (f32, usize) {
+        let i = (self.cdf.partition_point(|&c| c <= u) - 1).min(self.func.len() - 1);
+
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let t = if width > 0.0 {
+            (u - self.cdf[i]) / width
+        } else {
+            0.0
+        };
+
+        ((i as f32 + t) / self.func.len() as f32, i)
+    }
+
+    fn pdf(&self, i: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.func[i] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+/// A density over the directions, piecewise constant over
+/// the pixels of an equirectangular image with the center
+/// towards -z and the top row up.
+struct LatLongDistribution {
+    width: usize,
+    height: usize,
+
+    /// Rotation around the vertical axis, in radians
+    rotation: f32,
+
+    /// Picks a row, then one of `columns` picks the pixel
+    rows: Distribution1D,
+    columns: Vec<Distribution1D>,
+}
+
+impl LatLongDistribution {
+    /// A density proportional to `f` at the center of each pixel.
+    fn new<F>(width: usize, height: usize, rotation: f32, f: F) -> LatLongDistribution
+    where
+        F: Fn(usize, usize) -> f32,
+    {
+        let columns: Vec<Distribution1D> = (0..height)
+            .map(|y| {
+                // Rows near the poles cover a smaller solid angle
+                let sin_theta = (std::f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+                Distribution1D::new((0..width).map(|x| f(x, y).max(0.0) * sin_theta).collect())
+            })
+            .collect();
+        let rows = Distribution1D::new(columns.iter().map(|c| c.integral).collect());
+
+        LatLongDistribution {
+            width,
+            height,
+            rotation,
+            rows,
+            columns,
+        }
+    }
+
+    /// The image coordinates, in [0, 1), of a direction.
+    fn to_uv(&self, direction: Vec3<f32>) -> (f32, f32) {
+        let d = direction.normalized();
+        let phi = d.x.atan2(-d.z) - self.rotation;
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+
+        let u = (phi / (2.0 * std::f32::consts::PI) + 0.5).rem_euclid(1.0);
+        (u, theta / std::f32::consts::PI)
+    }
+
+    fn pixel(&self, direction: Vec3<f32>) -> (usize, usize) {
+        let (u, v) = self.to_uv(direction);
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    /// Returns the sampled direction and its pixel.
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> (Vec3<f32>, (usize, usize)) {
+        let square = random_square(rng);
+        let (v, y) = self.rows.sample(square.x);
+        let (u, x) = self.columns[y].sample(square.y);
+
+        let theta = v * std::f32::consts::PI;
+        let phi = (u - 0.5) * 2.0 * std::f32::consts::PI + self.rotation;
+        let direction = Vec3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        );
+
+        (direction, (x, y))
+    }
+
+    /// The solid angle density of sampling the direction.
+    fn pdf(&self, direction: Vec3<f32>) -> f32 {
+        let (x, y) = self.pixel(direction);
+
+        // From the horizontal components, which keep their
+        // precision near the poles, unlike `1 - y²`
+        let d = direction.normalized();
+        let sin_theta = (d.x * d.x + d.z * d.z).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let pdf_uv = self.rows.pdf(y) * self.columns[y].pdf(x);
+        pdf_uv / (2.0 * std::f32::consts::PI * std::f32::consts::PI * sin_theta)
+    }
+}
+
+/// An equirectangular image of the surroundings, with
+/// the center of the image towards -z and its top row up.
+/// Directions are importance sampled by their luminance.
+pub struct EnvironmentMap {
+    pixels: Vec<Rgb<f32>>,
+    distribution: LatLongDistribution,
+}
+
+impl EnvironmentMap {
+    /// Loads an image, preferably a `.hdr` or `.exr` one, scaling
+    /// its values by `intensity`. The rotation is in radians.
+    pub fn load(path: &str, rotation: f32, intensity: f32) -> Result<EnvironmentMap, String> {
+        let (width, height, pixels) = load_image(path, true)?;
+
+        let pixels = pixels.into_iter().map(|p| p * intensity).collect();
+        Ok(EnvironmentMap::new(width, height, pixels, rotation))
+    }
+
+    pub fn new(
+        width: usize,
+        height: usize,
+        pixels: Vec<Rgb<f32>>,
+        rotation: f32,
+    ) -> EnvironmentMap {
+        let distribution = LatLongDistribution::new(width, height, rotation, |x, y| {
+            luminance(pixels[y * width + x])
+        });
+
+        EnvironmentMap {
+            pixels,
+            distribution,
+        }
+    }
+
+    fn at(&self, (x, y): (usize, usize)) -> Rgb<f32> {
+        self.pixels[y * self.distribution.width + x]
+    }
+}
+
+impl Background for EnvironmentMap {
+    fn radiance(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        self.at(self.distribution.pixel(direction))
+    }
+}
+
+impl LightSampler for EnvironmentMap {
+    fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
+        let (direction, pixel) = self.distribution.sample(rng);
+
+        LightSample {
+            distance: f32::MAX,
+            ray: Ray {
+                origin: point,
+                direction,
+            },
+            radiance: self.at(pixel),
+            pdf: self.distribution.pdf(direction),
+            delta: false,
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<LightHit> {
+        Some(LightHit {
+            distance: f32::MAX,
+            radiance: self.radiance(ray.direction),
+            pdf: self.distribution.pdf(ray.direction),
+        })
+    }
+}
+
+/// The resolution of the table used to sample the sky.
+const SKY_TABLE_SIZE: (usize, usize) = (256, 128);
+
+/// Converts the luminances of the sky model, in kcd/m², so
+/// that a clear sky is a bit brighter than the default
+/// gradient.
+const SKY_SCALE: f32 = 0.1;
+
+/// The clear sky model of Preetham, Shirley and Smits (1999),
+/// giving the sky color for a sun position and turbidity.
+pub struct Sky {
+    /// Towards the sun
+    sun: Vec3<f32>,
+    /// The angle between the zenith and the sun
+    theta_sun: f32,
+    turbidity: f32,
+    intensity: f32,
+
+    /// Zenith values of the luminance Y and of the
+    /// chromaticities x and y
+    zenith: Vec3<f32>,
+    /// Perez coefficients of Y, x and y
+    perez: [[f32; 5]; 3],
+
+    distribution: LatLongDistribution,
+}
+
+impl Sky {
+    /// The sun is placed with its elevation over the horizon
+    /// and its azimuth from -z towards +x, both in radians.
+    /// The turbidity goes from 2 for a clear sky to about 10
+    /// for a hazy one.
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, intensity: f32) -> Sky {
+        let elevation = elevation.clamp(0.0, std::f32::consts::FRAC_PI_2);
+        let t = turbidity.clamp(1.7, 10.0);
+        let theta_sun = std::f32::consts::FRAC_PI_2 - elevation;
+
+        let sun = Vec3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let (th, th2, th3) = (
+            theta_sun,
+            theta_sun * theta_sun,
+            theta_sun * theta_sun * theta_sun,
+        );
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta_sun);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_x = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_yc = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        let mut sky = Sky {
+            sun,
+            theta_sun,
+            turbidity: t,
+            intensity,
+            zenith: Vec3::new(zenith_y.max(0.0), zenith_x, zenith_yc),
+            perez,
+            // Set below, once the sky can give its radiance
+            distribution: LatLongDistribution::new(1, 1, 0.0, |_, _| 1.0),
+        };
+
+        let (width, height) = SKY_TABLE_SIZE;
+        let dir = |x: usize, y: usize| {
+            let theta = std::f32::consts::PI * (y as f32 + 0.5) / height as f32;
+            let phi = 2.0 * std::f32::consts::PI * ((x as f32 + 0.5) / width as f32 - 0.5);
+            Vec3::new(
+                theta.sin() * phi.sin(),
+                theta.cos(),
+                -theta.sin() * phi.cos(),
+            )
+        };
+        sky.distribution = LatLongDistribution::new(width, height, 0.0, |x, y| {
+            luminance(sky.radiance(dir(x, y)))
+        });
+
+        sky
+    }
+
+    /// The direction towards the sun.
+    pub fn sun_direction(&self) -> Vec3<f32> {
+        self.sun
+    }
+
+    /// The color of the sunlight after crossing the
+    /// atmosphere, relative to its color outside of it, from
+    /// the Rayleigh and aerosol transmittances of the paper.
+    pub fn sun_transmittance(&self) -> Rgb<f32> {
+        let theta_deg = self.theta_sun.to_degrees().min(93.0);
+        // The relative optical mass of the air
+        let m = 1.0 / (self.theta_sun.cos() + 0.15 * (93.885 - theta_deg).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+
+        // Wavelengths in micrometers
+        Rgb::new(0.680f32, 0.550, 0.440).map(|lambda| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * m).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * m).exp();
+            rayleigh * aerosol
+        })
+    }
+
+    /// The Perez function of the given coefficients, for
+    /// the angle to the zenith and to the sun.
+    fn perez(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+        (1.0 + c[0] * (c[1] / cos_theta).exp())
+            * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+    }
+}
+
+impl Background for Sky {
+    /// Below the horizon, the sky gives the color of the horizon.
+    fn radiance(&self, direction: Vec3<f32>) -> Rgb<f32> {
+        let d = direction.normalized();
+        let d = Vec3::new(d.x, d.y.max(0.001), d.z).normalized();
+
+        let cos_theta = d.y;
+        let gamma = d.dot(self.sun).clamp(-1.0, 1.0).acos();
+
+        let mut values = [0f32; 3];
+        for (i, c) in self.perez.iter().enumerate() {
+            values[i] = self.zenith[i] * Sky::perez(c, cos_theta, gamma)
+                / Sky::perez(c, 1.0, self.theta_sun);
+        }
+        let [lum, x, y] = values;
+
+        if y <= 0.0 {
+            return Rgb::zero();
+        }
+
+        // xyY to XYZ to linear Rec. 709
+        let big_x = x / y * lum;
+        let big_z = (1.0 - x - y) / y * lum;
+        let rgb = Rgb::new(
+            3.2406 * big_x - 1.5372 * lum - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * lum + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * lum + 1.0570 * big_z,
+        );
+
+        rgb.map(|c| c.max(0.0) * SKY_SCALE * self.intensity)
+    }
+}
+
+impl LightSampler for Sky {
+    fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
+        let (direction, _) = self.distribution.sample(rng);
+
+        LightSample {
+            distance: f32::MAX,
+            ray: Ray {
+                origin: point,
+                direction,
+            },
+            radiance: self.radiance(direction),
+            pdf: self.distribution.pdf(direction),
+            delta: false,
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<LightHit> {
+        Some(LightHit {
+            distance: f32::MAX,
+            radiance: self.radiance(ray.direction),
+            pdf: self.distribution.pdf(ray.direction),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32::consts::PI;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const SAMPLES: usize = 200_000;
+
+    /// A small map with a bright spot, dark corners and a
+    /// black band, rotated so pixels don't line up with the axes.
+    fn map() -> EnvironmentMap {
+        let (width, height) = (16, 8);
+        let pixels = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                match (x, y) {
+                    (5, 2) => Rgb::new(50.0, 40.0, 30.0),
+                    (_, 6) => Rgb::zero(),
+                    _ => Rgb::broadcast(0.1 + (x * y) as f32 * 0.05),
+                }
+            })
+            .collect();
+        EnvironmentMap::new(width, height, pixels, 0.7)
+    }
+
+    /// The mean of the values, and its standard error.
+    fn mean(values: impl Iterator<Item = f32>) -> (f64, f64) {
+        let values: Vec<f64> = values.map(f64::from).collect();
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (mean, (variance / n).sqrt())
+    }
+
+    #[test]
+    fn samples_have_the_pdf_of_their_direction() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let map = map();
+        let distribution = &map.distribution;
+
+        let mut estimates = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            let (direction, (x, y)) = distribution.sample(&mut rng);
+            let pdf = distribution.pdf(direction);
+
+            // The density of the pixel, spread over its solid angle
+            let sin_theta = (direction.x * direction.x + direction.z * direction.z).sqrt();
+            let pixel_pdf = distribution.rows.pdf(y) * distribution.columns[y].pdf(x)
+                / (2.0 * PI * PI * sin_theta);
+            assert_eq!(distribution.pixel(direction), (x, y), "{:?}", direction);
+            assert!(
+                (pdf - pixel_pdf).abs() <= 1e-3 * pdf,
+                "{} != {}",
+                pdf,
+                pixel_pdf
+            );
+
+            estimates.push(luminance(map.radiance(direction)) / pdf);
+        }
+
+        // The luminance over the sphere, as found by sampling the
+        // map and by picking directions uniformly
+        let (sampled, sampled_error) = mean(estimates.into_iter());
+        let (uniform, uniform_error) = mean((0..SAMPLES).map(|_| {
+            let direction = uniform_sphere(random_square(&mut rng));
+            luminance(map.radiance(direction)) / uniform_sphere_pdf()
+        }));
+        assert!(
+            (sampled - uniform).abs() < 4.0 * (sampled_error + uniform_error),
+            "{} sampled, {} uniformly",
+            sampled,
+            uniform
+        );
+    }
+
+    #[test]
+    fn pdf_is_normalized() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let map = map();
+
+        let (integral, error) = mean((0..SAMPLES).map(|_| {
+            let direction = uniform_sphere(random_square(&mut rng));
+            map.distribution.pdf(direction) / uniform_sphere_pdf()
+        }));
+        assert!(
+            (integral - 1.0).abs() < 4.0 * error + 1e-3,
+            "the pdf integrates to {}",
+            integral
+        );
+    }
+
+    #[test]
+    fn sky_is_finite_and_non_negative() {
+        // Elevations at and below the horizon, which the sky
+        // clamps, and up to the zenith
+        for &elevation in &[-0.3, 0.0, 0.01, 0.4, 1.2, PI / 2.0] {
+            for &turbidity in &[2.0, 5.0, 10.0] {
+                let sky = Sky::new(elevation, 0.8, turbidity, 1.0);
+
+                let sun = sky.sun_transmittance();
+                for &c in &[sun.r, sun.g, sun.b] {
+                    assert!(c.is_finite() && (0.0..=1.0).contains(&c), "sun {:?}", sun);
+                }
+
+                // Down to the horizon and a bit below it, with
+                // one direction straight at the sun
+                let directions = (0..=20)
+                    .flat_map(|i| {
+                        let theta = PI * 0.55 * i as f32 / 20.0;
+                        (0..16).map(move |j| {
+                            let phi = 2.0 * PI * j as f32 / 16.0;
+                            Vec3::new(
+                                theta.sin() * phi.sin(),
+                                theta.cos(),
+                                -theta.sin() * phi.cos(),
+                            )
+                        })
+                    })
+                    .chain(std::iter::once(sky.sun_direction()));
+                for direction in directions {
+                    let radiance = sky.radiance(direction);
+                    for &c in &[radiance.r, radiance.g, radiance.b] {
+                        assert!(
+                            c.is_finite() && c >= 0.0,
+                            "{:?} at elevation {}, turbidity {}, towards {:?}",
+                            radiance,
+                            elevation,
+                            turbidity,
+                            direction
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sun_dims_towards_the_horizon() {
+        let sun =
+            |elevation: f32| luminance(Sky::new(elevation, 0.0, 3.0, 1.0).sun_transmittance());
+
+        assert!(sun(1.2) > sun(0.4));
+        assert!(sun(0.4) > sun(0.05));
+        assert!(sun(0.0) > 0.0);
+        assert_eq!(sun(-0.3), sun(0.0));
+    }
+}
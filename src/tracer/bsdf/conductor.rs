@@ -0,0 +1,175 @@
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use super::microfacet::{Ggx, MIN_ALPHA};
+use super::{flip, fresnel_conductor, reflect, upper_hemisphere, Bsdf, BsdfSample};
+
+/// A metal, rough or polished, modeled as microscopic mirrors
+/// oriented along the GGX distribution.
+#[derive(Copy, Clone, Debug)]
+pub struct Conductor {
+    /// The GGX roughness along the tangent and the bitangent,
+    /// different for brushed metals
+    pub alpha: Vec2<f32>,
+    /// The real and imaginary parts of the complex index of
+    /// refraction, per channel
+    pub eta: Rgb<f32>,
+    pub k: Rgb<f32>,
+}
+
+impl Conductor {
+    pub fn new(alpha: Vec2<f32>, eta: Rgb<f32>, k: Rgb<f32>) -> Conductor {
+        Conductor { alpha, eta, k }
+    }
+
+    /// A conductor made of a known metal, by name.
+    pub fn metal(name: &str, alpha: Vec2<f32>) -> Option<Conductor> {
+        // Measured at 650, 550 and 450nm
+        let (eta, k) = match name {
+            "gold" => (Rgb::new(0.143, 0.374, 1.442), Rgb::new(3.983, 2.385, 1.603)),
+            "copper" => (Rgb::new(0.200, 0.924, 1.102), Rgb::new(3.912, 2.452, 2.142)),
+            "aluminium" => (Rgb::new(1.657, 0.880, 0.521), Rgb::new(9.224, 6.270, 4.837)),
+            "silver" => (Rgb::new(0.155, 0.117, 0.138), Rgb::new(4.828, 3.122, 2.147)),
+            _ => return None,
+        };
+
+        Some(Conductor::new(alpha, eta, k))
+    }
+
+    fn is_smooth(&self) -> bool {
+        self.alpha.x < MIN_ALPHA && self.alpha.y < MIN_ALPHA
+    }
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> Rgb<f32> {
+        let (wo, wi) = upper_hemisphere(wo, wi);
+        if self.is_smooth() || wo.z <= 0f32 || wi.z <= 0f32 {
+            return Rgb::zero();
+        }
+
+        let h = (wo + wi).normalized();
+        fresnel_conductor(wo.dot(h), self.eta, self.k) * Ggx::new(self.alpha).reflectance(wo, wi)
+    }
+
+    fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Option<BsdfSample> {
+        let below = wo.z < 0f32;
+        let wo = if below { flip(wo) } else { wo };
+        if wo.z <= 0f32 {
+            return None;
+        }
+
+        if self.is_smooth() {
+            let wi = reflect(wo);
+            return Some(BsdfSample {
+                wi: if below { flip(wi) } else { wi },
+                f: fresnel_conductor(wo.z, self.eta, self.k) / wo.z,
+                pdf: 1f32,
+                specular: true,
+            });
+        }
+
+        let ggx = Ggx::new(self.alpha);
+        let (wi, h) = ggx.sample(wo, u);
+        if wi.z <= 0f32 {
+            return None;
+        }
+
+        let pdf = ggx.pdf(wo, wi);
+        if pdf <= 0f32 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi: if below { flip(wi) } else { wi },
+            f: fresnel_conductor(wo.dot(h), self.eta, self.k) * ggx.reflectance(wo, wi),
+            pdf,
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        let (wo, wi) = upper_hemisphere(wo, wi);
+        if self.is_smooth() || wo.z <= 0f32 || wi.z <= 0f32 {
+            return 0f32;
+        }
+
+        Ggx::new(self.alpha).pdf(wo, wi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::tracer::bsdf::tests::{albedo, assert_distributed, assert_samples_match};
+    use crate::tracer::sampling::{random_square, uniform_sphere};
+
+    /// A conductor that reflects all the light at every angle
+    fn white(alpha: Vec2<f32>) -> Conductor {
+        Conductor::new(alpha, Rgb::zero(), Rgb::one())
+    }
+
+    #[test]
+    fn samples_match_eval_and_pdf() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..500 {
+            let alpha = Vec2::new(rng.gen_range(0f32, 1f32), rng.gen_range(0f32, 1f32));
+            let wo = uniform_sphere(random_square(&mut rng));
+            assert_samples_match(&Conductor::metal("gold", alpha).unwrap(), wo, &mut rng);
+        }
+    }
+
+    #[test]
+    fn samples_follow_the_pdf() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for &alpha in &[
+            Vec2::broadcast(0.1),
+            Vec2::new(0.2, 0.6),
+            Vec2::broadcast(0.8),
+        ] {
+            let gold = Conductor::metal("gold", alpha).unwrap();
+            for &wo in &[
+                Vec3::new(0.3, -0.2, 0.9),
+                Vec3::new(-0.7, 0.1, 0.3),
+                Vec3::new(0.2, 0.5, -0.6),
+            ] {
+                assert_distributed(&gold, wo.normalized(), &mut rng);
+            }
+        }
+    }
+
+    #[test]
+    fn reflects_at_most_the_light_it_receives() {
+        let mut rng = StdRng::seed_from_u64(3);
+        assert_eq!(
+            white(Vec2::zero()).eval(Vec3::unit_z(), Vec3::unit_z()),
+            Rgb::zero()
+        );
+
+        for _ in 0..20 {
+            let alpha = Vec2::new(rng.gen_range(0f32, 1f32), rng.gen_range(0f32, 1f32));
+            let wo = uniform_sphere(random_square(&mut rng));
+            let albedo = albedo(&white(alpha), wo, &mut rng);
+            assert!(
+                albedo.iter().all(|&a| a <= 1.001),
+                "reflects {:?} with {:?} from {:?}",
+                albedo,
+                alpha,
+                wo
+            );
+        }
+
+        // All of it when smooth
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let albedo = albedo(&white(Vec2::zero()), wo, &mut rng);
+        assert!(
+            albedo.iter().all(|&a| (a - 1f32).abs() < 1e-4),
+            "{:?}",
+            albedo
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use super::{fresnel_dielectric, reflect, Bsdf, BsdfSample};
+
+/// A smooth boundary between two transparent media, like
+/// glass or water, that reflects and refracts light.
+#[derive(Copy, Clone, Debug)]
+pub struct Dielectric {
+    /// The index of refraction of the inside
+    pub ior: f32,
+    /// How much light is absorbed per unit of distance
+    /// traveled inside, per channel (Beer's law)
+    pub absorption: Rgb<f32>,
+}
+
+impl Dielectric {
+    pub fn new(ior: f32, absorption: Rgb<f32>) -> Dielectric {
+        Dielectric { ior, absorption }
+    }
+}
+
+impl Bsdf for Dielectric {
+    fn eval(&self, _wo: Vec3<f32>, _wi: Vec3<f32>) -> Rgb<f32> {
+        Rgb::zero()
+    }
+
+    /// Picks between the reflection and the refraction
+    /// according to the Fresnel reflectance, with `u.x`.
+    fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Option<BsdfSample> {
+        if wo.z == 0f32 {
+            return None;
+        }
+
+        // The shading frame has `wo` above the surface exactly
+        // on front-face hits (see `RayHit::frame`), the same flag
+        // with which the integrator applies the absorption
+        let entering = wo.z > 0f32;
+
+        // The ratio of the indices of refraction of
+        // the side of the ray and the other side
+        let eta = if entering { 1f32 / self.ior } else { self.ior };
+
+        let cos_i = wo.z.abs();
+        let sin2_t = eta * eta * (1f32 - cos_i * cos_i).max(0f32);
+
+        // Total internal reflection when it can't refract
+        let (reflectance, cos_t) = if sin2_t >= 1f32 {
+            (1f32, 0f32)
+        } else {
+            let cos_t = (1f32 - sin2_t).sqrt();
+            (fresnel_dielectric(cos_i, cos_t, eta), cos_t)
+        };
+
+        if u.x < reflectance {
+            Some(BsdfSample {
+                wi: reflect(wo),
+                f: Rgb::broadcast(reflectance / cos_i),
+                pdf: reflectance,
+                specular: true,
+            })
+        } else {
+            let normal = if entering {
+                Vec3::unit_z()
+            } else {
+                -Vec3::unit_z()
+            };
+
+            // Radiance changes with the squared ratio of the
+            // indices, as its solid angle is bent at the boundary
+            Some(BsdfSample {
+                wi: (-wo * eta + normal * (eta * cos_i - cos_t)).normalized(),
+                f: Rgb::broadcast((1f32 - reflectance) * eta * eta / cos_t),
+                pdf: 1f32 - reflectance,
+                specular: true,
+            })
+        }
+    }
+
+    fn pdf(&self, _wo: Vec3<f32>, _wi: Vec3<f32>) -> f32 {
+        0f32
+    }
+
+    fn absorption(&self) -> Rgb<f32> {
+        self.absorption
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_angle(degrees: f32, above: bool) -> Vec3<f32> {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Vec3::new(sin, 0f32, if above { cos } else { -cos })
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn refracts_entering() {
+        let glass = Dielectric::new(1.5, Rgb::zero());
+        let wo = at_angle(30f32, true);
+
+        let reflected = glass.sample(wo, Vec2::new(0f32, 0.5)).unwrap();
+        assert_eq!(reflected.wi, reflect(wo));
+        assert!(reflected.pdf > 0.03 && reflected.pdf < 0.05);
+
+        // Bent towards the normal, on the far side
+        let refracted = glass.sample(wo, Vec2::new(0.999, 0.5)).unwrap();
+        assert!(refracted.wi.z < 0f32);
+        assert_close(refracted.wi.x, -0.5 / 1.5);
+        assert_close(refracted.pdf, 1f32 - reflected.pdf);
+    }
+
+    #[test]
+    fn refracts_exiting() {
+        let glass = Dielectric::new(1.5, Rgb::zero());
+        let wo = at_angle(20f32, false);
+
+        // Bent away from the normal, out of the glass
+        let refracted = glass.sample(wo, Vec2::new(0.999, 0.5)).unwrap();
+        assert!(refracted.wi.z > 0f32);
+        assert_close(refracted.wi.x, -1.5 * 20f32.to_radians().sin());
+    }
+
+    #[test]
+    fn reflects_totally_past_the_critical_angle() {
+        let glass = Dielectric::new(1.5, Rgb::zero());
+        let wo = at_angle(60f32, false);
+
+        for &u in &[0f32, 0.5, 0.999] {
+            let sample = glass.sample(wo, Vec2::new(u, 0.5)).unwrap();
+            assert_eq!(sample.wi, reflect(wo));
+            assert_close(sample.pdf, 1f32);
+        }
+
+        // But refracts entering at the same angle
+        let sample = glass.sample(at_angle(60f32, true), Vec2::new(0.999, 0.5));
+        assert!(sample.unwrap().wi.z < 0f32);
+    }
+}
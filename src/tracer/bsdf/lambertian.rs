@@ -0,0 +1,52 @@
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use super::{flip, upper_hemisphere, Bsdf, BsdfSample};
+use crate::tracer::sampling::{cosine_hemisphere, cosine_hemisphere_pdf};
+
+/// A perfectly matte surface, reflecting the same
+/// amount of light in every direction.
+#[derive(Copy, Clone, Debug)]
+pub struct Lambertian {
+    /// The fraction of the light that gets reflected
+    pub rho: f32,
+}
+
+impl Lambertian {
+    pub fn new(rho: f32) -> Lambertian {
+        Lambertian { rho }
+    }
+}
+
+impl Bsdf for Lambertian {
+    fn eval(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> Rgb<f32> {
+        let (_, wi) = upper_hemisphere(wo, wi);
+        if wi.z <= 0f32 {
+            return Rgb::zero();
+        }
+
+        Rgb::broadcast(self.rho / std::f32::consts::PI)
+    }
+
+    fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Option<BsdfSample> {
+        let wi = cosine_hemisphere(u);
+        let wi = if wo.z < 0f32 { flip(wi) } else { wi };
+
+        let pdf = self.pdf(wo, wi);
+        if pdf <= 0f32 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi),
+            pdf,
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        let (_, wi) = upper_hemisphere(wo, wi);
+        cosine_hemisphere_pdf(wi.z)
+    }
+}
@@ -0,0 +1,108 @@
+use vek::vec::{Vec2, Vec3};
+
+/// Microfacet roughnesses below this are treated as perfectly
+/// smooth, as the distribution becomes too sharp to evaluate.
+pub const MIN_ALPHA: f32 = 1e-3;
+
+/// The GGX (Trowbridge-Reitz) distribution of microfacet
+/// normals, with the Smith masking and shadowing. All the
+/// directions are in the upper hemisphere of the local frame.
+#[derive(Copy, Clone, Debug)]
+pub struct Ggx {
+    /// The roughness along the tangent and the bitangent
+    pub alpha: Vec2<f32>,
+}
+
+impl Ggx {
+    pub fn new(alpha: Vec2<f32>) -> Ggx {
+        Ggx {
+            alpha: alpha.map(|a| a.max(MIN_ALPHA)),
+        }
+    }
+
+    /// The density of microfacets with the normal `h`.
+    pub fn d(&self, h: Vec3<f32>) -> f32 {
+        if h.z <= 0f32 {
+            return 0f32;
+        }
+
+        let x = h.x / self.alpha.x;
+        let y = h.y / self.alpha.y;
+        let t = x * x + y * y + h.z * h.z;
+
+        1f32 / (std::f32::consts::PI * self.alpha.x * self.alpha.y * t * t)
+    }
+
+    /// The Smith auxiliary function, from which the fraction
+    /// of microfacets visible from `w` is `1 / (1 + Λ)`.
+    pub fn lambda(&self, w: Vec3<f32>) -> f32 {
+        if w.z == 0f32 {
+            return f32::INFINITY;
+        }
+
+        let x = w.x * self.alpha.x;
+        let y = w.y * self.alpha.y;
+        let tan2 = (x * x + y * y) / (w.z * w.z);
+
+        0.5 * ((1f32 + tan2).sqrt() - 1f32)
+    }
+
+    /// The height correlated masking and shadowing
+    /// of the microfacets.
+    pub fn g2(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        1f32 / (1f32 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// The microfacet reflection, without the Fresnel term.
+    pub fn reflectance(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        let h = (wo + wi).normalized();
+        self.d(h) * self.g2(wo, wi) / (4f32 * wo.z * wi.z)
+    }
+
+    /// The density with which sampling the visible
+    /// normals reflects `wo` into `wi`.
+    pub fn pdf(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        // The density of the visible normals, changed
+        // to that of the reflected direction
+        let h = (wo + wi).normalized();
+        let g1 = 1f32 / (1f32 + self.lambda(wo));
+        self.d(h) * g1 / (4f32 * wo.z)
+    }
+
+    /// Picks a microfacet normal among the ones visible from
+    /// `wo`, proportionally to their projected area (Heitz, 2018).
+    pub fn sample_visible(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Vec3<f32> {
+        let alpha = self.alpha;
+
+        // Stretch the view so the distribution becomes a hemisphere
+        let v = Vec3::new(alpha.x * wo.x, alpha.y * wo.y, wo.z).normalized();
+
+        let len2 = v.x * v.x + v.y * v.y;
+        let t1 = if len2 > 0f32 {
+            Vec3::new(-v.y, v.x, 0f32) / len2.sqrt()
+        } else {
+            Vec3::unit_x()
+        };
+        let t2 = v.cross(t1);
+
+        // A point on the disk the hemisphere projects to,
+        // squashed to the part visible from `v`
+        let r = u.x.sqrt();
+        let phi = 2f32 * std::f32::consts::PI * u.y;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1f32 + v.z);
+        let p2 = (1f32 - s) * (1f32 - p1 * p1).max(0f32).sqrt() + s * r * phi.sin();
+
+        let n = t1 * p1 + t2 * p2 + v * (1f32 - p1 * p1 - p2 * p2).max(0f32).sqrt();
+
+        // Unstretch back
+        Vec3::new(alpha.x * n.x, alpha.y * n.y, n.z.max(1e-6)).normalized()
+    }
+
+    /// Reflects `wo` on a visible microfacet, returning
+    /// the direction and the microfacet normal.
+    pub fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> (Vec3<f32>, Vec3<f32>) {
+        let h = self.sample_visible(wo, u);
+        (-wo + h * (2f32 * wo.dot(h)), h)
+    }
+}
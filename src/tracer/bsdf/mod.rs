@@ -0,0 +1,347 @@
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+mod conductor;
+mod dielectric;
+mod lambertian;
+mod microfacet;
+mod principled;
+mod specular;
+
+pub use conductor::Conductor;
+pub use dielectric::Dielectric;
+pub use lambertian::Lambertian;
+pub use principled::Principled;
+pub use specular::{BlackBody, Glossy};
+
+/// How a surface scatters light: a bidirectional scattering
+/// distribution function.
+///
+/// Everything happens in the local shading frame, where the
+/// shading normal pointing out of the shape is +z. `wo` points
+/// back along the path (towards the camera), and `wi` is where
+/// the path continues (towards the light). Both are normalized,
+/// and `wo.z` is negative when the surface is seen from inside.
+///
+/// `sample` must agree with `eval` and `pdf`, or the weights
+/// of multiple importance sampling get biased; the tests check
+/// every model against this with `tests::assert_consistent`.
+pub trait Bsdf: Send + Sync {
+    /// The fraction of the light arriving from `wi` that leaves
+    /// towards `wo`, per unit of solid angle. Zero for specular
+    /// BSDFs, which can only be sampled.
+    fn eval(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> Rgb<f32>;
+
+    /// Picks `wi` by importance sampling the BSDF, given two
+    /// uniform numbers in [0, 1). Returns `None` if the surface
+    /// scatters no light.
+    fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Option<BsdfSample>;
+
+    /// The density with which `sample` picks `wi`, zero for
+    /// specular directions.
+    fn pdf(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32;
+
+    /// The fraction of light absorbed per unit of distance
+    /// traveled inside the surface.
+    fn absorption(&self) -> Rgb<f32> {
+        Rgb::zero()
+    }
+}
+
+/// A direction picked by `Bsdf::sample`.
+#[derive(Copy, Clone, Debug)]
+pub struct BsdfSample {
+    pub wi: Vec3<f32>,
+
+    /// The value of the BSDF for `wi`. For specular samples it
+    /// is whatever makes `f * |wi.z| / pdf` the throughput.
+    pub f: Rgb<f32>,
+
+    /// The solid angle density with which `wi` was picked.
+    /// Only the probability of the choice for specular samples.
+    pub pdf: f32,
+
+    /// Whether the direction was the only one possible, like
+    /// for a mirror. Light sampling can't find these, so they
+    /// are never weighted against it.
+    pub specular: bool,
+}
+
+/// Opaque surfaces scatter the same on both sides, so when seen
+/// from below they are evaluated with both directions mirrored.
+fn upper_hemisphere(wo: Vec3<f32>, wi: Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    if wo.z < 0f32 {
+        (flip(wo), flip(wi))
+    } else {
+        (wo, wi)
+    }
+}
+
+/// Mirrors a direction across the surface.
+fn flip(w: Vec3<f32>) -> Vec3<f32> {
+    Vec3::new(w.x, w.y, -w.z)
+}
+
+/// Mirrors a direction around the normal.
+fn reflect(w: Vec3<f32>) -> Vec3<f32> {
+    Vec3::new(-w.x, -w.y, w.z)
+}
+
+/// The Fresnel reflectance of unpolarized light at a smooth
+/// dielectric boundary, given the cosines of the incident and
+/// transmitted angles and the ratio of the indices of refraction.
+fn fresnel_dielectric(cos_i: f32, cos_t: f32, eta: f32) -> f32 {
+    let r_parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let r_perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+/// The Fresnel reflectance of unpolarized light on a conductor
+/// with the complex index of refraction `eta + i k`, per channel.
+fn fresnel_conductor(cos_i: f32, eta: Rgb<f32>, k: Rgb<f32>) -> Rgb<f32> {
+    let cos_i = cos_i.clamp(0f32, 1f32);
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1f32 - cos2;
+
+    let f = |eta: f32, k: f32| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = (t0 * t0 + 4f32 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2b2 + t0)).max(0f32).sqrt();
+
+        let t1 = a2b2 + cos2;
+        let t2 = 2f32 * cos_i * a;
+        let r_perpendicular = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let r_parallel = r_perpendicular * (t3 - t4) / (t3 + t4);
+
+        0.5 * (r_parallel + r_perpendicular)
+    };
+
+    Rgb::new(f(eta.r, k.r), f(eta.g, k.g), f(eta.b, k.b))
+}
+
+/// Schlick's approximation of the Fresnel reflectance, from the
+/// reflectance `f0` at normal incidence to `scale` at grazing angles.
+fn schlick(f0: Rgb<f32>, scale: f32, cos: f32) -> Rgb<f32> {
+    let t = (1f32 - cos.clamp(0f32, 1f32)).powi(5);
+    f0 + (Rgb::broadcast(scale) - f0) * t
+}
+
+/// Checks shared by the tests of every model, that it honors
+/// the contract of `Bsdf`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32::consts::PI;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::tracer::sampling::random_square;
+
+    const SAMPLES: usize = 100_000;
+    const COS_BINS: usize = 10;
+    const PHI_BINS: usize = 20;
+
+    /// The sub-cells of each bin over which the pdf is integrated
+    const SUBDIVISIONS: usize = 16;
+
+    /// Checks that the model honors the contract of `Bsdf`
+    /// from a few directions, above and below the surface.
+    pub fn assert_consistent(bsdf: &dyn Bsdf, rng: &mut StdRng) {
+        for &wo in &[
+            Vec3::new(0.1, 0.2, 0.95),
+            Vec3::new(-0.7, 0.1, 0.3),
+            Vec3::new(0.2, 0.5, -0.6),
+            Vec3::new(0.9, -0.3, -0.1),
+        ] {
+            let wo = wo.normalized();
+            assert_samples_match(bsdf, wo, rng);
+            assert_distributed(bsdf, wo, rng);
+        }
+    }
+
+    /// Checks that the samples leaving towards `wo` come with
+    /// the value and the density that `eval` and `pdf` give
+    /// for their direction, unless they are specular.
+    pub fn assert_samples_match(bsdf: &dyn Bsdf, wo: Vec3<f32>, rng: &mut StdRng) {
+        for _ in 0..64 {
+            let sample = match bsdf.sample(wo, random_square(rng)) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            assert_unit(sample.wi);
+            assert!(
+                sample.pdf > 0f32 && sample.pdf.is_finite(),
+                "pdf of {} for {:?}",
+                sample.pdf,
+                sample
+            );
+            assert!(
+                sample.f.iter().all(|f| *f >= 0f32 && f.is_finite()),
+                "{:?}",
+                sample
+            );
+            if sample.specular {
+                assert!(sample.pdf <= 1f32, "{:?}", sample);
+                continue;
+            }
+
+            let (f, pdf) = (bsdf.eval(wo, sample.wi), bsdf.pdf(wo, sample.wi));
+            assert!(
+                (sample.pdf - pdf).abs() <= 1e-4 * pdf,
+                "sampled a pdf of {} but pdf gives {} for {:?} from {:?}",
+                sample.pdf,
+                pdf,
+                sample.wi,
+                wo
+            );
+            assert!(
+                (sample.f - f)
+                    .iter()
+                    .all(|d| d.abs() <= 1e-4 * f.reduce_partial_max()),
+                "sampled {:?} but eval gives {:?} for {:?} from {:?}",
+                sample.f,
+                f,
+                sample.wi,
+                wo
+            );
+        }
+    }
+
+    /// Checks that the directions sampled towards `wo` are
+    /// distributed along `pdf`, with a chi-squared test on bins
+    /// of the sphere, equally sized along the cosine of the
+    /// polar angle and along the azimuth. Specular samples and
+    /// failed ones count towards none of the bins, as `pdf`
+    /// doesn't see them. Lobes much sharper than a bin, like
+    /// GGX with an alpha under about 0.05, can't be checked.
+    pub fn assert_distributed(bsdf: &dyn Bsdf, wo: Vec3<f32>, rng: &mut StdRng) {
+        let bin_of = |w: Vec3<f32>| {
+            let cos = ((w.z + 1f32) / 2f32 * COS_BINS as f32) as usize;
+            let phi = ((w.y.atan2(w.x) + PI) / (2f32 * PI) * PHI_BINS as f32) as usize;
+            cos.min(COS_BINS - 1) * PHI_BINS + phi.min(PHI_BINS - 1)
+        };
+
+        let mut counts = vec![0f64; COS_BINS * PHI_BINS];
+        for _ in 0..SAMPLES {
+            if let Some(sample) = bsdf.sample(wo, random_square(rng)) {
+                if !sample.specular {
+                    counts[bin_of(sample.wi)] += 1f64;
+                }
+            }
+        }
+
+        // The integral of the pdf over each bin, by the midpoint
+        // rule. Solid angles are areas in cosine and azimuth.
+        let (d_cos, d_phi) = (2f32 / COS_BINS as f32, 2f32 * PI / PHI_BINS as f32);
+        let (s_cos, s_phi) = (d_cos / SUBDIVISIONS as f32, d_phi / SUBDIVISIONS as f32);
+        let mut expected = vec![0f64; COS_BINS * PHI_BINS];
+        for (bin, expected) in expected.iter_mut().enumerate() {
+            for i in 0..SUBDIVISIONS * SUBDIVISIONS {
+                let cos = -1f32
+                    + (bin / PHI_BINS) as f32 * d_cos
+                    + ((i / SUBDIVISIONS) as f32 + 0.5) * s_cos;
+                let phi = -PI
+                    + (bin % PHI_BINS) as f32 * d_phi
+                    + ((i % SUBDIVISIONS) as f32 + 0.5) * s_phi;
+                let sin = (1f32 - cos * cos).max(0f32).sqrt();
+                let wi = Vec3::new(sin * phi.cos(), sin * phi.sin(), cos);
+                *expected += f64::from(bsdf.pdf(wo, wi) * s_cos * s_phi) * SAMPLES as f64;
+            }
+        }
+
+        // Bins too unlikely for the test are pooled together
+        let (mut chi2, mut dof) = (0f64, 0);
+        let (mut rest_count, mut rest_expected) = (0f64, 0f64);
+        for (&count, &expected) in counts.iter().zip(expected.iter()) {
+            if expected < 5f64 {
+                rest_count += count;
+                rest_expected += expected;
+            } else {
+                chi2 += (count - expected).powi(2) / expected;
+                dof += 1;
+            }
+        }
+        if rest_expected >= 5f64 {
+            chi2 += (rest_count - rest_expected).powi(2) / rest_expected;
+            dof += 1;
+        } else {
+            assert!(
+                rest_count < 20f64,
+                "{} samples where pdf expects {}, from {:?}",
+                rest_count,
+                rest_expected,
+                wo
+            );
+        }
+
+        // Above the 99.9th percentile of the chi-squared
+        // distribution, which is about dof + 3.1 sqrt(2 dof)
+        let dof = (dof.max(2) - 1) as f64;
+        let limit = dof + 4f64 * (2f64 * dof).sqrt() + 5f64;
+        assert!(
+            chi2 < limit,
+            "chi-squared of {} over {} degrees of freedom from {:?}",
+            chi2,
+            dof,
+            wo
+        );
+    }
+
+    /// Estimates the fraction of the light arriving from all
+    /// around that leaves towards `wo`, from the samples.
+    pub fn albedo(bsdf: &dyn Bsdf, wo: Vec3<f32>, rng: &mut StdRng) -> Rgb<f32> {
+        let total = (0..SAMPLES)
+            .filter_map(|_| bsdf.sample(wo, random_square(rng)))
+            .map(|s| s.f * (s.wi.z.abs() / s.pdf))
+            .fold(Rgb::zero(), |a, b| a + b);
+        total / SAMPLES as f32
+    }
+
+    fn assert_unit(w: Vec3<f32>) {
+        assert!(
+            (w.magnitude() - 1f32).abs() < 1e-4,
+            "{:?} isn't normalized",
+            w
+        );
+    }
+
+    #[test]
+    fn every_model_honors_the_contract() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let models: Vec<Box<dyn Bsdf>> = vec![
+            Box::new(Lambertian::new(0.8)),
+            Box::new(Glossy),
+            Box::new(Dielectric::new(1.5, Rgb::zero())),
+            Box::new(Conductor::metal("copper", Vec2::new(0.2, 0.4)).unwrap()),
+            Box::new(Principled {
+                transmission: 0.3,
+                clearcoat: 0.5,
+                clearcoat_roughness: 0.3,
+                ..Principled::default()
+            }),
+        ];
+
+        for bsdf in models.iter() {
+            assert_consistent(&**bsdf, &mut rng);
+        }
+    }
+
+    #[test]
+    fn lambertian_reflects_its_albedo() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for &wo in &[Vec3::new(0.6, 0.0, 0.8), Vec3::new(0.0, -0.8, -0.6)] {
+            let albedo = albedo(&Lambertian::new(0.8), wo, &mut rng);
+            assert!(
+                albedo.iter().all(|&a| (a - 0.8).abs() < 1e-3),
+                "{:?}",
+                albedo
+            );
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use super::microfacet::Ggx;
+use super::{flip, schlick, upper_hemisphere, Bsdf, BsdfSample, Dielectric};
+use crate::tracer::sampling::{cosine_hemisphere, cosine_hemisphere_pdf};
+
+/// One material for most surfaces, blending plastics, metals and
+/// glass with a few artist friendly parameters, after the Disney
+/// BRDF (Burley, 2012). All but `ior` are between 0 and 1.
+///
+/// It is made of a diffuse base with a sheen, a specular layer
+/// shared by its dielectric and metallic parts, a clearcoat on
+/// top, and a smooth transmission for its glass part.
+#[derive(Copy, Clone, Debug)]
+pub struct Principled {
+    /// The diffuse color, or the reflectance of metals
+    pub base_color: Rgb<f32>,
+    /// Blends from a dielectric to a metal
+    pub metallic: f32,
+    pub roughness: f32,
+    /// The dielectric reflectance at normal incidence,
+    /// where 0.5 is 4% like most materials
+    pub specular: f32,
+    /// The strength of a second, colorless specular layer
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    /// A soft white reflection at grazing angles, for cloth
+    pub sheen: f32,
+    /// Blends from an opaque dielectric to glass
+    pub transmission: f32,
+    /// The index of refraction of the glass part
+    pub ior: f32,
+}
+
+impl Default for Principled {
+    fn default() -> Principled {
+        Principled {
+            base_color: Rgb::broadcast(0.8),
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.1,
+            sheen: 0.0,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+}
+
+impl Principled {
+    fn ggx(&self) -> Ggx {
+        Ggx::new(Vec2::broadcast(self.roughness * self.roughness))
+    }
+
+    fn clearcoat_ggx(&self) -> Ggx {
+        Ggx::new(Vec2::broadcast(
+            self.clearcoat_roughness * self.clearcoat_roughness,
+        ))
+    }
+
+    /// The part of the surface that is neither metal nor glass.
+    fn dielectric(&self) -> f32 {
+        (1f32 - self.metallic) * (1f32 - self.transmission)
+    }
+
+    /// The reflectance of the specular layer at normal incidence,
+    /// with the parts of the surface that are diffuse dielectric
+    /// and the part that is metal.
+    fn specular_f0(&self) -> (Rgb<f32>, f32) {
+        let dielectric = self.dielectric();
+        let f0 =
+            Rgb::broadcast(0.08 * self.specular * dielectric) + self.base_color * self.metallic;
+
+        (f0, dielectric + self.metallic)
+    }
+
+    /// How often each lobe gets sampled when leaving towards `wo`:
+    /// the diffuse, specular, clearcoat and transmission ones.
+    /// `None` if the surface scatters nothing.
+    fn lobe_probabilities(&self, wo: Vec3<f32>) -> Option<[f32; 4]> {
+        let luminance = |c: Rgb<f32>| 0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b;
+        let cos = wo.z.abs();
+
+        let (f0, scale) = self.specular_f0();
+        let weights = [
+            self.dielectric() * (luminance(self.base_color) + self.sheen * 0.1),
+            luminance(schlick(f0, scale, cos)),
+            0.25 * self.clearcoat * schlick(Rgb::broadcast(0.04), 1f32, cos).r,
+            (1f32 - self.metallic) * self.transmission * luminance(self.base_color),
+        ];
+
+        let total: f32 = weights.iter().sum();
+        if total <= 0f32 {
+            return None;
+        }
+
+        Some([
+            weights[0] / total,
+            weights[1] / total,
+            weights[2] / total,
+            weights[3] / total,
+        ])
+    }
+}
+
+impl Bsdf for Principled {
+    /// Only the reflection of the opaque lobes, as
+    /// the transmission is specular.
+    fn eval(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> Rgb<f32> {
+        let (wo, wi) = upper_hemisphere(wo, wi);
+        if wo.z <= 0f32 || wi.z <= 0f32 {
+            return Rgb::zero();
+        }
+
+        let h = (wo + wi).normalized();
+        let cos_d = wi.dot(h);
+
+        // Diffuse with a retro-reflection on rough surfaces
+        let fd90 = 0.5 + 2f32 * self.roughness * cos_d * cos_d;
+        let fd = |cos: f32| 1f32 + (fd90 - 1f32) * (1f32 - cos).powi(5);
+        let diffuse = self.base_color * (fd(wo.z) * fd(wi.z) / std::f32::consts::PI)
+            + Rgb::broadcast(self.sheen * (1f32 - cos_d).powi(5));
+
+        let (f0, scale) = self.specular_f0();
+        let specular = schlick(f0, scale, cos_d) * self.ggx().reflectance(wo, wi);
+
+        let clearcoat = if self.clearcoat > 0f32 {
+            0.25 * self.clearcoat
+                * schlick(Rgb::broadcast(0.04), 1f32, cos_d).r
+                * self.clearcoat_ggx().reflectance(wo, wi)
+        } else {
+            0f32
+        };
+
+        diffuse * self.dielectric() + specular + Rgb::broadcast(clearcoat)
+    }
+
+    fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Option<BsdfSample> {
+        let [diffuse, specular, _, transmission] = self.lobe_probabilities(wo)?;
+
+        // The glass part is specular, so it is weighted on its own
+        // rather than through the mixture pdf. `u.x` is stretched
+        // back to [0, 1) once a lobe is picked with it.
+        if u.x < transmission {
+            let u = Vec2::new(u.x / transmission, u.y);
+            let sample = Dielectric::new(self.ior, Rgb::zero()).sample(wo, u)?;
+            let glass = (1f32 - self.metallic) * self.transmission / transmission;
+
+            return Some(BsdfSample {
+                f: sample.f * self.base_color * glass,
+                ..sample
+            });
+        }
+
+        let below = wo.z < 0f32;
+        let wo_up = if below { flip(wo) } else { wo };
+        let x = u.x - transmission;
+
+        let wi = if x < diffuse {
+            cosine_hemisphere(Vec2::new(x / diffuse, u.y))
+        } else if x < diffuse + specular {
+            self.ggx()
+                .sample(wo_up, Vec2::new((x - diffuse) / specular, u.y))
+                .0
+        } else {
+            let clearcoat = 1f32 - transmission - diffuse - specular;
+            let x = ((x - diffuse - specular) / clearcoat).min(1f32 - f32::EPSILON);
+            self.clearcoat_ggx().sample(wo_up, Vec2::new(x, u.y)).0
+        };
+        if wi.z <= 0f32 {
+            return None;
+        }
+
+        let wi = if below { flip(wi) } else { wi };
+        let pdf = self.pdf(wo, wi);
+        if pdf <= 0f32 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi),
+            pdf,
+            specular: false,
+        })
+    }
+
+    /// The density with which the opaque lobes pick `wi`.
+    fn pdf(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        let [diffuse, specular, clearcoat, _] = match self.lobe_probabilities(wo) {
+            Some(p) => p,
+            None => return 0f32,
+        };
+
+        let (wo, wi) = upper_hemisphere(wo, wi);
+        if wo.z <= 0f32 || wi.z <= 0f32 {
+            return 0f32;
+        }
+
+        let mut pdf = diffuse * cosine_hemisphere_pdf(wi.z);
+        if specular > 0f32 {
+            pdf += specular * self.ggx().pdf(wo, wi);
+        }
+        if clearcoat > 0f32 {
+            pdf += clearcoat * self.clearcoat_ggx().pdf(wo, wi);
+        }
+
+        pdf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::tracer::bsdf::tests::{assert_distributed, assert_samples_match};
+    use crate::tracer::sampling::{random_square, uniform_sphere};
+
+    fn random(rng: &mut StdRng) -> Principled {
+        let mut unit = || rng.gen_range(0f32, 1f32);
+        Principled {
+            base_color: Rgb::new(unit(), unit(), unit()),
+            metallic: unit(),
+            roughness: unit(),
+            specular: unit(),
+            clearcoat: unit(),
+            clearcoat_roughness: unit(),
+            sheen: unit(),
+            transmission: unit(),
+            ior: 1f32 + unit(),
+        }
+    }
+
+    #[test]
+    fn samples_match_eval_and_pdf() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..500 {
+            let principled = random(&mut rng);
+            let wo = uniform_sphere(random_square(&mut rng));
+            assert_samples_match(&principled, wo, &mut rng);
+        }
+    }
+
+    #[test]
+    fn samples_follow_the_pdf() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let models = [
+            Principled::default(),
+            Principled {
+                metallic: 1f32,
+                roughness: 0.3,
+                ..Principled::default()
+            },
+            Principled {
+                clearcoat: 1f32,
+                clearcoat_roughness: 0.3,
+                sheen: 0.5,
+                ..Principled::default()
+            },
+            Principled {
+                transmission: 0.7,
+                roughness: 0.6,
+                clearcoat: 0.5,
+                ..Principled::default()
+            },
+        ];
+
+        for principled in models.iter() {
+            for &wo in &[Vec3::new(0.3, -0.2, 0.9), Vec3::new(0.2, 0.5, -0.6)] {
+                assert_distributed(principled, wo.normalized(), &mut rng);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use super::{reflect, Bsdf, BsdfSample};
+
+/// A perfect mirror.
+#[derive(Copy, Clone, Debug)]
+pub struct Glossy;
+
+impl Bsdf for Glossy {
+    fn eval(&self, _wo: Vec3<f32>, _wi: Vec3<f32>) -> Rgb<f32> {
+        Rgb::zero()
+    }
+
+    fn sample(&self, wo: Vec3<f32>, _u: Vec2<f32>) -> Option<BsdfSample> {
+        if wo.z == 0f32 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi: reflect(wo),
+            f: Rgb::broadcast(1f32 / wo.z.abs()),
+            pdf: 1f32,
+            specular: true,
+        })
+    }
+
+    fn pdf(&self, _wo: Vec3<f32>, _wi: Vec3<f32>) -> f32 {
+        0f32
+    }
+}
+
+/// A surface that absorbs all the light it receives, like
+/// the ones that only emit.
+#[derive(Copy, Clone, Debug)]
+pub struct BlackBody;
+
+impl Bsdf for BlackBody {
+    fn eval(&self, _wo: Vec3<f32>, _wi: Vec3<f32>) -> Rgb<f32> {
+        Rgb::zero()
+    }
+
+    fn sample(&self, _wo: Vec3<f32>, _u: Vec2<f32>) -> Option<BsdfSample> {
+        None
+    }
+
+    fn pdf(&self, _wo: Vec3<f32>, _wi: Vec3<f32>) -> f32 {
+        0f32
+    }
+}
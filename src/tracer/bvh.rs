@@ -0,0 +1,479 @@
+use vek::vec::Vec3;
+
+use super::ray::Ray;
+
+/// An axis-aligned bounding box.
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3<f32>, max: Vec3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    /// A box containing nothing, to be grown with `union`.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::broadcast(f32::INFINITY),
+            max: Vec3::broadcast(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vec3<f32>]) -> Aabb {
+        points
+            .iter()
+            .fold(Aabb::empty(), |acc, p| acc.union(&Aabb::new(*p, *p)))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::partial_min(self.min, other.min),
+            max: Vec3::partial_max(self.max, other.max),
+        }
+    }
+
+    pub fn center(&self) -> Vec3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            0.0
+        } else {
+            2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+        }
+    }
+
+    /// The part of the ray between `t_min` and `t_max` that
+    /// is inside the box, if any.
+    pub fn clip(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+
+        for i in 0..3 {
+            let inv_dir = 1.0 / ray.direction[i];
+            let near = (self.min[i] - ray.origin[i]) * inv_dir;
+            let far = (self.max[i] - ray.origin[i]) * inv_dir;
+            let (near, far) = if near < far { (near, far) } else { (far, near) };
+
+            t0 = if near > t0 { near } else { t0 };
+            t1 = if far < t1 { far } else { t1 };
+
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
+
+    /// The slab test. Returns the distance at which the ray
+    /// enters the box, if it does so before `max_t`.
+    fn hit(&self, origin: Vec3<f32>, inv_dir: Vec3<f32>, max_t: f32) -> Option<f32> {
+        let mut t0 = 0f32;
+        let mut t1 = max_t;
+
+        for i in 0..3 {
+            let near = (self.min[i] - origin[i]) * inv_dir[i];
+            let far = (self.max[i] - origin[i]) * inv_dir[i];
+            let (near, far) = if near < far { (near, far) } else { (far, near) };
+
+            // Written so that NaNs (from 0 * inf) keep the old bounds
+            t0 = if near > t0 { near } else { t0 };
+            t1 = if far < t1 { far } else { t1 };
+
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some(t0)
+    }
+}
+
+/// A node of the flattened tree. Interior nodes have
+/// their left child right after them.
+struct Node {
+    bounds: Aabb,
+    /// First primitive of a leaf, or the right child of an interior node
+    offset: usize,
+    /// Number of primitives of a leaf, zero for interior nodes
+    count: usize,
+}
+
+/// The number of buckets used to evaluate the split candidates.
+const SAH_BUCKETS: usize = 12;
+
+/// Leaves with up to this many primitives are never split.
+const MAX_LEAF_SIZE: usize = 2;
+
+/// Larger sets of primitives are always split, even when the
+/// surface area heuristic finds a single leaf cheaper, so that
+/// leaves stay small enough to test one by one.
+const MAX_LEAF_ITEMS: usize = 16;
+
+/// A bounding volume hierarchy over a list of primitives,
+/// built with the surface area heuristic.
+///
+/// The tree only stores indices, so it works for anything
+/// with a bounding box. Unbounded primitives (like planes) are
+/// kept aside and always tested.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+struct BuildItem {
+    index: usize,
+    bounds: Aabb,
+    center: Vec3<f32>,
+}
+
+impl Bvh {
+    /// Builds the tree over the primitives with the given bounds.
+    pub fn new(bounds: &[Option<Aabb>]) -> Bvh {
+        let mut items = Vec::new();
+        let mut unbounded = Vec::new();
+
+        for (index, b) in bounds.iter().enumerate() {
+            match b {
+                Some(b) => items.push(BuildItem {
+                    index,
+                    bounds: *b,
+                    center: b.center(),
+                }),
+                None => unbounded.push(index),
+            }
+        }
+
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: Vec::with_capacity(items.len()),
+            unbounded,
+        };
+
+        if !items.is_empty() {
+            bvh.build(&mut items);
+        }
+
+        bvh
+    }
+
+    fn build(&mut self, items: &mut [BuildItem]) -> usize {
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, it| acc.union(&it.bounds));
+
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            offset: 0,
+            count: 0,
+        });
+
+        let split = if items.len() > MAX_LEAF_SIZE {
+            Bvh::find_split(items, &bounds)
+        } else {
+            None
+        };
+
+        match split {
+            Some(mid) => {
+                let (left, right) = items.split_at_mut(mid);
+                self.build(left);
+                let right = self.build(right);
+                self.nodes[node].offset = right;
+            }
+            None => {
+                self.nodes[node].offset = self.indices.len();
+                self.nodes[node].count = items.len();
+                self.indices.extend(items.iter().map(|it| it.index));
+            }
+        }
+
+        node
+    }
+
+    /// Partitions the items along the cheapest split according
+    /// to the SAH, returning where the right half starts. Returns
+    /// `None` if keeping them all in a leaf is cheaper.
+    fn find_split(items: &mut [BuildItem], bounds: &Aabb) -> Option<usize> {
+        let centers = items.iter().fold(Aabb::empty(), |acc, it| {
+            acc.union(&Aabb::new(it.center, it.center))
+        });
+        let extent = centers.max - centers.min;
+
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        if extent[axis] <= 0.0 {
+            return None;
+        }
+
+        let bucket_of = |it: &BuildItem| {
+            let rel = (it.center[axis] - centers.min[axis]) / extent[axis];
+            ((rel * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+        };
+
+        let mut counts = [0usize; SAH_BUCKETS];
+        let mut boxes = [Aabb::empty(); SAH_BUCKETS];
+        for it in items.iter() {
+            let b = bucket_of(it);
+            counts[b] += 1;
+            boxes[b] = boxes[b].union(&it.bounds);
+        }
+
+        // Cost of splitting after each bucket, relative to the
+        // cost of intersecting a primitive.
+        let mut best: Option<(usize, f32)> = None;
+        for split in 0..SAH_BUCKETS - 1 {
+            let (mut left, mut n_left) = (Aabb::empty(), 0);
+            for b in 0..=split {
+                left = left.union(&boxes[b]);
+                n_left += counts[b];
+            }
+            let (mut right, mut n_right) = (Aabb::empty(), 0);
+            for b in split + 1..SAH_BUCKETS {
+                right = right.union(&boxes[b]);
+                n_right += counts[b];
+            }
+
+            if n_left == 0 || n_right == 0 {
+                continue;
+            }
+
+            let cost = 0.125
+                + (n_left as f32 * left.surface_area() + n_right as f32 * right.surface_area())
+                    / bounds.surface_area().max(f32::MIN_POSITIVE);
+
+            match best {
+                Some((_, c)) if c <= cost => {}
+                _ => best = Some((split, cost)),
+            }
+        }
+
+        let (split, cost) = best?;
+        if cost >= items.len() as f32 && items.len() <= MAX_LEAF_ITEMS {
+            return None;
+        }
+
+        // Partition in place around the chosen bucket
+        let mut mid = 0;
+        for i in 0..items.len() {
+            if bucket_of(&items[i]) <= split {
+                items.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        Some(mid)
+    }
+
+    /// Finds the nearest primitive hit by the ray, up to
+    /// `max_distance`. `hit` tests a single primitive and
+    /// returns its hit distance; boxes farther than the nearest
+    /// hit so far are skipped.
+    pub fn intersect<F>(&self, ray: &Ray, max_distance: f32, mut hit: F)
+    where
+        F: FnMut(usize) -> Option<f32>,
+    {
+        let mut nearest = max_distance;
+
+        for &i in self.unbounded.iter() {
+            if let Some(t) = hit(i) {
+                nearest = nearest.min(t);
+            }
+        }
+
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_dir = ray.direction.map(|d| 1.0 / d);
+        let mut stack = vec![0usize];
+
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+
+            if node.bounds.hit(ray.origin, inv_dir, nearest).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &i in self.indices[node.offset..node.offset + node.count].iter() {
+                    if let Some(t) = hit(i) {
+                        nearest = nearest.min(t);
+                    }
+                }
+            } else {
+                // Visit the nearer child first, so the
+                // farther one is more likely to be culled
+                let (left, right) = (n + 1, node.offset);
+                let t_left = self.nodes[left].bounds.hit(ray.origin, inv_dir, nearest);
+                let t_right = self.nodes[right].bounds.hit(ray.origin, inv_dir, nearest);
+
+                match (t_left, t_right) {
+                    (Some(l), Some(r)) if l <= r => {
+                        stack.push(right);
+                        stack.push(left);
+                    }
+                    (Some(_), Some(_)) => {
+                        stack.push(left);
+                        stack.push(right);
+                    }
+                    (Some(_), None) => stack.push(left),
+                    (None, Some(_)) => stack.push(right),
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use vek::mat::Mat4;
+
+    use super::*;
+    use crate::tracer::camera::MtxCamera;
+    use crate::tracer::check_hit;
+    use crate::tracer::material::Material;
+    use crate::tracer::render_context::RenderContext;
+    use crate::tracer::shape::{Plane, Shape, Sphere, Triangle};
+
+    fn random_point(rng: &mut StdRng, extent: f32) -> Vec3<f32> {
+        Vec3::new(
+            rng.gen_range(-extent, extent),
+            rng.gen_range(-extent, extent),
+            rng.gen_range(-extent, extent),
+        )
+    }
+
+    fn context(objects: Arc<Vec<Arc<dyn Shape>>>, bvh: Option<Bvh>) -> RenderContext<MtxCamera> {
+        RenderContext {
+            width: 1,
+            height: 1,
+            samples: 1,
+            n_threads: 1,
+            objects,
+            bvh,
+            camera: MtxCamera {
+                vp_inverse: Mat4::identity(),
+                width: 1,
+                height: 1,
+            },
+            lights: Arc::new(Vec::new()),
+            fog: None,
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let material = Arc::new(Material::default());
+
+        let mut objects: Vec<Arc<dyn Shape>> = Vec::new();
+        for _ in 0..200 {
+            let center = random_point(&mut rng, 10.0);
+            let radius = rng.gen_range(0.05, 1.0);
+            objects.push(Arc::new(Sphere::new(center, radius, Material::default())));
+        }
+        for _ in 0..200 {
+            let corner = random_point(&mut rng, 10.0);
+            let vertices = [
+                corner,
+                corner + random_point(&mut rng, 1.0),
+                corner + random_point(&mut rng, 1.0),
+            ];
+            objects.push(Arc::new(Triangle::new(vertices, material.clone())));
+        }
+        // Unbounded shapes are tested against every ray
+        objects.push(Arc::new(Plane::new(
+            Vec3::new(0.0, -12.0, 0.0),
+            Vec3::unit_y(),
+            Material::default(),
+        )));
+
+        let bounds = objects.iter().map(|o| o.bounds()).collect::<Vec<_>>();
+        let objects = Arc::new(objects);
+        let with_bvh = context(objects.clone(), Some(Bvh::new(&bounds)));
+        let brute_force = context(objects, None);
+
+        // There are no media, so no collisions are drawn from it
+        let mut thread_rng = rand::thread_rng();
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray {
+                origin: random_point(&mut rng, 12.0),
+                direction: random_point(&mut rng, 1.0).normalized(),
+            };
+
+            let expected = check_hit(&brute_force, &mut thread_rng, &ray);
+            let found = check_hit(&with_bvh, &mut thread_rng, &ray);
+            match (expected, found) {
+                (None, None) => {}
+                (Some(expected), Some(found)) => {
+                    assert_eq!(expected.distance, found.distance, "{:?}", ray);
+                    assert_eq!(expected.point, found.point, "{:?}", ray);
+                    if found.object.bounds().is_some() {
+                        hits += 1;
+                    }
+                }
+                _ => panic!("{:?} hits {:?} without the BVH", ray, expected.is_some()),
+            }
+        }
+
+        // Enough rays should hit the bounded shapes, which the
+        // BVH is for, or the test is moot
+        assert!(hits > 200, "only {} hits", hits);
+    }
+
+    #[test]
+    fn stops_at_max_distance() {
+        let spheres: Vec<_> = (1..=100)
+            .map(|i| {
+                Sphere::new(
+                    Vec3::new(i as f32 * 3.0, 0.0, 0.0),
+                    1.0,
+                    Material::default(),
+                )
+            })
+            .collect();
+        let bvh = Bvh::new(&spheres.iter().map(|s| s.bounds()).collect::<Vec<_>>());
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::unit_x(),
+        };
+
+        // Nothing blocks the ray, like volumes for shadow rays
+        let mut visited = Vec::new();
+        bvh.intersect(&ray, 31.0, |i| {
+            visited.push(i);
+            None
+        });
+
+        for i in 0..10 {
+            assert!(visited.contains(&i), "{} wasn't visited", i);
+        }
+
+        // Leaves may reach a little past it, but not the far end
+        for &i in &visited {
+            let near = spheres[i].bounds().unwrap().min.x;
+            assert!(near <= 80.0, "visited {} at {}", i, near);
+        }
+    }
+}
@@ -70,6 +70,7 @@ impl Camera for MtxCamera {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Copy, Clone)]
 pub struct PerspCamera {
     pub corner: Vec3<f32>,
@@ -80,6 +81,7 @@ pub struct PerspCamera {
     height: u32,
 }
 
+#[allow(dead_code)]
 impl PerspCamera {
     pub fn new(
         origin: Vec3<f32>,
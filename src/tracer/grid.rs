@@ -0,0 +1,356 @@
+use vek::vec::Vec3;
+
+use super::bvh::Aabb;
+use super::ray::Ray;
+
+/// The number of cells along each side of a block.
+const BLOCK: usize = 8;
+
+/// Values on a regular grid of cells spanning a box, like
+/// the density of a heterogeneous medium. Each value is at
+/// the center of its cell, and interpolated in between.
+///
+/// The cells are stored in blocks of `BLOCK`³, and the blocks
+/// that are all zero, like the empty space around smoke, are
+/// left out. Each block also knows the largest value reached
+/// inside of it, which media use as a local majorant.
+pub struct Grid {
+    bounds: Aabb,
+    /// The number of cells along each axis
+    pub size: Vec3<usize>,
+    /// The number of blocks along each axis
+    blocks_size: Vec3<usize>,
+    blocks: Vec<Option<Box<[f32]>>>,
+    majorants: Vec<f32>,
+}
+
+impl Grid {
+    /// A grid with the value of `f` at the center of each cell.
+    pub fn from_fn<F>(bounds: Aabb, size: Vec3<usize>, f: F) -> Grid
+    where
+        F: Fn(Vec3<f32>) -> f32,
+    {
+        let size = size.map(|s| s.max(1));
+        let cell = (bounds.max - bounds.min) / size.map(|s| s as f32);
+
+        let mut values = Vec::with_capacity(size.product());
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let center = Vec3::new(x, y, z).map(|i| i as f32 + 0.5);
+                    values.push(f(bounds.min + center * cell));
+                }
+            }
+        }
+
+        Grid::from_values(bounds, size, &values)
+    }
+
+    /// A grid of the given values, with x varying fastest,
+    /// then y, then z.
+    pub fn from_values(bounds: Aabb, size: Vec3<usize>, values: &[f32]) -> Grid {
+        assert_eq!(values.len(), size.product());
+        let dense = |x: usize, y: usize, z: usize| values[(z * size.y + y) * size.x + x];
+
+        let blocks_size = size.map(|s| s.div_ceil(BLOCK));
+        let mut blocks = Vec::with_capacity(blocks_size.product());
+        let mut majorants = Vec::with_capacity(blocks_size.product());
+
+        for bz in 0..blocks_size.z {
+            for by in 0..blocks_size.y {
+                for bx in 0..blocks_size.x {
+                    let start = Vec3::new(bx, by, bz) * BLOCK;
+
+                    let mut block = vec![0.0; BLOCK * BLOCK * BLOCK];
+                    let mut empty = true;
+                    for (i, value) in block.iter_mut().enumerate() {
+                        let local = Vec3::new(i % BLOCK, i / BLOCK % BLOCK, i / (BLOCK * BLOCK));
+                        let cell = start + local;
+                        if cell.x < size.x && cell.y < size.y && cell.z < size.z {
+                            *value = dense(cell.x, cell.y, cell.z);
+                            empty &= *value == 0.0;
+                        }
+                    }
+                    blocks.push(if empty {
+                        None
+                    } else {
+                        Some(block.into_boxed_slice())
+                    });
+
+                    // Points of the block are interpolated with the
+                    // cells around them, up to one cell outside of it
+                    let lo = start.map(|s| s.saturating_sub(1));
+                    let hi = (start + BLOCK + 1).map2(size, usize::min);
+                    let mut majorant = 0f32;
+                    for z in lo.z..hi.z {
+                        for y in lo.y..hi.y {
+                            for x in lo.x..hi.x {
+                                majorant = majorant.max(dense(x, y, z));
+                            }
+                        }
+                    }
+                    majorants.push(majorant);
+                }
+            }
+        }
+
+        Grid {
+            bounds,
+            size,
+            blocks_size,
+            blocks,
+            majorants,
+        }
+    }
+
+    /// The same grid, scaled by `scale`, which must be
+    /// positive, then moved by `translate`.
+    pub fn placed(self, scale: f32, translate: Vec3<f32>) -> Grid {
+        assert!(scale > 0.0, "grids can't be scaled by {}", scale);
+        let place = |p: Vec3<f32>| p * scale + translate;
+        Grid {
+            bounds: Aabb::new(place(self.bounds.min), place(self.bounds.max)),
+            ..self
+        }
+    }
+
+    fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+        let block = ((z / BLOCK) * self.blocks_size.y + y / BLOCK) * self.blocks_size.x + x / BLOCK;
+        match &self.blocks[block] {
+            Some(values) => values[((z % BLOCK) * BLOCK + y % BLOCK) * BLOCK + x % BLOCK],
+            None => 0.0,
+        }
+    }
+
+    /// The trilinear interpolation of the values around the
+    /// point, which is zero outside of the bounds.
+    pub fn lookup(&self, point: Vec3<f32>) -> f32 {
+        let (min, max) = (self.bounds.min, self.bounds.max);
+        if (0..3).any(|i| point[i] < min[i] || point[i] > max[i]) {
+            return 0.0;
+        }
+
+        // In units of cells, from the center of the first one
+        let size = self.size.map(|s| s as f32);
+        let g =
+            ((point - min) / (max - min) * size - 0.5).map2(size, |g, s| g.max(0.0).min(s - 1.0));
+
+        let i0 = g.map(|g| g as usize);
+        let i1 = i0.map2(self.size, |i, s| (i + 1).min(s - 1));
+        let f = g - i0.map(|i| i as f32);
+
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        let along_x = |y: usize, z: usize| lerp(f.x, self.at(i0.x, y, z), self.at(i1.x, y, z));
+
+        lerp(
+            f.z,
+            lerp(f.y, along_x(i0.y, i0.z), along_x(i1.y, i0.z)),
+            lerp(f.y, along_x(i0.y, i1.z), along_x(i1.y, i1.z)),
+        )
+    }
+
+    /// Walks the blocks that the ray crosses between `t_min`
+    /// and `t_max`, in order. Each segment comes with the
+    /// largest value of the grid along it.
+    pub fn segments(&self, ray: &Ray, t_min: f32, t_max: f32) -> Segments<'_> {
+        let cell = (self.bounds.max - self.bounds.min) / self.size.map(|s| s as f32);
+        let block = cell * BLOCK as f32;
+
+        let (t, t_max) = match self.bounds.clip(ray, t_min, t_max) {
+            Some(extent) => extent,
+            None => (t_max, t_max),
+        };
+
+        let start = (ray.origin + ray.direction * t - self.bounds.min) / block;
+        let index = start.map2(self.blocks_size, |s, n| (s.max(0.0) as usize).min(n - 1));
+
+        // The distances along the ray to the next block
+        // boundary, and between boundaries, on each axis
+        let mut t_next = Vec3::broadcast(f32::INFINITY);
+        let mut t_delta = Vec3::broadcast(f32::INFINITY);
+        for i in 0..3 {
+            if ray.direction[i] != 0.0 {
+                let next = index[i] + if ray.direction[i] > 0.0 { 1 } else { 0 };
+                let boundary = self.bounds.min[i] + next as f32 * block[i];
+                t_next[i] = (boundary - ray.origin[i]) / ray.direction[i];
+                t_delta[i] = block[i] / ray.direction[i].abs();
+            }
+        }
+
+        Segments {
+            grid: self,
+            forward: ray.direction.map(|d| d > 0.0),
+            index,
+            t,
+            t_max,
+            t_next,
+            t_delta,
+        }
+    }
+}
+
+/// The segments of a ray through the blocks of a grid, see
+/// `Grid::segments`. A 3D DDA from one block to the next.
+pub struct Segments<'a> {
+    grid: &'a Grid,
+    forward: Vec3<bool>,
+    index: Vec3<usize>,
+    t: f32,
+    t_max: f32,
+    t_next: Vec3<f32>,
+    t_delta: Vec3<f32>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    /// The start and end of the segment, and the majorant
+    type Item = (f32, f32, f32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.t >= self.t_max {
+            return None;
+        }
+
+        let axis = if self.t_next.x < self.t_next.y {
+            if self.t_next.x < self.t_next.z {
+                0
+            } else {
+                2
+            }
+        } else if self.t_next.y < self.t_next.z {
+            1
+        } else {
+            2
+        };
+
+        let (index, size) = (self.index, self.grid.blocks_size);
+        let majorant = self.grid.majorants[(index.z * size.y + index.y) * size.x + index.x];
+        let start = self.t;
+        let mut end = self.t_next[axis].min(self.t_max);
+
+        // The last block reaches the end of the ray even if
+        // its boundary was rounded short of it
+        self.t_next[axis] += self.t_delta[axis];
+        if self.forward[axis] {
+            self.index[axis] += 1;
+            if self.index[axis] >= size[axis] {
+                end = self.t_max;
+            }
+        } else if self.index[axis] == 0 {
+            end = self.t_max;
+        } else {
+            self.index[axis] -= 1;
+        }
+        self.t = end;
+
+        Some((start, end, majorant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// A grid with blocks cut short on every side, and empty
+    /// blocks in between full ones.
+    fn grid(rng: &mut StdRng) -> Grid {
+        let bounds = Aabb::new(Vec3::new(-1.0, 0.0, -3.0), Vec3::new(2.0, 1.5, 1.0));
+        let size = Vec3::new(19, 12, 27);
+        let values: Vec<f32> = (0..size.product())
+            .map(|i| {
+                let x = i % size.x;
+                if x / BLOCK == 1 {
+                    0.0
+                } else {
+                    rng.gen_range(0.0, 4.0)
+                }
+            })
+            .collect();
+        Grid::from_values(bounds, size, &values)
+    }
+
+    fn random_ray(rng: &mut StdRng) -> Ray {
+        let mut point = || {
+            Vec3::new(
+                rng.gen_range(-3.0, 4.0),
+                rng.gen_range(-2.0, 3.0),
+                rng.gen_range(-5.0, 3.0),
+            )
+        };
+        let origin = point();
+        Ray {
+            origin,
+            direction: (point() - origin).normalized(),
+        }
+    }
+
+    #[test]
+    fn segments_are_contiguous() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let grid = grid(&mut rng);
+
+        let mut crossing = 0;
+        for _ in 0..2000 {
+            let ray = random_ray(&mut rng);
+            let (t_min, t_max) = (rng.gen_range(-1.0, 2.0), rng.gen_range(2.0, 8.0));
+            let segments: Vec<_> = grid.segments(&ray, t_min, t_max).collect();
+
+            match grid.bounds.clip(&ray, t_min, t_max) {
+                Some((start, end)) if start < end => {
+                    crossing += 1;
+                    assert_eq!(segments.first().unwrap().0, start, "{:?}", ray);
+                    assert_eq!(segments.last().unwrap().1, end, "{:?}", ray);
+                    for pair in segments.windows(2) {
+                        assert_eq!(pair[0].1, pair[1].0, "{:?}", ray);
+                    }
+                    for &(start, end, _) in &segments {
+                        assert!(start <= end, "{:?}", ray);
+                    }
+                }
+                _ => assert!(segments.is_empty(), "{:?}", ray),
+            }
+        }
+        assert!(crossing > 500, "only {} rays cross the grid", crossing);
+    }
+
+    #[test]
+    fn placing_moves_the_values() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let original = grid(&mut StdRng::seed_from_u64(4));
+        let placed = grid(&mut StdRng::seed_from_u64(4)).placed(2.5, Vec3::new(1.0, -2.0, 3.0));
+
+        for _ in 0..1000 {
+            let point = random_ray(&mut rng).origin;
+            let moved = point * 2.5 + Vec3::new(1.0, -2.0, 3.0);
+            assert!((original.lookup(point) - placed.lookup(moved)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn majorants_bound_their_segments() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let grid = grid(&mut rng);
+
+        for _ in 0..2000 {
+            let ray = random_ray(&mut rng);
+            for (start, end, majorant) in grid.segments(&ray, 0.0, f32::INFINITY) {
+                for i in 0..=10 {
+                    let t = start + (end - start) * i as f32 / 10.0;
+                    let value = grid.lookup(ray.origin + ray.direction * t);
+                    assert!(
+                        value <= majorant,
+                        "{} over {} at {:?}",
+                        value,
+                        majorant,
+                        ray
+                    );
+                }
+            }
+        }
+
+        // Blocks of zeros are skipped
+        assert!(grid.blocks.iter().any(|b| b.is_none()));
+    }
+}
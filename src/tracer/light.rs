@@ -1,56 +1,549 @@
-use vek::vec::Vec3;
+use std::sync::Arc;
+
 use vek::rgb::Rgb;
+use vek::vec::Vec3;
 
-use crate::tracer::ray::Ray;
+use crate::tracer::ray::{Frame, Ray};
+use crate::tracer::sampling::{
+    random_square, uniform_cone, uniform_cone_pdf, uniform_sphere, uniform_sphere_pdf,
+};
+use crate::tracer::shape::Shape;
 
 pub struct LightSample {
     pub distance: f32,
     pub ray: Ray,
+
+    /// The radiance arriving along the ray. For delta lights,
+    /// which can't be hit, the irradiance at normal incidence,
+    /// which falls off with the square of the distance.
+    pub radiance: Rgb<f32>,
+
+    /// The solid angle density with which the direction of
+    /// the ray was picked. Always 1 for delta lights.
+    pub pdf: f32,
+
+    /// Whether the light is a single point, that only light
+    /// sampling can reach.
+    pub delta: bool,
+}
+
+/// Where a ray hit a light, seen from the ray origin.
+pub struct LightHit {
+    pub distance: f32,
+    pub radiance: Rgb<f32>,
+
+    /// The density with which `LightSampler::sample` would
+    /// have picked the direction of the ray.
+    pub pdf: f32,
 }
 
+/// Lights are not part of the scene geometry: they cast no
+/// shadows and the paths go through them. BSDF sampled rays
+/// still pick up their radiance with `intersect`, so both
+/// strategies can be combined.
 pub trait LightSampler: Send + Sync {
     fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample;
+
+    /// Finds where the ray enters the light. Delta lights
+    /// are never hit.
+    fn intersect(&self, _ray: &Ray) -> Option<LightHit> {
+        None
+    }
+}
+
+/// A direction in the cone around `axis` with the given
+/// cosine of its half angle, picked uniformly.
+fn sample_cone(rng: &mut rand::rngs::ThreadRng, axis: Vec3<f32>, cos_max: f32) -> Vec3<f32> {
+    Frame::new(axis)
+        .to_world(uniform_cone(random_square(rng), cos_max))
+        .normalized()
 }
 
+/// A point radiating the same intensity in every direction.
 pub struct PointLight {
     pub position: vek::vec::Vec3<f32>,
+    /// Radiant intensity, the power per unit solid angle
+    pub intensity: Rgb<f32>,
+}
+
+impl PointLight {
+    /// A light emitting the given total power.
+    pub fn new(position: Vec3<f32>, power: Rgb<f32>) -> PointLight {
+        PointLight {
+            position,
+            intensity: power / (4.0 * std::f32::consts::PI),
+        }
+    }
 }
 
 impl LightSampler for PointLight {
     fn sample(&self, _: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
         let dir = self.position - point;
         let distance = dir.magnitude();
-        let dir = dir.normalized();
         let ray = Ray {
             origin: point,
-            direction: dir.normalized()
+            direction: dir.normalized(),
         };
         LightSample {
             distance,
             ray,
+            radiance: self.intensity / (distance * distance).max(f32::MIN_POSITIVE),
+            pdf: 1.0,
+            delta: true,
         }
     }
 }
 
+/// A sphere whose surface emits the same radiance
+/// everywhere and in every direction.
 pub struct AreaLight {
     pub position: vek::vec::Vec3<f32>,
     pub radius: f32,
+    pub radiance: Rgb<f32>,
+}
+
+impl AreaLight {
+    /// A light emitting the given total power.
+    pub fn new(position: Vec3<f32>, radius: f32, power: Rgb<f32>) -> AreaLight {
+        // Each point of the surface emits pi times its radiance
+        let area = 4.0 * std::f32::consts::PI * radius * radius;
+
+        AreaLight {
+            position,
+            radius,
+            radiance: power / (std::f32::consts::PI * area).max(f32::MIN_POSITIVE),
+        }
+    }
+
+    /// The cosine of the half angle of the cone the sphere
+    /// covers seen from `point`, or `None` from inside it.
+    fn cos_max(&self, point: Vec3<f32>) -> Option<f32> {
+        let dist2 = (self.position - point).magnitude_squared();
+        let r2 = self.radius * self.radius;
+
+        if dist2 <= r2 {
+            None
+        } else {
+            Some((1.0 - r2 / dist2).max(0.0).sqrt())
+        }
+    }
+
+    fn pdf(&self, point: Vec3<f32>) -> f32 {
+        match self.cos_max(point) {
+            Some(cos_max) => uniform_cone_pdf(cos_max),
+            None => uniform_sphere_pdf(),
+        }
+    }
+
+    /// The distance to the first point of the sphere
+    /// along the ray, or to its far side from inside it.
+    fn distance(&self, ray: &Ray) -> Option<f32> {
+        let oc = ray.origin - self.position;
+        let b = oc.dot(ray.direction);
+        let c = oc.magnitude_squared() - self.radius * self.radius;
+        let disc = b * b - c;
+
+        if disc < 0.0 {
+            return None;
+        }
+
+        let sqrt_disc = disc.sqrt();
+        if -b - sqrt_disc > 0.0 {
+            Some(-b - sqrt_disc)
+        } else if -b + sqrt_disc > 0.0 {
+            Some(-b + sqrt_disc)
+        } else {
+            None
+        }
+    }
 }
 
 impl LightSampler for AreaLight {
+    /// Samples the cone of directions covered by the
+    /// sphere uniformly, or every direction from inside it.
     fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
-        let light_point = (crate::tracer::random_in_hemisphere(rng, Vec3::zero(), Vec3::zero()) * self.radius)
-            + self.position;
-        let dir = light_point - point;
-        let distance = dir.magnitude();
-        let dir = dir.normalized();
+        let direction = match self.cos_max(point) {
+            Some(cos_max) => sample_cone(rng, (self.position - point).normalized(), cos_max),
+            None => uniform_sphere(random_square(rng)),
+        };
         let ray = Ray {
             origin: point,
-            direction: dir.normalized()
+            direction,
         };
+
+        // Rounding can make the sampled direction graze past
+        // the sphere, in which case it just touches it
+        let distance = self
+            .distance(&ray)
+            .unwrap_or_else(|| (self.position - point).dot(direction));
+
+        LightSample {
+            distance,
+            ray,
+            radiance: self.radiance,
+            pdf: self.pdf(point),
+            delta: false,
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<LightHit> {
+        let distance = self.distance(ray)?;
+
+        Some(LightHit {
+            distance,
+            radiance: self.radiance,
+            pdf: self.pdf(ray.origin),
+        })
+    }
+}
+
+/// Light coming from a very distant source, like the sun,
+/// from the same directions everywhere.
+pub struct DirectionalLight {
+    /// The direction towards the light
+    pub direction: Vec3<f32>,
+    /// The cosine of the angular radius of the source. The
+    /// light is a delta light when it is 1.
+    pub cos_max: f32,
+    /// The radiance of the source, or the irradiance it
+    /// gives when it is a delta light
+    pub radiance: Rgb<f32>,
+}
+
+impl DirectionalLight {
+    /// A light shining along `direction`, giving the
+    /// irradiance on surfaces facing it. Sources with an
+    /// angular diameter (in radians) cast soft shadows.
+    pub fn new(direction: Vec3<f32>, irradiance: Rgb<f32>, diameter: f32) -> DirectionalLight {
+        let half_angle = (diameter * 0.5).clamp(0.0, std::f32::consts::FRAC_PI_2);
+        let cos_max = half_angle.cos();
+
+        // A disk of radiance L covering the cone gives an
+        // irradiance of pi * sin^2 L at its center
+        let sin2 = 1.0 - cos_max * cos_max;
+        let radiance = if sin2 > 0.0 {
+            irradiance / (std::f32::consts::PI * sin2)
+        } else {
+            irradiance
+        };
+
+        DirectionalLight {
+            direction: -direction.normalized(),
+            cos_max,
+            radiance,
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        self.cos_max >= 1.0
+    }
+}
+
+impl LightSampler for DirectionalLight {
+    fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
+        let (direction, pdf) = if self.is_delta() {
+            (self.direction, 1.0)
+        } else {
+            (
+                sample_cone(rng, self.direction, self.cos_max),
+                uniform_cone_pdf(self.cos_max),
+            )
+        };
+
+        LightSample {
+            // Anything in the way casts a shadow
+            distance: f32::MAX,
+            ray: Ray {
+                origin: point,
+                direction,
+            },
+            radiance: self.radiance,
+            pdf,
+            delta: self.is_delta(),
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<LightHit> {
+        if self.is_delta() || ray.direction.dot(self.direction) < self.cos_max {
+            return None;
+        }
+
+        Some(LightHit {
+            distance: f32::MAX,
+            radiance: self.radiance,
+            pdf: uniform_cone_pdf(self.cos_max),
+        })
+    }
+}
+
+/// A point light shining in a cone, fading out
+/// smoothly between an inner and an outer angle.
+pub struct SpotLight {
+    pub position: Vec3<f32>,
+    /// The axis of the cone
+    pub direction: Vec3<f32>,
+    /// The intensity within the inner cone
+    pub intensity: Rgb<f32>,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+}
+
+impl SpotLight {
+    /// A light emitting the given total power. The angles
+    /// are the half angles of the cones, in radians.
+    pub fn new(
+        position: Vec3<f32>,
+        direction: Vec3<f32>,
+        power: Rgb<f32>,
+        inner: f32,
+        outer: f32,
+    ) -> SpotLight {
+        let cos_outer = outer.clamp(0.0, std::f32::consts::PI).cos();
+        let cos_inner = inner.clamp(0.0, std::f32::consts::PI).cos().max(cos_outer);
+
+        // The smoothstep falloff is symmetric about the middle
+        // of the cosines, so it covers as much solid angle as
+        // a hard cone cut halfway
+        let solid_angle = 1.0 / uniform_cone_pdf(0.5 * (cos_inner + cos_outer));
+
+        SpotLight {
+            position,
+            direction: direction.normalized(),
+            intensity: power / solid_angle.max(f32::MIN_POSITIVE),
+            cos_inner,
+            cos_outer,
+        }
+    }
+
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        if cos_theta <= self.cos_outer {
+            0.0
+        } else if cos_theta >= self.cos_inner {
+            1.0
+        } else {
+            let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+impl LightSampler for SpotLight {
+    fn sample(&self, _: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
+        let dir = self.position - point;
+        let distance = dir.magnitude();
+        let direction = dir.normalized();
+        let falloff = self.falloff(-direction.dot(self.direction));
+
         LightSample {
             distance,
+            ray: Ray {
+                origin: point,
+                direction,
+            },
+            radiance: self.intensity * falloff / (distance * distance).max(f32::MIN_POSITIVE),
+            pdf: 1.0,
+            delta: true,
+        }
+    }
+}
+
+/// A shape of the scene with an emissive material, sampled
+/// uniformly over its area. Unlike the other lights, it is
+/// found by the paths through the scene geometry.
+pub struct ShapeLight {
+    shape: Arc<dyn Shape>,
+}
+
+impl ShapeLight {
+    /// Makes a light of the shape if it emits anything and
+    /// can be sampled. `tracer::trace` weights the emission of
+    /// every such shape it hits as if it were one of the lights.
+    pub fn new(shape: Arc<dyn Shape>) -> Option<ShapeLight> {
+        if shape.material().emittance == Rgb::zero() || shape.area() <= 0.0 {
+            None
+        } else {
+            Some(ShapeLight { shape })
+        }
+    }
+
+    /// The solid angle density with which the light made of
+    /// `shape` picks the direction from `origin` to `point`,
+    /// a point of the shape with the given normal.
+    pub fn pdf(shape: &dyn Shape, origin: Vec3<f32>, point: Vec3<f32>, normal: Vec3<f32>) -> f32 {
+        let to_light = point - origin;
+        let dist2 = to_light.magnitude_squared();
+        let cos = normal.dot(to_light).abs() / dist2.sqrt();
+
+        if cos <= 0.0 {
+            0.0
+        } else {
+            dist2 / (cos * shape.area())
+        }
+    }
+}
+
+impl LightSampler for ShapeLight {
+    fn sample(&self, rng: &mut rand::rngs::ThreadRng, point: Vec3<f32>) -> LightSample {
+        let (target, normal) = match self.shape.sample_surface(rng) {
+            Some(s) => (s.point, s.normal),
+            None => (self.shape.position(), Vec3::zero()),
+        };
+
+        let dir = target - point;
+        let distance = dir.magnitude();
+        let ray = Ray {
+            origin: point,
+            direction: dir.normalized(),
+        };
+
+        LightSample {
+            // Stop short of the surface, so that the shape
+            // doesn't shadow its own sample
+            distance: distance * 0.999,
             ray,
+            radiance: self.shape.material().emittance,
+            pdf: ShapeLight::pdf(self.shape.as_ref(), point, target, normal),
+            delta: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32::consts::PI;
+
+    /// How finely the integrals over angles are cut.
+    const STEPS: usize = 4000;
+
+    /// The midpoint rule over [a, b].
+    fn integrate(a: f32, b: f32, f: impl Fn(f32) -> f32) -> f32 {
+        let step = (b - a) / STEPS as f32;
+        (0..STEPS)
+            .map(|i| f64::from(f(a + (i as f32 + 0.5) * step)))
+            .sum::<f64>() as f32
+            * step
+    }
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() <= 2e-3 * expected,
+            "{} instead of {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn spot_light_emits_its_power() {
+        let position = Vec3::new(1.0, 2.0, 3.0);
+        let direction = Vec3::new(0.0, -1.0, 0.0);
+        let frame = Frame::new(direction);
+
+        for &(inner, outer) in &[(0.3, 0.6), (0.0, 1.0), (0.5, 0.5), (1.2, 2.5)] {
+            let light = SpotLight::new(position, direction, Rgb::broadcast(10.0), inner, outer);
+
+            // The intensity, back from what a point at some
+            // distance receives, over the whole sphere
+            let distance = 2.5;
+            let power = 2.0
+                * PI
+                * integrate(-1.0, 1.0, |cos| {
+                    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                    let point = position + frame.to_world(Vec3::new(sin, 0.0, cos)) * distance;
+                    let sample = light.sample(&mut rand::thread_rng(), point);
+                    sample.radiance.g * distance * distance
+                });
+
+            assert_close(power, 10.0);
+        }
+    }
+
+    #[test]
+    fn point_light_emits_its_power() {
+        let position = Vec3::new(1.0, 2.0, 3.0);
+        let light = PointLight::new(position, Rgb::new(8.0, 4.0, 2.0));
+
+        // It falls off with the square of the distance, the
+        // same in every direction
+        for &distance in &[0.5, 1.0, 7.0] {
+            for &direction in &[
+                Vec3::unit_x(),
+                -Vec3::unit_y(),
+                Vec3::new(1.0, 2.0, -2.0) / 3.0,
+            ] {
+                let point = position + direction * distance;
+                let sample = light.sample(&mut rand::thread_rng(), point);
+                assert!(sample.delta);
+                assert!((sample.distance - distance).abs() < 1e-5);
+
+                let power = sample.radiance * 4.0 * PI * distance * distance;
+                assert_close(power.r, 8.0);
+                assert_close(power.b, 2.0);
+            }
+        }
+    }
+
+    #[test]
+    fn area_light_emits_its_power() {
+        let position = Vec3::new(1.0, 2.0, 3.0);
+        let radius = 0.5;
+        let light = AreaLight::new(position, radius, Rgb::broadcast(6.0));
+
+        // Every point of a sphere around the light, facing it,
+        // receives the same irradiance, and the power is what
+        // crosses that sphere
+        for &distance in &[0.6, 1.0, 5.0] {
+            let origin = position + Vec3::new(0.0, 0.0, distance);
+            let frame = Frame::new(-Vec3::unit_z());
+            let irradiance = 2.0
+                * PI
+                * integrate(0.0, PI / 2.0, |theta| {
+                    let ray = Ray {
+                        origin,
+                        direction: frame
+                            .to_world(Vec3::new(theta.sin(), 0.0, theta.cos()))
+                            .normalized(),
+                    };
+                    let radiance = light.intersect(&ray).map_or(0.0, |hit| hit.radiance.r);
+                    radiance * theta.cos() * theta.sin()
+                });
+
+            assert_close(irradiance * 4.0 * PI * distance * distance, 6.0);
+        }
+    }
+
+    #[test]
+    fn directional_light_gives_its_irradiance() {
+        let direction = Vec3::new(0.3, -1.0, 0.2).normalized();
+        let origin = Vec3::new(-1.0, 4.0, 0.5);
+
+        // Straight at the source, the irradiance is the
+        // radiance over the cone, weighted by the cosine
+        for &diameter in &[0.01, 0.2, 1.0, 3.0] {
+            let light = DirectionalLight::new(direction, Rgb::broadcast(3.0), diameter);
+            let frame = Frame::new(-direction);
+            let irradiance = 2.0
+                * PI
+                * integrate(0.0, 0.5 * diameter, |theta| {
+                    let ray = Ray {
+                        origin,
+                        direction: frame
+                            .to_world(Vec3::new(theta.sin(), 0.0, theta.cos()))
+                            .normalized(),
+                    };
+                    let radiance = light.intersect(&ray).map_or(0.0, |hit| hit.radiance.b);
+                    radiance * theta.cos() * theta.sin()
+                });
+
+            assert_close(irradiance, 3.0);
+        }
+
+        let light = DirectionalLight::new(direction, Rgb::broadcast(3.0), 0.0);
+        let sample = light.sample(&mut rand::thread_rng(), origin);
+        assert!(sample.delta);
+        assert!((sample.ray.direction + direction).magnitude() < 1e-6);
+        assert_eq!(sample.radiance, Rgb::broadcast(3.0));
+    }
+}
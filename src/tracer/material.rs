@@ -1,82 +1,166 @@
+use std::sync::Arc;
+
 use vek::rgb::Rgb;
-use vek::vec::Vec3;
-
-use super::ray::Ray;
-
-/// The different implementations for the
-/// bidirectional reflectance distribution function.
-#[derive(Copy, Clone)]
-pub enum BRDF {
-    /// A simple lambertian BRDF parametrized by reflectivity (rho)
-    Lambertian(f32),
-    Glossy,
-    BlackBody,
-}
+use vek::vec::{Vec2, Vec3};
 
-fn random_in_hemisphere(
-    rng: &mut rand::rngs::ThreadRng,
-    center: Vec3<f32>,
-    normal: Vec3<f32>,
-) -> Vec3<f32> {
-    use rand::Rng;
+use super::bsdf::{Bsdf, Conductor, Principled};
+use super::ray::RayHit;
+use super::texture::{ConstantTexture, Texture};
 
-    // Thanks, FermatsLibrary!
-    let u = rng.gen_range(0f32, 1f32);
-    let v = rng.gen_range(0f32, 1f32);
+/// The step, in texture coordinates and in distance, over
+/// which the slopes of bump maps are measured.
+const BUMP_DELTA: f32 = 1e-3;
 
-    let theta = 2f32 * std::f32::consts::PI * u;
-    let phi = (2f32 * v - 1f32).acos();
+/// How close to grazing the view may get to a normal
+/// tilted by a normal map.
+const MIN_VIEW_COS: f32 = 0.01;
 
-    let x = theta.sin() * phi.cos();
-    let y = theta.sin() * phi.sin();
-    let z = theta.cos();
+#[derive(Clone)]
+pub struct Material {
+    /// The intrinsinc color of the material.
+    pub albedo: Arc<dyn Texture>,
 
-    center + normal + Vec3::new(x, y, z)
+    /// The emitted color.
+    pub emittance: Rgb<f32>,
+
+    /// How the surface scatters light.
+    pub bsdf: Arc<dyn BsdfModel>,
+
+    /// Details added to the shading normal.
+    pub normal_map: Option<NormalMap>,
 }
 
-impl BRDF {
-    /// Returns the amount of light reflected at the given directions.
-    pub fn at(&self, incoming: Vec3<f32>, outgoing: Vec3<f32>, normal: Vec3<f32>) -> f32 {
-        match *self {
-            BRDF::Lambertian(rho) => rho / std::f32::consts::PI,
-            BRDF::Glossy => 1f32,
-            BRDF::BlackBody => 0f32,
+/// A light gray principled material, for shapes that
+/// don't have one.
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            albedo: Arc::new(ConstantTexture(Rgb::one())),
+            emittance: Rgb::zero(),
+            bsdf: Arc::new(Principled::default()),
+            normal_map: None,
         }
     }
+}
 
-    /// Reflects the incoming ray according to the BRDF
-    pub fn reflect(
-        &self,
-        rng: &mut rand::rngs::ThreadRng,
-        incoming: Vec3<f32>,
-        point: Vec3<f32>,
-        normal: Vec3<f32>,
-    ) -> Option<Ray> {
-        match *self {
-            BRDF::Lambertian(_) => {
-                let outgoing = (random_in_hemisphere(rng, point, normal) - point).normalized();
-                Some(Ray {
-                    origin: point,
-                    direction: outgoing,
-                })
+/// Tilts the shading normal to fake details that the
+/// geometry doesn't have.
+#[derive(Clone)]
+pub enum NormalMap {
+    /// Normals in the shading frame, encoded as colors
+    /// (`[0.5, 0.5, 1]` leaves the normal as is).
+    Normal(Arc<dyn Texture>),
+
+    /// A height between 0 and 1, scaled by the given height
+    /// in units of texture coordinates.
+    Bump(Arc<dyn Texture>, f32),
+}
+
+impl Material {
+    /// Applies the normal map of the material to the shading
+    /// frame of a hit. The geometric normal is left as is.
+    pub fn apply_normal_map<'a>(&self, hit: RayHit<'a>) -> RayHit<'a> {
+        let (t, b, n) = (hit.tangent, hit.bitangent, hit.normal);
+
+        let normal = match self.normal_map {
+            None => return hit,
+            Some(NormalMap::Normal(ref map)) => {
+                let c = map.color(hit.uv, hit.point) * 2.0 - 1.0;
+                t * c.r + b * c.g + n * c.b.max(0.0)
             }
-            BRDF::Glossy => Some(Ray {
-                origin: point,
-                direction: incoming.reflected(-normal),
-            }),
-            BRDF::BlackBody => None,
+            Some(NormalMap::Bump(ref map, height)) => {
+                // The slopes of the height along the tangents, from the
+                // texture coordinates for images and from the position
+                // for solid textures
+                let height_at = |duv: Vec2<f32>, dp: Vec3<f32>| {
+                    map.value(hit.uv + duv * BUMP_DELTA, hit.point + dp * BUMP_DELTA)
+                };
+                let h = height_at(Vec2::zero(), Vec3::zero());
+                let du = height_at(Vec2::unit_x(), t);
+                let dv = height_at(Vec2::unit_y(), b);
+
+                let slope = Vec2::new(du - h, dv - h) * (height / BUMP_DELTA);
+                n - t * slope.x - b * slope.y
+            }
+        };
+
+        if normal.magnitude_squared() < 1e-12 {
+            return hit;
         }
+        let normal = normal.normalized();
+
+        // Near silhouettes, the tilted normal can face away from
+        // the viewer, who would then see the surface from below.
+        // Bend it back just enough to face them.
+        let view = -hit.ray.direction;
+        let side = if hit.front_face { 1.0 } else { -1.0 };
+        let cos = normal.dot(view) * side;
+        let normal = if cos < MIN_VIEW_COS {
+            (normal + view * side * (MIN_VIEW_COS - cos)).normalized()
+        } else {
+            normal
+        };
+
+        hit.with_shading_frame(normal, Some(t))
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Material {
-    /// The intrinsinc color of the material.
-    pub albedo: Rgb<f32>,
+/// Builds the BSDF of a material at a point of its surface,
+/// from parameters that can vary over it.
+pub trait BsdfModel: Send + Sync {
+    fn at(&self, uv: Vec2<f32>, point: Vec3<f32>) -> Box<dyn Bsdf>;
+}
 
-    /// The emitted color.
-    pub emittance: Rgb<f32>,
+/// BSDFs with fixed parameters are the same everywhere.
+impl<B: Bsdf + Clone + 'static> BsdfModel for B {
+    fn at(&self, _uv: Vec2<f32>, _point: Vec3<f32>) -> Box<dyn Bsdf> {
+        Box::new(self.clone())
+    }
+}
+
+/// A `Principled` BSDF with textured parameters.
+pub struct PrincipledModel {
+    pub base_color: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    pub specular: Arc<dyn Texture>,
+    pub clearcoat: Arc<dyn Texture>,
+    pub clearcoat_roughness: Arc<dyn Texture>,
+    pub sheen: Arc<dyn Texture>,
+    pub transmission: Arc<dyn Texture>,
+    pub ior: f32,
+}
 
-    /// The BRDF.
-    pub brdf: BRDF,
+impl BsdfModel for PrincipledModel {
+    fn at(&self, uv: Vec2<f32>, point: Vec3<f32>) -> Box<dyn Bsdf> {
+        let value = |t: &Arc<dyn Texture>| t.value(uv, point).clamp(0.0, 1.0);
+
+        Box::new(Principled {
+            base_color: self.base_color.color(uv, point),
+            metallic: value(&self.metallic),
+            roughness: value(&self.roughness),
+            specular: value(&self.specular),
+            clearcoat: value(&self.clearcoat),
+            clearcoat_roughness: value(&self.clearcoat_roughness),
+            sheen: value(&self.sheen),
+            transmission: value(&self.transmission),
+            ior: self.ior,
+        })
+    }
+}
+
+/// A `Conductor` with a textured, isotropic roughness.
+pub struct ConductorModel {
+    pub roughness: Arc<dyn Texture>,
+    pub eta: Rgb<f32>,
+    pub k: Rgb<f32>,
+}
+
+impl BsdfModel for ConductorModel {
+    fn at(&self, uv: Vec2<f32>, point: Vec3<f32>) -> Box<dyn Bsdf> {
+        let roughness = self.roughness.value(uv, point).max(0.0);
+        let alpha = Vec2::broadcast(roughness * roughness);
+
+        Box::new(Conductor::new(alpha, self.eta, self.k))
+    }
 }
@@ -1,9 +1,15 @@
+pub mod background;
+pub mod bsdf;
+pub mod bvh;
 pub mod camera;
+pub mod grid;
 pub mod light;
 pub mod material;
 pub mod ray;
 pub mod render_context;
+pub mod sampling;
 pub mod shape;
+pub mod texture;
 pub mod volume;
 
 use rand::Rng;
@@ -11,75 +17,59 @@ use rayon::prelude::*;
 use vek::rgb::Rgb;
 use vek::vec::{Vec2, Vec3};
 
+use bsdf::Bsdf;
 use camera::Camera;
-use material::Material;
-use ray::{Ray, RayHit};
+use light::ShapeLight;
+use ray::{Frame, Ray, RayHit};
 use render_context::RenderContext;
+use sampling::random_square;
 use shape::Shape;
 
 use std::sync::Arc;
 
-static MAX_DEPTH: u16 = 20;
+/// Paths are never terminated by Russian roulette
+/// before this many bounces.
+const MIN_BOUNCES: u32 = 3;
 
 type Sender = std::sync::mpsc::Sender<(Vec2<usize>, Rgb<f32>)>;
 
-fn spawn_thread<'a, C>(sender: &Sender, ctx: Arc<RenderContext<C>>, idx: u16)
-where
-    C: Camera + Send + Sync + 'static,
-{
-    let tx = sender.clone();
-
-    let idx = idx as usize;
-
-    let dx = 2 * ctx.width / ctx.n_threads as usize;
-    let dy = 2 * ctx.height / ctx.n_threads as usize;
-
-    let threads_per_row = ctx.n_threads as usize / 2;
-
-    // FIXME!
-    let offset = Vec2::new(idx % threads_per_row, idx / threads_per_row);
-    let offset = offset * Vec2::new(dx, dy);
-
-    std::thread::spawn(move || {
-        let mut rng = rand::thread_rng();
-        for j in 0..dy {
-            for i in 0..dx {
-                let coord = Vec2::new(i, j);
-                let coord = coord + offset;
-
-                let mut acc = Rgb::<f32>::zero();
-
-                for _ in 0..ctx.samples {
-                    let ray = ctx.camera.generate_ray(coord);
-                    //println!("{:?}", ray);
-                    acc += trace(ctx.clone(), &mut rng, ray, 0);
-                }
-
-                let color = acc / ctx.samples as f32;
-
-                //let ray = ctx.camera.generate_ray(coord);
-                //let color = trace(ctx.clone(), &mut rng, ray, 0);
-
-                tx.send((coord, color)).unwrap();
-            }
-        }
-    });
-}
-
+/// Finds the nearest surface hit or medium collision along
+/// the ray. Collisions are random, so they are drawn with `rng`.
 fn check_hit<'a, C: Camera>(
-    ctx: Arc<RenderContext<C>>,
+    ctx: &'a RenderContext<C>,
+    rng: &mut rand::rngs::ThreadRng,
     ray: &'a Ray,
-) -> Option<(Arc<dyn Shape>, RayHit<'a>)> {
-    let mut nearest_hit: Option<(Arc<dyn Shape>, RayHit<'a>)> = None;
+) -> Option<RayHit<'a>> {
+    let mut nearest_hit: Option<RayHit<'a>> = None;
+    let mut hit_object = |object: &'a dyn Shape| {
+        if object.is_medium() {
+            object.sample_collision(ray, rng)
+        } else {
+            object.intersects(ray)
+        }
+    };
+
+    if let Some(ref bvh) = ctx.bvh {
+        bvh.intersect(ray, f32::INFINITY, |i| {
+            let hit = hit_object(&*ctx.objects[i])?;
+            match nearest_hit {
+                Some(nearest) if nearest.distance <= hit.distance => {}
+                _ => nearest_hit = Some(hit),
+            }
+            Some(hit.distance)
+        });
+
+        return nearest_hit;
+    }
 
     for obj in ctx.objects.iter() {
-        if let Some(hit) = obj.intersects(&ray) {
-            if let Some((_, nearest)) = nearest_hit {
+        if let Some(hit) = hit_object(&**obj) {
+            if let Some(nearest) = nearest_hit {
                 if hit.distance < nearest.distance {
-                    nearest_hit = Some((obj.clone(), hit));
+                    nearest_hit = Some(hit);
                 }
             } else {
-                nearest_hit = Some((obj.clone(), hit));
+                nearest_hit = Some(hit);
             }
         }
     }
@@ -87,183 +77,370 @@ fn check_hit<'a, C: Camera>(
     nearest_hit
 }
 
+/// Estimates the radiance arriving along the ray, by
+/// following a single path through the scene.
+///
+/// At every bounce, the lights are sampled directly (next
+/// event estimation), then the BSDF picks the direction in
+/// which the path continues. Lights reached by both strategies
+/// get their contributions combined with multiple importance
+/// sampling. After `MIN_BOUNCES`, paths are randomly terminated
+/// with a probability that grows as their throughput drops
+/// (Russian roulette), which keeps the estimate unbiased
+/// without a fixed depth limit.
+///
+/// The path starts in the fog, if there is any, and is in it
+/// whenever it travels outside of the objects.
 fn trace<C: Camera>(
-    ctx: Arc<RenderContext<C>>,
+    ctx: &RenderContext<C>,
     rng: &mut rand::rngs::ThreadRng,
-    ray: Ray,
-    depth: u16,
+    mut ray: Ray,
 ) -> Rgb<f32> {
-    if depth > MAX_DEPTH {
-        Rgb::zero()
-    } else {
-        let nearest_hit = check_hit(ctx.clone(), &ray);
-
-        if let Some((obj, hit)) = nearest_hit {
-            let diffuse = {
-                let mut diffuse_coeff = 0f32;
-
-                for light in ctx.lights.iter() {
-                    let sample = light.sample(&mut rng.clone(), hit.point);
-                    let light_ray = sample.ray;
-                    
-                    if let Some((_, light_hit)) = check_hit(ctx.clone(), &light_ray) {
-                        if light_hit.distance < sample.distance {
-                            continue;
-                        }
-                    }
-                    
-                    diffuse_coeff += light_ray.direction.dot(obj.normal_at(hit.point)).max(0.0);
+    let mut radiance = Rgb::<f32>::zero();
+    let mut throughput = Rgb::<f32>::one();
+    let mut bounces = 0;
+
+    // The pdf of the BSDF sample that produced the current
+    // ray, or `None` if light sampling couldn't have found it
+    let mut bsdf_pdf: Option<f32> = None;
+    let mut in_fog = ctx.fog.is_some();
+
+    loop {
+        let mut hit = check_hit(ctx, rng, &ray);
+        if let Some(fog) = ctx.fog.as_ref().filter(|_| in_fog) {
+            if let Some(fog_hit) = fog.sample_collision(&ray, rng) {
+                match hit {
+                    Some(nearest) if nearest.distance <= fog_hit.distance => {}
+                    _ => hit = Some(fog_hit),
                 }
+            }
+        }
+        let max_distance = hit.map_or(f32::INFINITY, |hit| hit.distance);
+
+        for light in ctx.lights.iter() {
+            if let Some(light_hit) = light.intersect(&ray) {
+                if light_hit.distance < max_distance {
+                    let weight = match bsdf_pdf {
+                        Some(pdf) => power_heuristic(pdf, light_hit.pdf),
+                        None => 1.0,
+                    };
+                    radiance += throughput * light_hit.radiance * weight;
+                }
+            }
+        }
 
-                diffuse_coeff * obj.material().albedo
-            };
+        let hit = match hit {
+            Some(hit) => hit,
+            // The background was picked up with the lights
+            None => break,
+        };
+
+        let material = hit.material;
+        let hit = material.apply_normal_map(hit);
+        let albedo = material.albedo.color(hit.uv, hit.point);
+        let bsdf = material.bsdf.at(hit.uv, hit.point);
+        let frame = hit.frame();
+        let wo = frame.to_local(-ray.direction);
+
+        // A ray leaving through the back of a surface
+        // traveled inside of it
+        if !hit.front_face {
+            let absorption = bsdf.absorption();
+            if absorption != Rgb::zero() {
+                throughput *= absorption.map(|a| (-a * hit.distance).exp());
+            }
+        }
 
-            let reflected = {
-                let normal = obj.normal_at(hit.point);
-                if let Some(reflection_ray) = obj.material().brdf.reflect(&mut rng.clone(), ray.direction, hit.point, normal) {
-                    let pdf = obj.material().brdf.at(ray.direction, reflection_ray.direction, normal);
-                    (trace(ctx.clone(), rng, reflection_ray, depth + 1) * normal.dot(ray.direction).max(0.0)) / pdf
-                } else {
-                    Rgb::zero()
+        if material.emittance != Rgb::zero() {
+            // Emissive shapes are sampled as lights too,
+            // unless they can't be, see `ShapeLight::new`
+            let weight = match bsdf_pdf {
+                Some(pdf) if hit.object.area() > 0.0 => {
+                    let light_pdf =
+                        ShapeLight::pdf(hit.object, ray.origin, hit.point, hit.geometric_normal);
+                    power_heuristic(pdf, light_pdf)
                 }
+                _ => 1.0,
             };
+            radiance += throughput * material.emittance * weight;
+        }
+        let direct = direct_light(ctx, rng, &hit, &frame, wo, &*bsdf, in_fog);
+        radiance += throughput * albedo * direct;
 
-            let refracted = {
-                // TODO
-                Rgb::zero()
-            };
+        let sample = match bsdf.sample(wo, random_square(rng)) {
+            Some(sample) if sample.pdf > 0.0 => sample,
+            _ => break,
+        };
 
-            obj.material().emittance + diffuse + reflected + refracted
+        throughput *= albedo * sample.f * (sample.wi.z.abs() / sample.pdf);
+        bsdf_pdf = if sample.specular {
+            None
         } else {
-            let t = 0.5 * (ray.direction.normalized().y + 1.0);
-            (1.0 - t) * Rgb::new(1.0, 1.0, 1.0) + t * Rgb::new(0.5, 0.7, 1.0)
+            Some(sample.pdf)
+        };
+
+        bounces += 1;
+        if bounces > MIN_BOUNCES {
+            let survival = throughput.reduce_partial_max().min(0.95);
+            if rng.gen_range(0f32, 1f32) >= survival {
+                break;
+            }
+            throughput /= survival;
+        } else if throughput == Rgb::zero() {
+            break;
         }
 
-        //if let Some(hit) = nearest_hit {
-        //let normal = hit.object.normal_at(&hit);
-
-        ////if depth == 0 {
-        ////println!(
-        ////"Primary hit at {:?}. Origin: {:?}",
-        ////hit.ray.origin + hit.distance * hit.ray.direction,
-        ////hit.ray.origin
-        ////);
-        ////}
-
-        //match hit.object.material() {
-        //Material::Lambertian(color, roughness) => {
-        //let new_origin = ray.origin + ray.direction * hit.distance;
-        //let new_direction =
-        //(random_in_hemisphere(rng, new_origin, normal) - new_origin).normalized();
-
-        //let cos_theta = new_direction.dot(normal);
-
-        //let p = 1f32 / (2f32 * std::f32::consts::PI);
-
-        //let new_ray = Ray {
-        //origin: new_origin,
-        //direction: new_direction,
-        //};
-
-        //let brdf = roughness / std::f32::consts::PI;
-
-        //let indirect = trace(ctx.clone(), rng, new_ray, depth + 1);
-
-        ////diffuse*color + brdf * incoming * cos_theta / p
-        ////brdf * incoming * cos_theta / p
-
-        //let diffuse = ctx.lights.iter()
-        //.filter_map(|l| {
-        //let dist = (l.position - new_origin);
-
-        //let shadow_ray = Ray {
-        //origin: new_origin,
-        //direction: dist.normalized()
-        //};
-
-        //match check_hit(ctx.clone(), &shadow_ray) {
-        //Some(hit) if hit.distance > dist.magnitude() => {
-        //Some(normal.dot(dist.normalized()).max(0.0))
-        //},
-        //None => {
-        //Some(normal.dot(dist.normalized()).max(0.0))
-        //},
-        //_ => None
-        //}
-        //})
-        //.sum::<f32>();
-
-        //ctx.ambient + color * (diffuse + brdf * indirect * cos_theta / p)
-        //}
-        //Material::Glossy => {
-        //let reflected = ray.direction.reflected(-normal);
-        //let new_origin = ray.origin + ray.direction * hit.distance;
-        //let new_ray = Ray {
-        //origin: new_origin,
-        //direction: reflected.normalized(),
-        //};
-        //0.8f32 * trace(ctx, rng, new_ray, depth + 1)
-        //}
-        //}
-        //} else {
-        //let t = 0.5 * (ray.direction.normalized().y + 1.0);
-        //(1.0 - t) * Rgb::new(1.0, 1.0, 1.0) + t * Rgb::new(0.5, 0.7, 1.0)
-        //}
+        in_fog = enters_fog(ctx, &hit, sample.wi.z * wo.z < 0.0, in_fog);
+        ray = hit.spawn(frame.to_world(sample.wi));
     }
+
+    radiance
 }
 
-pub fn random_in_hemisphere(
+/// The light reflected at the hit straight from the lights,
+/// with one sample per light, before the albedo.
+fn direct_light<C: Camera>(
+    ctx: &RenderContext<C>,
     rng: &mut rand::rngs::ThreadRng,
-    center: Vec3<f32>,
-    normal: Vec3<f32>,
-) -> Vec3<f32> {
-    //let theta = rng.gen_range(0f32, std::f32::consts::PI);
-    //let phi = rng.gen_range(0f32, 2f32 * std::f32::consts::PI);
+    hit: &RayHit,
+    frame: &Frame,
+    wo: Vec3<f32>,
+    bsdf: &dyn Bsdf,
+    in_fog: bool,
+) -> Rgb<f32> {
+    let mut reflected = Rgb::zero();
 
-    //let x = theta.sin() * phi.cos();
-    //let y = theta.sin() * phi.sin();
-    //let z = theta.cos();
+    for light in ctx.lights.iter() {
+        let sample = light.sample(rng, hit.point);
+        let direction = sample.ray.direction;
 
-    //center + normal + Vec3::new(x, y, z)
+        let wi = frame.to_local(direction);
+        let f = bsdf.eval(wo, wi);
+        if f == Rgb::zero() || sample.pdf <= 0.0 {
+            continue;
+        }
 
-    let x = rng.gen_range(0f32, 1f32);
-    let y = rng.gen_range(0f32, 1f32);
-    let z = rng.gen_range(0f32, 1f32);
+        if sample.radiance == Rgb::zero() {
+            continue;
+        }
+
+        let shadow_ray = hit.spawn(direction);
+        let mut transmittance = transmittance(ctx, rng, &shadow_ray, sample.distance);
+        if let Some(fog) = ctx.fog.as_ref() {
+            if transmittance > 0.0 && enters_fog(ctx, hit, wi.z * wo.z < 0.0, in_fog) {
+                transmittance *= fog
+                    .transmittance(&shadow_ray, sample.distance, rng)
+                    .unwrap_or(1.0);
+            }
+        }
+        if transmittance <= 0.0 {
+            continue;
+        }
+
+        let weight = if sample.delta {
+            1.0
+        } else {
+            power_heuristic(sample.pdf, bsdf.pdf(wo, wi))
+        };
+
+        reflected += sample.radiance * f * (wi.z.abs() * weight * transmittance / sample.pdf);
+    }
+
+    reflected
+}
 
-    center + normal + Vec3::new(x, y, z).normalized()
+/// Whether a ray leaving the hit goes through the fog, given
+/// whether it was transmitted through the surface. Only
+/// transmissions, like refractions into and out of glass,
+/// move the path between the fog and the inside of an
+/// object, which must be closed. Reflections, even off the
+/// back of open surfaces, and scattering in a medium leave
+/// the path where it was.
+fn enters_fog<C: Camera>(
+    ctx: &RenderContext<C>,
+    hit: &RayHit,
+    transmitted: bool,
+    in_fog: bool,
+) -> bool {
+    ctx.fog.is_some()
+        && if transmitted && !hit.object.is_medium() {
+            !hit.front_face
+        } else {
+            in_fog
+        }
 }
 
-pub fn render<'a, C>(sender: Sender, ctx: RenderContext<C>)
+/// The fraction of the light that gets from the origin of the
+/// ray to `distance` along it: none if a surface is in the way,
+/// and what the volumes let through otherwise.
+fn transmittance<C: Camera>(
+    ctx: &RenderContext<C>,
+    rng: &mut rand::rngs::ThreadRng,
+    ray: &Ray,
+    distance: f32,
+) -> f32 {
+    let mut transmittance = 1.0;
+
+    // Boxes past `distance` are never visited, and once
+    // blocked, the returned distance culls the rest
+    let mut visit = |object: &dyn Shape| -> Option<f32> {
+        if transmittance <= 0.0 {
+            return None;
+        }
+
+        match object.transmittance(ray, distance, rng) {
+            Some(t) => transmittance *= t,
+            None => match object.intersects(ray) {
+                Some(hit) if hit.distance < distance => {
+                    transmittance = 0.0;
+                    return Some(0.0);
+                }
+                _ => {}
+            },
+        }
+        None
+    };
+
+    match ctx.bvh {
+        Some(ref bvh) => bvh.intersect(ray, distance, |i| visit(&*ctx.objects[i])),
+        None => {
+            for obj in ctx.objects.iter() {
+                visit(&**obj);
+            }
+        }
+    }
+
+    transmittance
+}
+
+/// The MIS weight of a sample taken with density `pdf`, when
+/// the other strategy would have taken it with `other_pdf`.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+pub fn render<C>(sender: Sender, ctx: RenderContext<C>)
 where
     C: Camera + Send + Sync + 'static,
 {
     let pxls = 0..(ctx.width * ctx.height);
 
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(ctx.n_threads as usize)
+        .build()
+        .unwrap();
+
     let ctx = Arc::new(ctx);
-    //for i in 0..ctx.n_threads {
-    //spawn_thread(sender, ctx.clone(), i);
-    //}
 
     std::thread::spawn(move || {
-        pxls.into_par_iter()
-            .for_each_with(sender.clone(), |s, idx| {
-                let u = idx / ctx.width;
-                let v = idx % ctx.width;
+        pool.install(|| {
+            pxls.into_par_iter()
+                .for_each_with(sender.clone(), |s, idx| {
+                    let u = idx % ctx.width;
+                    let v = idx / ctx.width;
 
-                let coord = Vec2::new(u, v);
+                    let coord = Vec2::new(u, v);
 
-                let mut acc = Rgb::<f32>::zero();
-                let mut rng = rand::thread_rng();
+                    let mut acc = Rgb::<f32>::zero();
+                    let mut rng = rand::thread_rng();
 
-                for _ in 0..ctx.samples {
-                    let ray = ctx.camera.generate_ray(coord);
-                    //println!("{:?}", ray);
-                    acc += trace(ctx.clone(), &mut rng, ray, 0);
-                }
+                    for _ in 0..ctx.samples {
+                        let ray = ctx.camera.generate_ray(coord);
+                        acc += trace(&ctx, &mut rng, ray);
+                    }
 
-                let color = acc / ctx.samples as f32;
+                    let color = acc / ctx.samples as f32;
 
-                s.send((coord, color)).unwrap();
-            });
+                    s.send((coord, color)).unwrap();
+                });
+        });
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vek::mat::Mat4;
+
+    use camera::MtxCamera;
+    use material::Material;
+    use shape::{Sphere, Triangle};
+    use volume::{Fog, HomogeneousMedium};
+
+    fn foggy() -> RenderContext<MtxCamera> {
+        RenderContext {
+            width: 1,
+            height: 1,
+            samples: 1,
+            n_threads: 1,
+            objects: Arc::new(Vec::new()),
+            bvh: None,
+            camera: MtxCamera {
+                vp_inverse: Mat4::identity(),
+                width: 1,
+                height: 1,
+            },
+            lights: Arc::new(Vec::new()),
+            fog: Some(Fog::new(
+                Box::new(HomogeneousMedium { extinction: 0.1 }),
+                Rgb::one(),
+                0.0,
+            )),
+        }
+    }
+
+    #[test]
+    fn tracks_the_fog_through_surfaces() {
+        let ctx = foggy();
+        let material = Material::default();
+        let down = Ray {
+            origin: Vec3::new(0.2, 0.2, 1.0),
+            direction: -Vec3::unit_z(),
+        };
+        let up = Ray {
+            origin: Vec3::new(0.2, 0.2, -1.0),
+            direction: Vec3::unit_z(),
+        };
+
+        // Reflections off either side of an open surface,
+        // however the winding goes
+        let quad = Triangle::new(
+            [Vec3::zero(), Vec3::unit_x(), Vec3::unit_y()],
+            Arc::new(Material::default()),
+        );
+        let front = quad.intersects(&down).unwrap();
+        let back = quad.intersects(&up).unwrap();
+        assert_ne!(front.front_face, back.front_face);
+        for hit in &[front, back] {
+            assert!(enters_fog(&ctx, hit, false, true));
+            assert!(!enters_fog(&ctx, hit, false, false));
+        }
+
+        // Refractions into and out of a closed object
+        let sphere = Sphere::new(Vec3::zero(), 0.5, Material::default());
+        let entering = RayHit::new(&down, 0.5, &sphere, &material, Vec3::unit_z(), Vec2::zero());
+        let exiting = RayHit::new(
+            &down,
+            1.5,
+            &sphere,
+            &material,
+            -Vec3::unit_z(),
+            Vec2::zero(),
+        );
+        assert!(!enters_fog(&ctx, &entering, true, true));
+        assert!(enters_fog(&ctx, &exiting, true, false));
+        assert!(!enters_fog(&ctx, &exiting, false, false));
+
+        // Media scatter every way without moving the path
+        let fog = ctx.fog.as_ref().unwrap();
+        let collision = RayHit::new(&down, 0.5, fog, &material, Vec3::unit_z(), Vec2::zero());
+        assert!(enters_fog(&ctx, &collision, true, true));
+        assert!(!enters_fog(&ctx, &collision, true, false));
+    }
+}
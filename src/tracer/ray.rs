@@ -1,18 +1,235 @@
-use std::sync::Arc;
-
 use vek::vec::{Vec2, Vec3};
 
+use super::material::Material;
 use super::shape::Shape;
 
+/// How far from the surface secondary rays start, to keep
+/// them from hitting the surface they are leaving.
+const SPAWN_OFFSET: f32 = 0.0001;
+
 #[derive(Copy, Clone, Debug)]
 pub struct Ray {
     pub origin: Vec3<f32>,
     pub direction: Vec3<f32>,
 }
 
+/// Everything known about the point where a ray hit a shape.
+/// It is filled in once by `Shape::intersects`, so the
+/// integrator never has to query the shape again.
 #[derive(Copy, Clone)]
 pub struct RayHit<'a> {
     pub ray: &'a Ray,
     pub distance: f32,
     pub point: Vec3<f32>,
+
+    /// The shape that was hit.
+    pub object: &'a dyn Shape,
+    pub material: &'a Material,
+
+    /// The true normal of the surface, pointing out of the shape.
+    pub geometric_normal: Vec3<f32>,
+
+    /// The normal used for shading (e.g. interpolated from the
+    /// vertex normals), on the same side as the geometric one.
+    pub normal: Vec3<f32>,
+
+    /// Together with `normal`, an orthonormal basis of
+    /// the shading frame. The tangent follows the direction
+    /// of increasing `u` when the shape has texture coordinates.
+    pub tangent: Vec3<f32>,
+    pub bitangent: Vec3<f32>,
+
+    /// The texture coordinates of the point.
+    pub uv: Vec2<f32>,
+
+    /// Whether the ray hit the outside of the surface.
+    pub front_face: bool,
+}
+
+impl<'a> RayHit<'a> {
+    /// A hit whose shading normal is the geometric one,
+    /// which must be normalized and point out of the shape.
+    pub fn new(
+        ray: &'a Ray,
+        distance: f32,
+        object: &'a dyn Shape,
+        material: &'a Material,
+        normal: Vec3<f32>,
+        uv: Vec2<f32>,
+    ) -> RayHit<'a> {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+
+        RayHit {
+            ray,
+            distance,
+            point: ray.origin + distance * ray.direction,
+            object,
+            material,
+            geometric_normal: normal,
+            normal,
+            tangent,
+            bitangent,
+            uv,
+            front_face: ray.direction.dot(normal) < 0.0,
+        }
+    }
+
+    /// Replaces the shading normal, building the rest of the
+    /// frame around it. If a tangent is given, it is made
+    /// orthogonal to the normal and used as is.
+    pub fn with_shading_frame(
+        mut self,
+        normal: Vec3<f32>,
+        tangent: Option<Vec3<f32>>,
+    ) -> RayHit<'a> {
+        // Keep the shading normal on the same side as the
+        // geometric one, even with bad vertex normals
+        let normal = if normal.dot(self.geometric_normal) < 0.0 {
+            -normal
+        } else {
+            normal
+        };
+
+        let tangent = tangent
+            .map(|t| t - normal * normal.dot(t))
+            .filter(|t| t.magnitude_squared() > 1e-12)
+            .map(|t| t.normalized());
+
+        let (tangent, bitangent) = match tangent {
+            Some(t) => (t, normal.cross(t)),
+            None => orthonormal_basis(normal),
+        };
+
+        self.normal = normal;
+        self.tangent = tangent;
+        self.bitangent = bitangent;
+        self
+    }
+
+    /// The shading frame, in which BSDFs work. The ray comes
+    /// from above it exactly when it hits the front face, so
+    /// BSDFs tell the inside from the outside like the rest
+    /// of the integrator. Near silhouettes, where the shading
+    /// normal would put the ray on the other side, the frame
+    /// falls back to the geometric normal.
+    pub fn frame(&self) -> Frame {
+        let above = self.ray.direction.dot(self.normal) < 0.0;
+        let hit = if above == self.front_face {
+            *self
+        } else {
+            self.with_shading_frame(self.geometric_normal, Some(self.tangent))
+        };
+
+        Frame {
+            tangent: hit.tangent,
+            bitangent: hit.bitangent,
+            normal: hit.normal,
+        }
+    }
+
+    /// Creates a ray leaving the hit point in the given
+    /// direction, pushed off the surface along the geometric
+    /// normal to the side the direction points to.
+    pub fn spawn(&self, direction: Vec3<f32>) -> Ray {
+        let offset = if direction.dot(self.geometric_normal) >= 0.0 {
+            self.geometric_normal * SPAWN_OFFSET
+        } else {
+            -self.geometric_normal * SPAWN_OFFSET
+        };
+
+        Ray {
+            origin: self.point + offset,
+            direction,
+        }
+    }
+}
+
+/// An orthonormal basis, to move directions to and from
+/// the space where the normal is +z.
+#[derive(Copy, Clone, Debug)]
+pub struct Frame {
+    pub tangent: Vec3<f32>,
+    pub bitangent: Vec3<f32>,
+    pub normal: Vec3<f32>,
+}
+
+impl Frame {
+    /// Any frame around the given unit normal.
+    pub fn new(normal: Vec3<f32>) -> Frame {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        Frame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(self, v: Vec3<f32>) -> Vec3<f32> {
+        Vec3::new(
+            v.dot(self.tangent),
+            v.dot(self.bitangent),
+            v.dot(self.normal),
+        )
+    }
+
+    pub fn to_world(self, v: Vec3<f32>) -> Vec3<f32> {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+}
+
+/// Two unit vectors that form an orthonormal basis with
+/// the given unit normal (Duff et al., 2017).
+pub fn orthonormal_basis(n: Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let sign = 1f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+
+    (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tracer::shape::Sphere;
+
+    #[test]
+    fn frame_agrees_with_the_face() {
+        let sphere = Sphere::new(Vec3::zero(), 1.0, Material::default());
+        let material = Material::default();
+        let grazing = Ray {
+            origin: Vec3::new(-1.0, 0.0, 0.2),
+            direction: Vec3::new(1.0, 0.0, -0.2).normalized(),
+        };
+        let hit = |ray| RayHit::new(ray, 1.0, &sphere, &material, Vec3::unit_z(), Vec2::zero());
+
+        // A shading normal that doesn't face the ray is ignored
+        let tilted = Vec3::new(0.6, 0.0, 0.8);
+        let silhouette = hit(&grazing).with_shading_frame(tilted, None);
+        assert!(silhouette.front_face);
+        assert_eq!(silhouette.frame().normal, Vec3::unit_z());
+        assert!(silhouette.frame().to_local(-grazing.direction).z > 0.0);
+
+        // But kept where it does, or from inside
+        let facing = Vec3::new(-0.6, 0.0, 0.8);
+        assert_eq!(
+            hit(&grazing)
+                .with_shading_frame(facing, None)
+                .frame()
+                .normal,
+            facing
+        );
+
+        let inside = Ray {
+            origin: Vec3::new(-1.0, 0.0, -0.2),
+            direction: Vec3::new(1.0, 0.0, 0.2).normalized(),
+        };
+        let exiting = hit(&inside).with_shading_frame(tilted, None);
+        assert!(!exiting.front_face);
+        assert_eq!(exiting.frame().normal, tilted);
+        assert!(exiting.frame().to_local(-inside.direction).z < 0.0);
+    }
 }
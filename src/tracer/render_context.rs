@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
+use super::bvh::Bvh;
 use super::camera::Camera;
 use super::light::LightSampler;
 use super::shape::Shape;
+use super::volume::Fog;
 
 /// Stores all the information needed to perform
 /// the rendering.
@@ -14,8 +16,14 @@ pub struct RenderContext<C: Camera> {
 
     pub objects: Arc<Vec<Arc<dyn Shape>>>,
 
+    /// The hierarchy over `objects`. Without it, every
+    /// object is tested against every ray.
+    pub bvh: Option<Bvh>,
+
     pub camera: C,
 
     pub lights: Arc<Vec<Arc<dyn LightSampler>>>,
-    pub ambient: vek::rgb::Rgb<f32>,
+
+    /// The medium around the objects, if any.
+    pub fog: Option<Fog>,
 }
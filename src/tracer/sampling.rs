@@ -0,0 +1,310 @@
+//! Warps of uniform random numbers to the domains the renderer
+//! samples, with the densities they end up with.
+//!
+//! Directions are in a local frame around +z, to be moved to
+//! the world with a `ray::Frame`. All the samplers take two
+//! uniform numbers in [0, 1), see `random_square`.
+
+use rand::Rng;
+use vek::vec::{Vec2, Vec3};
+
+use std::f32::consts::PI;
+
+/// Two uniform random numbers in [0, 1).
+pub fn random_square<R: Rng + ?Sized>(rng: &mut R) -> Vec2<f32> {
+    Vec2::new(rng.gen_range(0f32, 1f32), rng.gen_range(0f32, 1f32))
+}
+
+/// A direction picked uniformly over the whole sphere.
+pub fn uniform_sphere(u: Vec2<f32>) -> Vec3<f32> {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> f32 {
+    1.0 / (4.0 * PI)
+}
+
+/// A direction picked uniformly over the hemisphere around +z.
+#[cfg(test)]
+pub fn uniform_hemisphere(u: Vec2<f32>) -> Vec3<f32> {
+    let z = u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+#[cfg(test)]
+pub fn uniform_hemisphere_pdf() -> f32 {
+    1.0 / (2.0 * PI)
+}
+
+/// A direction in the hemisphere around +z, with a density
+/// proportional to its cosine to +z. Points on the disk are
+/// projected up to the hemisphere (Malley's method).
+pub fn cosine_hemisphere(u: Vec2<f32>) -> Vec3<f32> {
+    let d = concentric_disk(u);
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+
+    Vec3::new(d.x, d.y, z)
+}
+
+/// The density of `cosine_hemisphere` for a direction
+/// with the given cosine to +z.
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.0) / PI
+}
+
+/// A point picked uniformly on the unit disk. The square is
+/// mapped to concentric squares, which keeps neighbouring
+/// samples close to each other (Shirley and Chiu, 1997).
+pub fn concentric_disk(u: Vec2<f32>) -> Vec2<f32> {
+    let offset = u * 2.0 - Vec2::one();
+    if offset.x == 0.0 && offset.y == 0.0 {
+        return Vec2::zero();
+    }
+
+    let (r, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, PI / 4.0 * (offset.y / offset.x))
+    } else {
+        (offset.y, PI / 2.0 - PI / 4.0 * (offset.x / offset.y))
+    };
+
+    Vec2::new(theta.cos(), theta.sin()) * r
+}
+
+/// The density of `concentric_disk`, per unit of area.
+#[cfg(test)]
+pub fn concentric_disk_pdf() -> f32 {
+    1.0 / PI
+}
+
+/// A direction picked uniformly in the cone around +z
+/// with the given cosine of its half angle.
+pub fn uniform_cone(u: Vec2<f32>, cos_max: f32) -> Vec3<f32> {
+    let cos_theta = 1.0 - u.x * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// The density of `uniform_cone`, one over the
+/// solid angle of the cone.
+pub fn uniform_cone_pdf(cos_max: f32) -> f32 {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
+/// A point picked uniformly on a triangle, as the barycentric
+/// coordinates of the second and third vertices.
+pub fn uniform_triangle(u: Vec2<f32>) -> Vec2<f32> {
+    let su = u.x.sqrt();
+    Vec2::new(su * (1.0 - u.y), su * u.y)
+}
+
+/// The density of `uniform_triangle` on a triangle
+/// with the given area.
+#[cfg(test)]
+pub fn uniform_triangle_pdf(area: f32) -> f32 {
+    1.0 / area
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const SAMPLES: usize = 200_000;
+    const BINS: usize = 20;
+
+    fn samples<T, F: Fn(Vec2<f32>) -> T>(seed: u64, f: F) -> Vec<T> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..SAMPLES).map(|_| f(random_square(&mut rng))).collect()
+    }
+
+    /// Checks that the values are distributed along the given
+    /// cumulative distribution function over [lo, hi], with a
+    /// chi-squared test on equally sized bins.
+    fn assert_distributed(values: &[f32], lo: f32, hi: f32, cdf: impl Fn(f32) -> f32) {
+        let mut counts = [0usize; BINS];
+        for &v in values {
+            assert!(
+                v >= lo - 1e-5 && v <= hi + 1e-5,
+                "{} is out of [{}, {}]",
+                v,
+                lo,
+                hi
+            );
+            let bin = ((v - lo) / (hi - lo) * BINS as f32) as usize;
+            counts[bin.min(BINS - 1)] += 1;
+        }
+
+        let mut chi2 = 0.0;
+        for (i, &count) in counts.iter().enumerate() {
+            let a = lo + (hi - lo) * i as f32 / BINS as f32;
+            let b = lo + (hi - lo) * (i + 1) as f32 / BINS as f32;
+            let expected = (cdf(b) - cdf(a)) as f64 * values.len() as f64;
+            if expected > 0.0 {
+                chi2 += (count as f64 - expected).powi(2) / expected;
+            } else {
+                assert_eq!(count, 0, "bin {} should be empty", i);
+            }
+        }
+
+        // Far above the 99.9th percentile of the chi-squared
+        // distribution with 19 degrees of freedom (43.8)
+        assert!(
+            chi2 < 60.0,
+            "chi-squared of {} for counts {:?}",
+            chi2,
+            counts
+        );
+    }
+
+    /// Checks that the density integrates to one over the sphere,
+    /// estimating the integral with uniformly picked directions.
+    fn assert_normalized(pdf: impl Fn(Vec3<f32>) -> f32) {
+        let estimates: Vec<f64> = samples(7, uniform_sphere)
+            .iter()
+            .map(|&w| f64::from(pdf(w) / uniform_sphere_pdf()))
+            .collect();
+
+        let n = estimates.len() as f64;
+        let integral = estimates.iter().sum::<f64>() / n;
+        let variance = estimates
+            .iter()
+            .map(|e| (e - integral).powi(2))
+            .sum::<f64>()
+            / (n - 1.0);
+
+        // Within four standard errors of the estimate
+        let tolerance = 4.0 * (variance / n).sqrt() + 1e-4;
+        assert!(
+            (integral - 1.0).abs() < tolerance,
+            "the pdf integrates to {}",
+            integral
+        );
+    }
+
+    fn assert_unit(w: Vec3<f32>) {
+        assert!(
+            (w.magnitude() - 1.0).abs() < 1e-4,
+            "{:?} isn't normalized",
+            w
+        );
+    }
+
+    fn azimuth(w: Vec3<f32>) -> f32 {
+        w.y.atan2(w.x)
+    }
+
+    #[test]
+    fn uniform_sphere_is_uniform() {
+        let directions = samples(1, uniform_sphere);
+        directions.iter().for_each(|&w| assert_unit(w));
+
+        // Equal areas between equally spaced heights (Archimedes)
+        let z: Vec<_> = directions.iter().map(|w| w.z).collect();
+        assert_distributed(&z, -1.0, 1.0, |z| (z + 1.0) / 2.0);
+
+        let phi: Vec<_> = directions.iter().map(|&w| azimuth(w)).collect();
+        assert_distributed(&phi, -PI, PI, |phi| (phi + PI) / (2.0 * PI));
+
+        assert_normalized(|_| uniform_sphere_pdf());
+    }
+
+    #[test]
+    fn uniform_hemisphere_is_uniform() {
+        let directions = samples(2, uniform_hemisphere);
+        directions.iter().for_each(|&w| assert_unit(w));
+
+        let z: Vec<_> = directions.iter().map(|w| w.z).collect();
+        assert_distributed(&z, 0.0, 1.0, |z| z);
+
+        let phi: Vec<_> = directions.iter().map(|&w| azimuth(w)).collect();
+        assert_distributed(&phi, -PI, PI, |phi| (phi + PI) / (2.0 * PI));
+
+        assert_normalized(|w| {
+            if w.z >= 0.0 {
+                uniform_hemisphere_pdf()
+            } else {
+                0.0
+            }
+        });
+    }
+
+    #[test]
+    fn cosine_hemisphere_follows_the_cosine() {
+        let directions = samples(3, cosine_hemisphere);
+        directions.iter().for_each(|&w| assert_unit(w));
+
+        // The density of the height is 2z
+        let z: Vec<_> = directions.iter().map(|w| w.z).collect();
+        assert_distributed(&z, 0.0, 1.0, |z| z * z);
+
+        let phi: Vec<_> = directions.iter().map(|&w| azimuth(w)).collect();
+        assert_distributed(&phi, -PI, PI, |phi| (phi + PI) / (2.0 * PI));
+
+        assert_normalized(|w| cosine_hemisphere_pdf(w.z));
+    }
+
+    #[test]
+    fn concentric_disk_is_uniform() {
+        let points = samples(4, concentric_disk);
+
+        // The squared radius is uniform for a uniform disk
+        let r2: Vec<_> = points.iter().map(|p| p.magnitude_squared()).collect();
+        assert_distributed(&r2, 0.0, 1.0, |r2| r2);
+
+        let phi: Vec<_> = points.iter().map(|p| p.y.atan2(p.x)).collect();
+        assert_distributed(&phi, -PI, PI, |phi| (phi + PI) / (2.0 * PI));
+
+        assert!((concentric_disk_pdf() * PI - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn uniform_cone_is_uniform() {
+        for &cos_max in [0.9f32, 0.3, -0.5].iter() {
+            let directions = samples(5, |u| uniform_cone(u, cos_max));
+            directions.iter().for_each(|&w| assert_unit(w));
+
+            let z: Vec<_> = directions.iter().map(|w| w.z).collect();
+            assert_distributed(&z, cos_max, 1.0, |z| (z - cos_max) / (1.0 - cos_max));
+
+            assert_normalized(|w| {
+                if w.z >= cos_max {
+                    uniform_cone_pdf(cos_max)
+                } else {
+                    0.0
+                }
+            });
+        }
+
+        // The whole sphere is a cone too
+        assert!((uniform_cone_pdf(-1.0) - uniform_sphere_pdf()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn uniform_triangle_is_uniform() {
+        let points = samples(6, uniform_triangle);
+
+        // Each barycentric coordinate b has the density 2(1 - b)
+        // on a uniform triangle, whatever the vertex
+        let cdf = |b: f32| 1.0 - (1.0 - b) * (1.0 - b);
+        let b0: Vec<_> = points.iter().map(|b| 1.0 - b.x - b.y).collect();
+        let b1: Vec<_> = points.iter().map(|b| b.x).collect();
+        let b2: Vec<_> = points.iter().map(|b| b.y).collect();
+
+        assert_distributed(&b0, 0.0, 1.0, cdf);
+        assert_distributed(&b1, 0.0, 1.0, cdf);
+        assert_distributed(&b2, 0.0, 1.0, cdf);
+
+        assert!((uniform_triangle_pdf(0.5) - 2.0).abs() < 1e-6);
+    }
+}
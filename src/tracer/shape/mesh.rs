@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use rand::Rng;
+use vek::vec::Vec3;
+
+use crate::tracer::bvh::{Aabb, Bvh};
+use crate::tracer::material::Material;
+use crate::tracer::ray::{Ray, RayHit};
+
+use super::{Shape, SurfaceSample, Triangle};
+
+/// A group of triangles sharing the same material.
+pub struct Mesh {
+    pub name: String,
+    pub triangles: Vec<Triangle>,
+    pub material: Arc<Material>,
+    bvh: Bvh,
+    /// The running sum of the areas of the triangles, to
+    /// pick them proportionally to their area
+    area_cdf: Vec<f32>,
+}
+
+impl Mesh {
+    pub fn new(name: String, triangles: Vec<Triangle>, material: Arc<Material>) -> Mesh {
+        let bounds = triangles.iter().map(|t| t.bounds()).collect::<Vec<_>>();
+        let bvh = Bvh::new(&bounds);
+
+        let area_cdf = triangles
+            .iter()
+            .scan(0.0, |sum, t| {
+                *sum += t.area();
+                Some(*sum)
+            })
+            .collect();
+
+        Mesh {
+            name,
+            triangles,
+            material,
+            bvh,
+            area_cdf,
+        }
+    }
+
+    /// Scales the mesh around the origin, then moves it. The
+    /// scale must be positive, as mirroring the vertices would
+    /// turn the faces and their normals inside out.
+    pub fn transformed(self, scale: f32, translate: Vec3<f32>) -> Mesh {
+        assert!(scale > 0.0, "meshes can't be scaled by {}", scale);
+        let mut triangles = self.triangles;
+        for triangle in triangles.iter_mut() {
+            for v in triangle.vertices.iter_mut() {
+                *v = *v * scale + translate;
+            }
+        }
+
+        Mesh::new(self.name, triangles, self.material)
+    }
+}
+
+impl Shape for Mesh {
+    fn intersects<'a>(&'a self, ray: &'a Ray) -> Option<RayHit<'a>> {
+        let mut nearest_hit: Option<RayHit<'a>> = None;
+
+        self.bvh.intersect(ray, f32::INFINITY, |i| {
+            let hit = self.triangles[i].intersects(ray)?;
+            match nearest_hit {
+                Some(nearest) if nearest.distance <= hit.distance => {}
+                _ => nearest_hit = Some(hit),
+            }
+            Some(hit.distance)
+        });
+
+        nearest_hit.map(|hit| RayHit {
+            object: self,
+            ..hit
+        })
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn position(&self) -> Vec3<f32> {
+        let sum = self
+            .triangles
+            .iter()
+            .fold(Vec3::zero(), |acc, t| acc + t.position());
+
+        sum / self.triangles.len().max(1) as f32
+    }
+
+    fn volume(&self) -> f32 {
+        0.0
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.triangles.iter().filter_map(|t| t.bounds()).fold(
+            None,
+            |acc: Option<Aabb>, b| match acc {
+                Some(acc) => Some(acc.union(&b)),
+                None => Some(b),
+            },
+        )
+    }
+
+    fn area(&self) -> f32 {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    fn sample_surface(&self, rng: &mut rand::rngs::ThreadRng) -> Option<SurfaceSample> {
+        if self.triangles.is_empty() {
+            return None;
+        }
+
+        let target = rng.gen_range(0f32, 1f32) * self.area();
+        let i = self.area_cdf.partition_point(|&sum| sum <= target);
+
+        self.triangles[i.min(self.triangles.len() - 1)].sample_surface(rng)
+    }
+}
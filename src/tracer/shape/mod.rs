@@ -1,13 +1,18 @@
 use vek::vec::Vec3;
 
+use super::bvh::Aabb;
 use super::material::Material;
 use super::ray::{Ray, RayHit};
 
+mod mesh;
 mod plane;
 mod sphere;
+mod triangle;
 
+pub use mesh::Mesh;
 pub use plane::Plane;
 pub use sphere::Sphere;
+pub use triangle::Triangle;
 
 //pub trait Shape {
 //fn intersects<'a>(&self, ray: Ray) -> Option<RayHit<'a>>;
@@ -23,13 +28,75 @@ pub use sphere::Sphere;
 //}
 
 pub trait Shape: Send + Sync {
-    fn intersects<'a>(&self, ray: &'a Ray) -> Option<RayHit<'a>>;
-    fn normal_at(&self, point: Vec3<f32>) -> Vec3<f32>;
-    fn material(&self) -> Material;
+    /// Finds the nearest hit in front of the ray, with all the
+    /// surface data the integrator needs at that point.
+    fn intersects<'a>(&'a self, ray: &'a Ray) -> Option<RayHit<'a>>;
+    fn material(&self) -> &Material;
     fn position(&self) -> Vec3<f32>;
+    #[allow(dead_code)]
     fn volume(&self) -> f32;
+
+    /// The bounding box of the shape, or `None` if it is unbounded.
+    fn bounds(&self) -> Option<Aabb>;
+
+    /// The area of the surface. Only shapes with an area
+    /// can be sampled, and so be used as lights.
+    fn area(&self) -> f32 {
+        0.0
+    }
+
+    /// Picks a point uniformly over the surface.
+    fn sample_surface(&self, _rng: &mut rand::rngs::ThreadRng) -> Option<SurfaceSample> {
+        None
+    }
+
+    /// Whether the shape is a participating medium, in which
+    /// rays scatter without crossing any surface. Media are
+    /// never hit by `intersects`, only by `sample_collision`.
+    fn is_medium(&self) -> bool {
+        false
+    }
+
+    /// Picks where the ray first collides with the particles
+    /// of a medium, or `None` if it gets through.
+    fn sample_collision<'a>(
+        &'a self,
+        _ray: &'a Ray,
+        _rng: &mut rand::rngs::ThreadRng,
+    ) -> Option<RayHit<'a>> {
+        None
+    }
+
+    /// The fraction of the light that gets through the shape
+    /// along the ray, up to `max_distance`, or `None` for
+    /// surfaces, which block it. It may be a random estimate.
+    fn transmittance(
+        &self,
+        _ray: &Ray,
+        _max_distance: f32,
+        _rng: &mut rand::rngs::ThreadRng,
+    ) -> Option<f32> {
+        None
+    }
+}
+
+/// A shape that can carry a volume. It must be convex, so
+/// that a ray crosses its inside in a single span, and
+/// know which points are inside of it, for rays that start
+/// there and never cross its surface.
+pub trait Carrier: Shape {
+    fn contains(&self, point: Vec3<f32>) -> bool;
 }
 
+/// A point on the surface of a shape.
+#[derive(Copy, Clone, Debug)]
+pub struct SurfaceSample {
+    pub point: Vec3<f32>,
+    /// The geometric normal, pointing out of the shape
+    pub normal: Vec3<f32>,
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum QuadraticResult {
     TwoReal(f32, f32),
@@ -49,8 +116,8 @@ pub fn solve(a: f32, b: f32, c: f32) -> QuadraticResult {
         QuadraticResult::OneReal(-b / (2f32 * a))
     } else {
         QuadraticResult::TwoReal(
-            ((-b + disc.sqrt()) / 2f32 * a),
-            ((-b - disc.sqrt()) / 2f32 * a),
+            (-b + disc.sqrt()) / (2f32 * a),
+            (-b - disc.sqrt()) / (2f32 * a),
         )
     }
 }
@@ -158,10 +225,3 @@ pub fn solve(a: f32, b: f32, c: f32) -> QuadraticResult {
 //}
 //}
 //}
-
-#[derive(Copy, Clone)]
-pub struct Triangle {
-    pub vertices: [vek::Vec3<f32>; 3],
-    pub normals: Option<[vek::Vec3<f32>; 3]>,
-    pub tex_coords: Option<[vek::Vec2<f32>; 3]>,
-}
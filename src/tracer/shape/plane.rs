@@ -1,11 +1,12 @@
-use vek::vec::Vec3;
+use vek::vec::{Vec2, Vec3};
 
+use crate::tracer::bvh::Aabb;
 use crate::tracer::material::Material;
-use crate::tracer::ray::{Ray, RayHit};
+use crate::tracer::ray::{orthonormal_basis, Ray, RayHit};
 
-use super::Shape;
+use super::{Carrier, Shape};
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Plane {
     pub point: Vec3<f32>,
     pub normal: Vec3<f32>,
@@ -23,7 +24,7 @@ impl Plane {
 }
 
 impl Shape for Plane {
-    fn intersects<'a>(&self, ray: &'a Ray) -> Option<RayHit<'a>> {
+    fn intersects<'a>(&'a self, ray: &'a Ray) -> Option<RayHit<'a>> {
         let nr = self.normal.dot(ray.direction);
         let u = ray.origin - self.point;
         let t = -((u.dot(self.normal)) / nr);
@@ -31,20 +32,18 @@ impl Shape for Plane {
         if nr == 0f32 || t <= 0f32 {
             None
         } else {
-            Some(RayHit {
-                ray: &ray,
-                distance: t,
-                point: ray.origin + t * ray.direction,
-            })
-        }
-    }
+            // Texture coordinates are distances along the
+            // tangents of the shading frame
+            let (tangent, bitangent) = orthonormal_basis(self.normal);
+            let offset = ray.origin + t * ray.direction - self.point;
+            let uv = Vec2::new(offset.dot(tangent), offset.dot(bitangent));
 
-    fn normal_at(&self, point: Vec3<f32>) -> Vec3<f32> {
-        self.normal
+            Some(RayHit::new(ray, t, self, &self.material, self.normal, uv))
+        }
     }
 
-    fn material(&self) -> Material {
-        self.material
+    fn material(&self) -> &Material {
+        &self.material
     }
 
     fn position(&self) -> Vec3<f32> {
@@ -54,4 +53,15 @@ impl Shape for Plane {
     fn volume(&self) -> f32 {
         0.0
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+/// Everything behind the plane is inside of it.
+impl Carrier for Plane {
+    fn contains(&self, point: Vec3<f32>) -> bool {
+        (point - self.point).dot(self.normal) < 0.0
+    }
 }
@@ -1,11 +1,15 @@
-use vek::vec::Vec3;
+use std::f32::consts::PI;
 
+use vek::vec::{Vec2, Vec3};
+
+use crate::tracer::bvh::Aabb;
 use crate::tracer::material::Material;
 use crate::tracer::ray::{Ray, RayHit};
+use crate::tracer::sampling::{random_square, uniform_sphere};
 
-use super::Shape;
+use super::{Carrier, Shape, SurfaceSample};
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Sphere {
     pub center: Vec3<f32>,
     pub radius: f32,
@@ -23,7 +27,7 @@ impl Sphere {
 }
 
 impl Shape for Sphere {
-    fn intersects<'a>(&self, ray: &'a Ray) -> Option<RayHit<'a>> {
+    fn intersects<'a>(&'a self, ray: &'a Ray) -> Option<RayHit<'a>> {
         let oc = ray.origin - self.center;
         let a = ray.direction.dot(ray.direction);
         let b = 2.0 * oc.dot(ray.direction);
@@ -33,28 +37,43 @@ impl Shape for Sphere {
 
         use super::QuadraticResult;
 
-        match roots {
-            QuadraticResult::OneReal(x) | QuadraticResult::TwoReal(_, x) => {
-                if x < 0f32 {
-                    None
+        // The nearest root in front of the ray, which is the
+        // far one when the ray starts inside the sphere
+        let x = match roots {
+            QuadraticResult::OneReal(x) => x,
+            QuadraticResult::TwoReal(far, near) => {
+                if near > 0f32 {
+                    near
                 } else {
-                    Some(RayHit {
-                        ray: &ray,
-                        distance: x,
-                        point: ray.origin + x * ray.direction,
-                    })
+                    far
                 }
             }
-            _ => None,
+            _ => return None,
+        };
+
+        if x <= 0f32 {
+            return None;
         }
-    }
 
-    fn normal_at(&self, point: Vec3<f32>) -> Vec3<f32> {
-        (point - self.center).normalized()
+        let point = ray.origin + x * ray.direction;
+        let normal = (point - self.center).normalized();
+
+        // Longitude and latitude, with `u` going around the
+        // y axis and `v` from the bottom to the top
+        let uv = Vec2::new(
+            0.5 - normal.z.atan2(normal.x) / (2.0 * PI),
+            0.5 + normal.y.clamp(-1.0, 1.0).asin() / PI,
+        );
+        let tangent = Vec3::new(normal.z, 0.0, -normal.x);
+
+        Some(
+            RayHit::new(ray, x, self, &self.material, normal, uv)
+                .with_shading_frame(normal, Some(tangent)),
+        )
     }
 
-    fn material(&self) -> Material {
-        self.material
+    fn material(&self) -> &Material {
+        &self.material
     }
 
     fn position(&self) -> Vec3<f32> {
@@ -62,6 +81,30 @@ impl Shape for Sphere {
     }
 
     fn volume(&self) -> f32 {
-        (4.0/3.0) * std::f32::consts::PI * self.radius.powi(3)
+        (4.0 / 3.0) * PI * self.radius.powi(3)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vec3::broadcast(self.radius.abs());
+        Some(Aabb::new(self.center - r, self.center + r))
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, rng: &mut rand::rngs::ThreadRng) -> Option<SurfaceSample> {
+        let normal = uniform_sphere(random_square(rng));
+
+        Some(SurfaceSample {
+            point: self.center + normal * self.radius,
+            normal,
+        })
+    }
+}
+
+impl Carrier for Sphere {
+    fn contains(&self, point: Vec3<f32>) -> bool {
+        (point - self.center).magnitude_squared() < self.radius * self.radius
     }
 }
@@ -1,35 +1,216 @@
-//let edge1 = triangle.vertices[1] - triangle.vertices[0];
-//let edge2 = triangle.vertices[2] - triangle.vertices[0];
-//let h = ray.direction.cross(edge2);
-//let a = edge1.dot(h);
-
-//if a.abs() < 0.0001 {
-    //return None;
-//}
-
-//let f = 1.0/a;
-//let s = ray.origin - triangle.vertices[0];
-//let u = f * s.dot(h);
-
-//if u < 0.0 || u > 1.0 {
-    //return None;
-//}
-
-//let q = s.cross(edge1);
-//let v = f * ray.direction.dot(q);
-
-//if v < 0.0 || u + v > 1.0 {
-    //return None;
-//}
-
-//let t = f * edge2.dot(q);
-
-//if t > 0.0001 {
-    //Some(RayHit {
-        //ray: &ray,
-        //distance: t,
-        //object: *self,
-    //})
-//} else {
-    //None
-//}
+use std::sync::Arc;
+
+use vek::vec::{Vec2, Vec3};
+
+use crate::tracer::bvh::Aabb;
+use crate::tracer::material::Material;
+use crate::tracer::ray::{Ray, RayHit};
+use crate::tracer::sampling::{random_square, uniform_triangle};
+
+use super::{Shape, SurfaceSample};
+
+/// Rays making a smaller sine than this with the plane of the
+/// triangle are considered parallel to it, and miss.
+const PARALLEL_SINE: f32 = 1e-7;
+
+/// Hits closer than this to the origin of the ray are ignored.
+const MIN_DISTANCE: f32 = 0.0001;
+
+/// How much the bounds are grown on every side, so that
+/// axis-aligned triangles don't get flat boxes.
+const BOUNDS_PAD: f32 = 0.0001;
+
+/// A triangle with optional per-vertex normals and texture
+/// coordinates. The front face is the one from which the
+/// vertices are seen in counterclockwise order.
+///
+/// Without texture coordinates, the hits get the barycentric
+/// weights of the second and third vertices as their UVs.
+///
+/// The material is shared, as meshes have many triangles.
+#[derive(Clone)]
+pub struct Triangle {
+    pub vertices: [Vec3<f32>; 3],
+    pub normals: Option<[Vec3<f32>; 3]>,
+    pub tex_coords: Option<[Vec2<f32>; 3]>,
+    pub material: Arc<Material>,
+}
+
+impl Triangle {
+    pub fn new(vertices: [Vec3<f32>; 3], material: Arc<Material>) -> Triangle {
+        Triangle {
+            vertices,
+            normals: None,
+            tex_coords: None,
+            material,
+        }
+    }
+
+    /// The normal of the plane of the triangle.
+    pub fn face_normal(&self) -> Vec3<f32> {
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+
+        edge1.cross(edge2).normalized()
+    }
+
+    /// The direction of increasing `u` on the plane
+    /// of the triangle, if it has texture coordinates.
+    fn tangent(&self) -> Option<Vec3<f32>> {
+        let uvs = self.tex_coords?;
+
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let duv1 = uvs[1] - uvs[0];
+        let duv2 = uvs[2] - uvs[0];
+
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        Some((edge1 * duv2.y - edge2 * duv1.y) / det)
+    }
+}
+
+impl Shape for Triangle {
+    fn intersects<'a>(&'a self, ray: &'a Ray) -> Option<RayHit<'a>> {
+        // Möller–Trumbore
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+        let h = ray.direction.cross(edge2);
+        let a = edge1.dot(h);
+
+        // The determinant grows with the area of the triangle,
+        // so it is compared relative to the edges
+        if a.abs() <= PARALLEL_SINE * edge1.magnitude() * edge2.magnitude() {
+            return None;
+        }
+
+        let f = 1.0 / a;
+        let s = ray.origin - self.vertices[0];
+        let u = f * s.dot(h);
+
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let v = f * ray.direction.dot(q);
+
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = f * edge2.dot(q);
+
+        if t <= MIN_DISTANCE {
+            return None;
+        }
+
+        // Barycentric weights of the three vertices
+        let b = Vec3::new(1.0 - u - v, u, v);
+
+        let uv = match self.tex_coords {
+            Some(uvs) => uvs[0] * b.x + uvs[1] * b.y + uvs[2] * b.z,
+            None => Vec2::new(u, v),
+        };
+
+        let hit = RayHit::new(ray, t, self, &self.material, self.face_normal(), uv);
+
+        let normal = match self.normals {
+            Some(normals) => (normals[0] * b.x + normals[1] * b.y + normals[2] * b.z).normalized(),
+            None => hit.geometric_normal,
+        };
+
+        Some(hit.with_shading_frame(normal, self.tangent()))
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn position(&self) -> Vec3<f32> {
+        (self.vertices[0] + self.vertices[1] + self.vertices[2]) / 3.0
+    }
+
+    fn volume(&self) -> f32 {
+        0.0
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let b = Aabb::from_points(&self.vertices);
+        let pad = Vec3::broadcast(BOUNDS_PAD);
+        Some(Aabb::new(b.min - pad, b.max + pad))
+    }
+
+    fn area(&self) -> f32 {
+        let edge1 = self.vertices[1] - self.vertices[0];
+        let edge2 = self.vertices[2] - self.vertices[0];
+
+        0.5 * edge1.cross(edge2).magnitude()
+    }
+
+    fn sample_surface(&self, rng: &mut rand::rngs::ThreadRng) -> Option<SurfaceSample> {
+        let b = uniform_triangle(random_square(rng));
+
+        let point = self.vertices[0]
+            + (self.vertices[1] - self.vertices[0]) * b.x
+            + (self.vertices[2] - self.vertices[0]) * b.y;
+
+        Some(SurfaceSample {
+            point,
+            normal: self.face_normal(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ray(origin: Vec3<f32>, direction: Vec3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalized(),
+        }
+    }
+
+    #[test]
+    fn hits_tiny_triangles() {
+        let size = 1e-3;
+        let triangle = Triangle::new(
+            [
+                Vec3::zero(),
+                Vec3::new(size, 0.0, 0.0),
+                Vec3::new(0.0, size, 0.0),
+            ],
+            Arc::new(Material::default()),
+        );
+
+        let down = ray(Vec3::new(0.2 * size, 0.3 * size, 1.0), -Vec3::unit_z());
+        let hit = triangle.intersects(&down).unwrap();
+        assert!((hit.distance - 1.0).abs() < 1e-6);
+        assert!((hit.uv - Vec2::new(0.2, 0.3)).magnitude() < 1e-3);
+
+        let origin = Vec3::new(1.0, 1.0, 1.0);
+        let slanted = ray(origin, Vec3::new(0.25 * size, 0.25 * size, 0.0) - origin);
+        assert!(triangle.intersects(&slanted).is_some());
+
+        let outside = Vec3::new(0.6 * size, 0.6 * size, 1.0);
+        assert!(triangle
+            .intersects(&ray(outside, -Vec3::unit_z()))
+            .is_none());
+    }
+
+    #[test]
+    fn misses_parallel_rays() {
+        let triangle = Triangle::new(
+            [Vec3::zero(), Vec3::unit_x(), Vec3::unit_y()],
+            Arc::new(Material::default()),
+        );
+
+        let origin = Vec3::new(-1.0, 0.25, 0.0);
+        assert!(triangle.intersects(&ray(origin, Vec3::unit_x())).is_none());
+    }
+}
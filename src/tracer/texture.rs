@@ -0,0 +1,432 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
+
+use crate::exr;
+use crate::tonemap::srgb_eotf;
+
+/// A value that varies over a surface, looked up by the
+/// texture coordinates of a point or by its position.
+pub trait Texture: Send + Sync {
+    fn color(&self, uv: Vec2<f32>, point: Vec3<f32>) -> Rgb<f32>;
+
+    /// The texture as a single number, for parameters
+    /// that aren't colors: the average of its channels.
+    fn value(&self, uv: Vec2<f32>, point: Vec3<f32>) -> f32 {
+        let c = self.color(uv, point);
+        (c.r + c.g + c.b) / 3.0
+    }
+}
+
+/// The same color everywhere.
+pub struct ConstantTexture(pub Rgb<f32>);
+
+impl Texture for ConstantTexture {
+    fn color(&self, _uv: Vec2<f32>, _point: Vec3<f32>) -> Rgb<f32> {
+        self.0
+    }
+}
+
+/// What happens to texture coordinates outside of [0, 1).
+#[derive(Copy, Clone, Debug)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl Wrap {
+    pub fn from_name(name: &str) -> Option<Wrap> {
+        match name {
+            "repeat" => Some(Wrap::Repeat),
+            "clamp" => Some(Wrap::Clamp),
+            "mirror" => Some(Wrap::Mirror),
+            _ => None,
+        }
+    }
+
+    /// Brings a pixel index into [0, size).
+    fn apply(self, i: i64, size: usize) -> usize {
+        let n = size as i64;
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.clamp(0, n - 1),
+            Wrap::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {
+                    i
+                } else {
+                    2 * n - 1 - i
+                }
+            }
+        };
+
+        i as usize
+    }
+}
+
+/// An image stretched over the [0, 1] square of texture
+/// coordinates, with `v` going up, and filtered bilinearly.
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb<f32>>,
+    wrap: Wrap,
+    /// How many times the image repeats along `u` and `v`
+    scale: Vec2<f32>,
+}
+
+impl ImageTexture {
+    /// Loads an image, see `load_image`.
+    pub fn load(
+        path: &str,
+        srgb: bool,
+        wrap: Wrap,
+        scale: Vec2<f32>,
+    ) -> Result<ImageTexture, String> {
+        let (width, height, pixels) = load_image(path, srgb)?;
+
+        Ok(ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap,
+            scale,
+        })
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Rgb<f32> {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn color(&self, uv: Vec2<f32>, _point: Vec3<f32>) -> Rgb<f32> {
+        let uv = uv * self.scale;
+
+        // Pixel centers are at half integers
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+/// Alternates between two textures on a grid of squares
+/// over the texture coordinates.
+pub struct CheckerTexture {
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+    /// The number of squares along `u` and `v`
+    pub scale: Vec2<f32>,
+}
+
+impl Texture for CheckerTexture {
+    fn color(&self, uv: Vec2<f32>, point: Vec3<f32>) -> Rgb<f32> {
+        let cell = (uv * self.scale).map(|c| c.floor() as i64);
+
+        if (cell.x + cell.y).rem_euclid(2) == 0 {
+            self.even.color(uv, point)
+        } else {
+            self.odd.color(uv, point)
+        }
+    }
+}
+
+/// Blends between two textures along fractal Perlin noise,
+/// a solid texture that follows the position of the point
+/// rather than its texture coordinates.
+pub struct NoiseTexture {
+    pub low: Arc<dyn Texture>,
+    pub high: Arc<dyn Texture>,
+    /// The frequency of the first octave, per unit of distance
+    pub scale: f32,
+    /// The number of layers of noise, each twice as fine and
+    /// half as strong as the previous one
+    pub octaves: usize,
+    permutation: [u8; 512],
+}
+
+impl NoiseTexture {
+    pub fn new(
+        low: Arc<dyn Texture>,
+        high: Arc<dyn Texture>,
+        scale: f32,
+        octaves: usize,
+    ) -> NoiseTexture {
+        // Shuffled the same way every time, so renders are repeatable
+        let mut table: Vec<u8> = (0..=255).collect();
+        table.shuffle(&mut StdRng::seed_from_u64(0x5eed));
+
+        let mut permutation = [0u8; 512];
+        for (i, p) in permutation.iter_mut().enumerate() {
+            *p = table[i % 256];
+        }
+
+        NoiseTexture {
+            low,
+            high,
+            scale,
+            octaves: octaves.max(1),
+            permutation,
+        }
+    }
+
+    /// Improved Perlin noise (Perlin, 2002), roughly in [-1, 1].
+    fn perlin(&self, p: Vec3<f32>) -> f32 {
+        let cell = p.map(|c| c.floor());
+        let f = p - cell;
+        let i = cell.map(|c| (c as i64).rem_euclid(256) as usize);
+
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+        let perm = |i: usize| self.permutation[i] as usize;
+        let grad = |hash: usize, x: f32, y: f32, z: f32| {
+            // The dot product with one of 12 edge directions of a cube
+            let h = hash & 15;
+            let a = if h < 8 { x } else { y };
+            let b = if h < 4 {
+                y
+            } else if h == 12 || h == 14 {
+                x
+            } else {
+                z
+            };
+            (if h & 1 == 0 { a } else { -a }) + (if h & 2 == 0 { b } else { -b })
+        };
+
+        let a = perm(i.x) + i.y;
+        let (aa, ab) = (perm(a) + i.z, perm(a + 1) + i.z);
+        let b = perm(i.x + 1) + i.y;
+        let (ba, bb) = (perm(b) + i.z, perm(b + 1) + i.z);
+
+        let (x, y, z) = (f.x, f.y, f.z);
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, grad(perm(aa), x, y, z), grad(perm(ba), x - 1.0, y, z)),
+                lerp(
+                    u,
+                    grad(perm(ab), x, y - 1.0, z),
+                    grad(perm(bb), x - 1.0, y - 1.0, z),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    grad(perm(aa + 1), x, y, z - 1.0),
+                    grad(perm(ba + 1), x - 1.0, y, z - 1.0),
+                ),
+                lerp(
+                    u,
+                    grad(perm(ab + 1), x, y - 1.0, z - 1.0),
+                    grad(perm(bb + 1), x - 1.0, y - 1.0, z - 1.0),
+                ),
+            ),
+        )
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn color(&self, uv: Vec2<f32>, point: Vec3<f32>) -> Rgb<f32> {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut p = point * self.scale;
+
+        for _ in 0..self.octaves {
+            sum += amplitude * self.perlin(p);
+            total += amplitude;
+            amplitude *= 0.5;
+            p *= 2.0;
+        }
+
+        let t = (0.5 + 0.5 * sum / total).clamp(0.0, 1.0);
+        self.low.color(uv, point) * (1.0 - t) + self.high.color(uv, point) * t
+    }
+}
+
+/// Loads an image as linear colors, its rows from the top.
+///
+/// `.hdr` and `.exr` files are read as is. Other formats are
+/// decoded by the `image` crate, and taken as sRGB encoded if
+/// `srgb` is set, which is right for colors but not for data
+/// like roughness.
+pub fn load_image(path: &str, srgb: bool) -> Result<(usize, usize, Vec<Rgb<f32>>), String> {
+    let open_err = |e: std::io::Error| format!("Couldnt open {}: {}", path, e);
+    let read_err = |e: String| format!("Couldnt read {}: {}", path, e);
+
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+
+    let (width, height, pixels) = match extension.as_deref() {
+        Some("hdr") => {
+            let file = BufReader::new(File::open(path).map_err(open_err)?);
+            let decoder = image::hdr::HDRDecoder::new(file).map_err(|e| read_err(e.to_string()))?;
+            let meta = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()
+                .map_err(|e| read_err(e.to_string()))?
+                .into_iter()
+                .map(|p| Rgb::new(p.data[0], p.data[1], p.data[2]))
+                .collect();
+
+            (meta.width as usize, meta.height as usize, pixels)
+        }
+        Some("exr") => {
+            let mut file = BufReader::new(File::open(path).map_err(open_err)?);
+            exr::read(&mut file).map_err(read_err)?
+        }
+        _ => {
+            let img = image::open(path)
+                .map_err(|e| read_err(e.to_string()))?
+                .to_rgb();
+            let decode = |c: u8| {
+                let c = f32::from(c) / 255.0;
+                if srgb {
+                    srgb_eotf(c)
+                } else {
+                    c
+                }
+            };
+            let pixels = img
+                .pixels()
+                .map(|p| Rgb::new(decode(p.data[0]), decode(p.data[1]), decode(p.data[2])))
+                .collect();
+
+            (img.width() as usize, img.height() as usize, pixels)
+        }
+    };
+
+    if width == 0 || height == 0 {
+        return Err(read_err("the image is empty".to_string()));
+    }
+
+    Ok((width, height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x2 image whose pixels hold their own coordinates, in
+    /// red and green.
+    fn image(wrap: Wrap) -> ImageTexture {
+        let (width, height) = (4, 2);
+        ImageTexture {
+            width,
+            height,
+            pixels: (0..width * height)
+                .map(|i| Rgb::new((i % width) as f32, (i / width) as f32, 0.0))
+                .collect(),
+            wrap,
+            scale: Vec2::one(),
+        }
+    }
+
+    fn close(a: Rgb<f32>, b: Rgb<f32>) -> bool {
+        (a - b).map(f32::abs).reduce_partial_max() < 1e-5
+    }
+
+    #[test]
+    fn wraps_indices() {
+        let wrapped = |wrap: Wrap| -> Vec<usize> { (-5..=8).map(|i| wrap.apply(i, 4)).collect() };
+
+        assert_eq!(
+            wrapped(Wrap::Repeat),
+            [3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0]
+        );
+        assert_eq!(
+            wrapped(Wrap::Clamp),
+            [0, 0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3, 3]
+        );
+        assert_eq!(
+            wrapped(Wrap::Mirror),
+            [3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0]
+        );
+
+        // A single pixel is all there is to wrap to
+        for &wrap in &[Wrap::Repeat, Wrap::Clamp, Wrap::Mirror] {
+            assert!((-3..3).all(|i| wrap.apply(i, 1) == 0));
+        }
+    }
+
+    #[test]
+    fn pixel_centers_give_their_pixel() {
+        let texture = image(Wrap::Clamp);
+
+        for y in 0..2 {
+            for x in 0..4 {
+                // `v` goes up while the rows go down
+                let uv = Vec2::new((x as f32 + 0.5) / 4.0, 1.0 - (y as f32 + 0.5) / 2.0);
+                let color = texture.color(uv, Vec3::zero());
+                assert!(
+                    close(color, Rgb::new(x as f32, y as f32, 0.0)),
+                    "{:?} at pixel ({}, {})",
+                    color,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn interpolates_between_pixels() {
+        let texture = image(Wrap::Clamp);
+
+        // Halfway between the centers of pixels (1, 0), (2, 0),
+        // (1, 1) and (2, 1), and a quarter of the way
+        let color = texture.color(Vec2::new(0.5, 0.5), Vec3::zero());
+        assert!(close(color, Rgb::new(1.5, 0.5, 0.0)), "{:?}", color);
+        let color = texture.color(Vec2::new(0.375 + 0.0625, 0.75 - 0.125), Vec3::zero());
+        assert!(close(color, Rgb::new(1.25, 0.25, 0.0)), "{:?}", color);
+
+        // Past the edge, clamping holds the last pixels while
+        // repeating blends towards the other side
+        let color = image(Wrap::Clamp).color(Vec2::new(1.0, 0.75), Vec3::zero());
+        assert!(close(color, Rgb::new(3.0, 0.0, 0.0)), "{:?}", color);
+        let color = image(Wrap::Repeat).color(Vec2::new(1.0, 0.75), Vec3::zero());
+        assert!(close(color, Rgb::new(1.5, 0.0, 0.0)), "{:?}", color);
+        let color = image(Wrap::Mirror).color(Vec2::new(-0.125, 0.75), Vec3::zero());
+        assert!(close(color, Rgb::new(0.0, 0.0, 0.0)), "{:?}", color);
+    }
+
+    #[test]
+    fn checker_alternates_across_zero() {
+        let checker = CheckerTexture {
+            even: Arc::new(ConstantTexture(Rgb::one())),
+            odd: Arc::new(ConstantTexture(Rgb::zero())),
+            scale: Vec2::broadcast(2.0),
+        };
+        let at = |u: f32, v: f32| checker.color(Vec2::new(u, v), Vec3::zero()).r;
+
+        assert_eq!(at(0.25, 0.25), 1.0);
+        assert_eq!(at(0.75, 0.25), 0.0);
+        assert_eq!(at(-0.25, 0.25), 0.0);
+        assert_eq!(at(-0.25, -0.25), 1.0);
+        assert_eq!(at(-0.75, -0.25), 0.0);
+        assert_eq!(at(0.25, -0.75), 1.0);
+    }
+}
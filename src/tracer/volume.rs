@@ -1,63 +1,627 @@
-use crate::tracer::Shape;
-use crate::tracer::material::{ Material, BRDF };
-use crate::tracer::ray::{Ray, RayHit};
+use std::f32::consts::PI;
+use std::sync::Arc;
 
-use rand_distr::{ Poisson, UnitSphere, Distribution };
-use vek::vec::{ Vec3, Rgb };
+use rand::{Rng, RngCore};
+use vek::rgb::Rgb;
+use vek::vec::{Vec2, Vec3};
 
-pub struct Volume<S: Shape> {
+use crate::tracer::bsdf::{Bsdf, BsdfSample};
+use crate::tracer::bvh::Aabb;
+use crate::tracer::grid::Grid;
+use crate::tracer::material::Material;
+use crate::tracer::ray::{Frame, Ray, RayHit};
+use crate::tracer::shape::{Carrier, Shape};
+use crate::tracer::texture::ConstantTexture;
+
+/// A participating medium: particles that absorb and
+/// scatter the light going through them. Where they are
+/// is described by the extinction coefficient, the sum of
+/// the absorption and scattering ones.
+pub trait Medium: Send + Sync {
+    /// Picks where a ray going from `t_min` to `t_max` along
+    /// it first hits a particle, or `None` if it gets through.
+    ///
+    /// Collisions are sampled proportionally to the extinction
+    /// times the transmittance, so the weight of a collision is
+    /// the albedo of the medium, and getting through has a
+    /// weight of one.
+    fn sample_distance(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        rng: &mut dyn RngCore,
+    ) -> Option<f32>;
+
+    /// The fraction of the light that gets from `t_min` to
+    /// `t_max` along the ray. It may be a random estimate.
+    fn transmittance(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> f32;
+}
+
+/// A distance to the next collision with a medium whose
+/// extinction is `majorant` everywhere.
+fn free_flight(rng: &mut dyn RngCore, majorant: f32) -> f32 {
+    -(1.0 - rng.gen_range(0f32, 1f32)).ln() / majorant
+}
+
+/// A medium with the same extinction everywhere.
+pub struct HomogeneousMedium {
+    pub extinction: f32,
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample_distance(
+        &self,
+        _ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        rng: &mut dyn RngCore,
+    ) -> Option<f32> {
+        if self.extinction <= 0.0 {
+            return None;
+        }
+
+        let t = t_min + free_flight(rng, self.extinction);
+        if t < t_max {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    fn transmittance(&self, _ray: &Ray, t_min: f32, t_max: f32, _rng: &mut dyn RngCore) -> f32 {
+        if self.extinction <= 0.0 {
+            1.0
+        } else {
+            (-self.extinction * (t_max - t_min)).exp()
+        }
+    }
+}
+
+/// A medium that thins out exponentially with the height,
+/// like the air and the mist over the ground. Its extinction
+/// is `extinction` at `height`, and drops by a factor of e
+/// every `falloff` above it.
+///
+/// The density along a ray is itself an exponential, so its
+/// integral can be inverted to sample collisions exactly.
+pub struct ExponentialMedium {
+    pub extinction: f32,
+    pub height: f32,
+    pub falloff: f32,
+}
+
+impl ExponentialMedium {
+    /// The extinction at the origin of the ray, and how fast
+    /// it decays along it: the extinction at `t` is `a e^(-k t)`.
+    fn decay(&self, ray: &Ray) -> (f32, f32) {
+        let a = self.extinction * (-(ray.origin.y - self.height) / self.falloff).exp();
+        let k = ray.direction.y / self.falloff;
+        (a, k)
+    }
+
+    /// The integral of the extinction from `t_min` to `t`.
+    fn optical_depth(&self, ray: &Ray, t_min: f32, t: f32) -> f32 {
+        let (a, k) = self.decay(ray);
+        if k.abs() < 1e-6 {
+            a * (t - t_min)
+        } else {
+            a / k * ((-k * t_min).exp() - (-k * t).exp())
+        }
+    }
+}
+
+impl Medium for ExponentialMedium {
+    fn sample_distance(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        rng: &mut dyn RngCore,
+    ) -> Option<f32> {
+        let (a, k) = self.decay(ray);
+        if a <= 0.0 {
+            return None;
+        }
+
+        // Solves `optical_depth(t) = depth` for `t`
+        let depth = -(1.0 - rng.gen_range(0f32, 1f32)).ln();
+        let t = if k.abs() < 1e-6 {
+            t_min + depth / a
+        } else {
+            // Going up, the medium can thin out before the
+            // depth is reached
+            let e = (-k * t_min).exp() - depth * k / a;
+            if e <= 0.0 {
+                return None;
+            }
+            -e.ln() / k
+        };
+
+        if t < t_max {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, t_min: f32, t_max: f32, _rng: &mut dyn RngCore) -> f32 {
+        (-self.optical_depth(ray, t_min, t_max)).exp()
+    }
+}
+
+/// A medium whose density varies over a grid, scaled by the
+/// extinction where the density is 1. Outside of the grid,
+/// there is none of it.
+///
+/// Collisions are found with delta tracking and the
+/// transmittance is estimated with ratio tracking (Novák et
+/// al., 2014), both against the largest extinction of each
+/// block of the grid the ray crosses: tentative collisions
+/// are sampled as if the medium was that dense all over the
+/// block, and the difference is made up by null collisions
+/// that leave the ray unchanged. Empty blocks are skipped.
+pub struct GridMedium {
+    pub density: Grid,
+    pub extinction: f32,
+}
+
+impl GridMedium {
+    fn extinction_at(&self, point: Vec3<f32>) -> f32 {
+        self.density.lookup(point) * self.extinction
+    }
+}
+
+impl Medium for GridMedium {
+    fn sample_distance(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        rng: &mut dyn RngCore,
+    ) -> Option<f32> {
+        for (mut t, end, majorant) in self.density.segments(ray, t_min, t_max) {
+            let majorant = majorant * self.extinction;
+            if majorant <= 0.0 {
+                continue;
+            }
+
+            // Free flights are memoryless, so the one that
+            // overshoots the block can start over at its end
+            loop {
+                t += free_flight(rng, majorant);
+                if t >= end {
+                    break;
+                }
+
+                let extinction = self.extinction_at(ray.origin + ray.direction * t);
+                if rng.gen_range(0f32, 1f32) * majorant < extinction {
+                    return Some(t);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn transmittance(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut dyn RngCore) -> f32 {
+        let mut transmittance = 1.0;
+
+        for (mut t, end, majorant) in self.density.segments(ray, t_min, t_max) {
+            let majorant = majorant * self.extinction;
+            if majorant <= 0.0 {
+                continue;
+            }
+
+            loop {
+                t += free_flight(rng, majorant);
+                if t >= end {
+                    break;
+                }
+
+                let extinction = self.extinction_at(ray.origin + ray.direction * t);
+                transmittance *= 1.0 - extinction / majorant;
+            }
+        }
+
+        transmittance
+    }
+}
+
+/// The Henyey-Greenstein phase function, the distribution
+/// of the directions in which particles scatter light.
+/// Its anisotropy `g` goes from -1, where light is sent
+/// back, to 1, where it keeps going; 0 is isotropic.
+///
+/// It is used as the BSDF of the collisions with a medium,
+/// in a frame whose normal points back along the ray. The
+/// integrator weighs BSDFs by the cosine with the normal,
+/// which phase functions don't have, so it is divided out.
+#[derive(Copy, Clone, Debug)]
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    /// The density of scattering by an angle of the given
+    /// cosine, per unit solid angle.
+    fn p(&self, cos_theta: f32) -> f32 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.max(f32::MIN_POSITIVE).sqrt())
+    }
+}
+
+impl Bsdf for HenyeyGreenstein {
+    fn eval(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> Rgb<f32> {
+        if wi.z == 0.0 {
+            return Rgb::zero();
+        }
+
+        // The light keeps going when `wi` is opposite to `wo`
+        Rgb::broadcast(self.p(-wo.dot(wi)) / wi.z.abs())
+    }
+
+    fn sample(&self, wo: Vec3<f32>, u: Vec2<f32>) -> Option<BsdfSample> {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u.x
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.x);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        }
+        .clamp(-1.0, 1.0);
+
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let local = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let wi = Frame::new(-wo).to_world(local);
+
+        let pdf = self.p(cos_theta);
+        if wi.z == 0.0 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi,
+            f: Rgb::broadcast(pdf / wi.z.abs()),
+            pdf,
+            specular: false,
+        })
+    }
+
+    fn pdf(&self, wo: Vec3<f32>, wi: Vec3<f32>) -> f32 {
+        self.p(-wo.dot(wi))
+    }
+}
+
+/// The material of the collisions with a medium.
+fn medium_material(albedo: Rgb<f32>, anisotropy: f32) -> Material {
+    Material {
+        albedo: Arc::new(ConstantTexture(albedo)),
+        emittance: Rgb::zero(),
+        bsdf: Arc::new(HenyeyGreenstein { g: anisotropy }),
+        normal_map: None,
+    }
+}
+
+/// A medium filling the inside of a carrier shape. The
+/// carrier itself is invisible: rays go through it unless
+/// they collide with the medium, where they are scattered
+/// with the albedo of the medium as the material albedo.
+///
+/// The carrier must be convex, see `Carrier`: rays only
+/// look for the medium between where they enter and first
+/// leave it.
+pub struct Volume<S: Carrier> {
     carrier: S,
-    density: f32,
-    dist: Poisson<f32>,
+    medium: Box<dyn Medium>,
+    material: Material,
 }
 
-impl<S: Shape> Volume<S> {
-    pub fn new(carrier: S, density: f32) -> Volume<S> {
-        let dist = Poisson::new(density).unwrap();
+impl<S: Carrier> Volume<S> {
+    /// The albedo is the fraction of the extinction that is
+    /// scattering, and `anisotropy` the `g` of the phase function.
+    pub fn new(
+        carrier: S,
+        medium: Box<dyn Medium>,
+        albedo: Rgb<f32>,
+        anisotropy: f32,
+    ) -> Volume<S> {
         Volume {
             carrier,
-            density,
-            dist,
+            medium,
+            material: medium_material(albedo, anisotropy),
+        }
+    }
+
+    /// Where the ray enters and leaves the carrier, which
+    /// can be behind its origin if it starts inside.
+    fn extent(&self, ray: &Ray) -> Option<(f32, f32)> {
+        match self.carrier.intersects(ray) {
+            Some(hit) if !hit.front_face => Some((0.0, hit.distance)),
+            Some(hit) => {
+                let inside = hit.spawn(ray.direction);
+                let exit = match self.carrier.intersects(&inside) {
+                    Some(exit) => hit.distance + exit.distance,
+                    None => f32::INFINITY,
+                };
+                Some((hit.distance, exit))
+            }
+            None if self.carrier.contains(ray.origin) => Some((0.0, f32::INFINITY)),
+            None => None,
         }
     }
 }
 
-impl<S: Shape> Shape for Volume<S> {
-    fn intersects<'a>(&self, ray: &'a Ray) -> Option<RayHit<'a>> {
-        let mut carrier_hit = self.carrier.intersects(ray)?;
+impl<S: Carrier> Shape for Volume<S> {
+    /// The carrier is invisible, see `sample_collision`.
+    fn intersects<'a>(&'a self, _ray: &'a Ray) -> Option<RayHit<'a>> {
+        None
+    }
 
-        let op = self.carrier.position() - carrier_hit.point;
+    fn sample_collision<'a>(
+        &'a self,
+        ray: &'a Ray,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Option<RayHit<'a>> {
+        let (t_min, t_max) = self.extent(ray)?;
+        let t = self.medium.sample_distance(ray, t_min, t_max, rng)?;
 
-        let ray_volume = std::f32::consts::TAU * 0.0001 * (2.0 * op.magnitude());
-        let n_particles = self.dist.sample(&mut rand::thread_rng());
+        // There is no surface, so the frame faces the ray
+        Some(RayHit::new(
+            ray,
+            t,
+            self,
+            &self.material,
+            -ray.direction,
+            Vec2::zero(),
+        ))
+    }
 
-        // wat
-        let k = ( n_particles * ray_volume ) / self.density;
+    fn material(&self) -> &Material {
+        &self.material
+    }
 
-        carrier_hit.point += 2.0 * k * op;
+    fn position(&self) -> Vec3<f32> {
+        self.carrier.position()
+    }
 
-        Some(carrier_hit)
+    fn volume(&self) -> f32 {
+        self.carrier.volume()
     }
 
-    fn normal_at(&self, _point: Vec3<f32>) -> Vec3<f32> {
-        let mut r = rand::thread_rng();
-        let nrml = UnitSphere.sample(&mut r);
-        Vec3::from(nrml)
+    fn bounds(&self) -> Option<Aabb> {
+        self.carrier.bounds()
     }
 
-    fn material(&self) -> Material {
-        Material {
-            albedo: Rgb::new(0.0, 0.0, 0.0),
-            emittance: Rgb::new(0.0, 0.0, 0.0),
-            brdf: BRDF::Glossy,
+    fn is_medium(&self) -> bool {
+        true
+    }
+
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        max_distance: f32,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Option<f32> {
+        let transmittance = match self.extent(ray) {
+            Some((t_min, t_max)) if t_min < max_distance => {
+                self.medium
+                    .transmittance(ray, t_min, t_max.min(max_distance), rng)
+            }
+            _ => 1.0,
+        };
+        Some(transmittance)
+    }
+}
+
+/// A medium filling the space around the objects, where the
+/// camera is. Unlike volumes, it isn't part of the scene
+/// objects: the integrator only looks for collisions with it
+/// along the parts of paths that are outside of the objects.
+pub struct Fog {
+    medium: Box<dyn Medium>,
+    material: Material,
+}
+
+impl Fog {
+    /// The albedo and anisotropy are as for volumes.
+    pub fn new(medium: Box<dyn Medium>, albedo: Rgb<f32>, anisotropy: f32) -> Fog {
+        Fog {
+            medium,
+            material: medium_material(albedo, anisotropy),
         }
     }
+}
+
+impl Shape for Fog {
+    fn intersects<'a>(&'a self, _ray: &'a Ray) -> Option<RayHit<'a>> {
+        None
+    }
+
+    fn sample_collision<'a>(
+        &'a self,
+        ray: &'a Ray,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Option<RayHit<'a>> {
+        let t = self.medium.sample_distance(ray, 0.0, f32::INFINITY, rng)?;
+
+        Some(RayHit::new(
+            ray,
+            t,
+            self,
+            &self.material,
+            -ray.direction,
+            Vec2::zero(),
+        ))
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
 
     fn position(&self) -> Vec3<f32> {
-        self.carrier.position()
+        Vec3::zero()
     }
 
     fn volume(&self) -> f32 {
-        self.carrier.volume()
+        f32::INFINITY
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn is_medium(&self) -> bool {
+        true
+    }
+
+    fn transmittance(
+        &self,
+        ray: &Ray,
+        max_distance: f32,
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Option<f32> {
+        Some(self.medium.transmittance(ray, 0.0, max_distance, rng))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::tracer::sampling::{random_square, uniform_sphere, uniform_sphere_pdf};
+
+    const SAMPLES: usize = 100_000;
+
+    /// The mean of the values, and its standard error.
+    fn mean(values: impl Iterator<Item = f32>) -> (f64, f64) {
+        let values: Vec<f64> = values.map(f64::from).collect();
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (mean, (variance / n).sqrt())
+    }
+
+    /// Checks that the estimate is within four standard
+    /// errors of the expected value.
+    fn assert_estimates(values: impl Iterator<Item = f32>, expected: f64) {
+        let (mean, error) = mean(values);
+        assert!(
+            (mean - expected).abs() < 4.0 * error + 1e-4,
+            "{} estimates {}",
+            mean,
+            expected
+        );
+    }
+
+    fn ray_along_x() -> Ray {
+        Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::unit_x(),
+        }
+    }
+
+    #[test]
+    fn homogeneous_mean_free_path() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let medium = HomogeneousMedium { extinction: 2.5 };
+        let ray = ray_along_x();
+
+        let distances = (0..SAMPLES).map(|_| {
+            medium
+                .sample_distance(&ray, 0.0, f32::INFINITY, &mut rng)
+                .unwrap()
+        });
+        assert_estimates(distances, 1.0 / 2.5);
+    }
+
+    #[test]
+    fn constant_grid_tracking() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let bounds = Aabb::new(Vec3::broadcast(-1.0), Vec3::broadcast(4.0));
+        let medium = GridMedium {
+            density: Grid::from_fn(bounds, Vec3::broadcast(20), |_| 0.5),
+            extinction: 1.5,
+        };
+        let ray = ray_along_x();
+        let (t_min, t_max) = (0.5, 2.5);
+        let expected = (-0.5f64 * 1.5 * 2.0).exp();
+
+        // Ratio tracking
+        let estimates = (0..SAMPLES).map(|_| medium.transmittance(&ray, t_min, t_max, &mut rng));
+        assert_estimates(estimates, expected);
+
+        // Delta tracking, which gets through as often
+        let escapes =
+            (0..SAMPLES).map(
+                |_| match medium.sample_distance(&ray, t_min, t_max, &mut rng) {
+                    Some(t) => {
+                        assert!(t >= t_min && t < t_max, "collision at {}", t);
+                        0.0
+                    }
+                    None => 1.0,
+                },
+            );
+        assert_estimates(escapes, expected);
+    }
+
+    #[test]
+    fn exponential_inverts_the_optical_depth() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let medium = ExponentialMedium {
+            extinction: 0.8,
+            height: 0.5,
+            falloff: 0.7,
+        };
+
+        // Down into the medium, along it, and up out of it,
+        // where collisions may never happen
+        for &direction in &[Vec3::new(1.0, -1.0, 0.0), Vec3::unit_x(), Vec3::unit_y()] {
+            let ray = Ray {
+                origin: Vec3::new(0.0, 1.0, 0.0),
+                direction: direction.normalized(),
+            };
+            let distances: Vec<_> = (0..SAMPLES)
+                .map(|_| medium.sample_distance(&ray, 0.25, f32::INFINITY, &mut rng))
+                .collect();
+
+            // Collisions happen before `t` as often as the
+            // light doesn't get there
+            for &t in &[0.5, 1.0, 2.0, 4.0, f32::INFINITY] {
+                let collided = distances.iter().map(|d| match d {
+                    Some(d) if *d < t => 1.0,
+                    _ => 0.0,
+                });
+                let transmittance = medium.transmittance(&ray, 0.25, t, &mut rng);
+                assert_estimates(collided, 1.0 - f64::from(transmittance));
+            }
+        }
+    }
+
+    #[test]
+    fn henyey_greenstein_is_normalized() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let wo = Vec3::new(0.3, -0.4, 0.5).normalized();
+
+        for &g in &[-0.8, -0.3, 0.0, 0.5, 0.8] {
+            let phase = HenyeyGreenstein { g };
+            let estimates = (0..SAMPLES).map(|_| {
+                let wi = uniform_sphere(random_square(&mut rng));
+                phase.pdf(wo, wi) / uniform_sphere_pdf()
+            });
+            assert_estimates(estimates, 1.0);
+
+            // The sampled directions have the density they report
+            for _ in 0..100 {
+                let sample = phase.sample(wo, random_square(&mut rng)).unwrap();
+                let pdf = phase.pdf(wo, sample.wi);
+                assert!((sample.pdf - pdf).abs() <= 1e-3 * pdf, "g = {}", g);
+            }
+        }
     }
 }
@@ -2,19 +2,28 @@ use vek::vec::repr_c::{rgb::Rgb, vec2::Vec2, vec3::Vec3};
 
 use super::gl_shader::GlShader;
 use super::gl_texture::GlTexture;
+use super::output::{Framebuffer, SaveOptions};
+use super::tonemap::ToneMap;
 
 type Receiver = std::sync::mpsc::Receiver<(Vec2<usize>, Rgb<f32>)>;
 
 /// A simple OpenGL-based realtime visualizer
-pub struct Visualizer {
-    receiver: Receiver,
-    w: usize,
-    h: usize,
-    event_loop: glutin::event_loop::EventLoop<()>,
-}
+pub struct Visualizer;
 
 impl Visualizer {
-    pub fn new(mut receiver: Receiver, w: usize, h: usize) -> Visualizer {
+    /// Opens the window and shows the pixels as they arrive
+    /// through the receiver. Only returns when the window is closed.
+    ///
+    /// The tone map is applied in the fragment shader. If an
+    /// output path is given, the image is also saved there once
+    /// every pixel has arrived.
+    pub fn run(
+        mut receiver: Receiver,
+        w: usize,
+        h: usize,
+        tonemap: ToneMap,
+        mut output: Option<(String, SaveOptions)>,
+    ) -> ! {
         let event_loop = glutin::event_loop::EventLoop::new();
 
         let builder = glutin::window::WindowBuilder::new()
@@ -35,7 +44,7 @@ impl Visualizer {
             let mut pos_vbo = 0 as gl::types::GLuint;
             let mut uvs_vbo = 0 as gl::types::GLuint;
 
-            let positions = vec![
+            let positions = [
                 Vec3::new(1.0f32, 1.0f32, 0.0f32),   // top right
                 Vec3::new(-1.0f32, 1.0f32, 0.0f32),  // top left
                 Vec3::new(-1.0f32, -1.0f32, 0.0f32), // bottom left
@@ -44,7 +53,7 @@ impl Visualizer {
                 Vec3::new(1.0f32, 1.0f32, 0.0f32),   // top right
             ];
 
-            let uvs = vec![
+            let uvs = [
                 Vec2::new(0.0f32, 0.0f32),
                 Vec2::new(1.0f32, 0.0f32),
                 Vec2::new(1.0f32, 1.0f32),
@@ -85,11 +94,13 @@ impl Visualizer {
         let tex = GlTexture::new(w, h, None);
 
         let shader = GlShader::new(vec![
-            Box::new((gl::VERTEX_SHADER, "res/show_texture.vs.glsl".to_string())),
-            Box::new((gl::FRAGMENT_SHADER, "res/show_texture.fs.glsl".to_string())),
+            (gl::VERTEX_SHADER, "res/show_texture.vs.glsl".to_string()),
+            (gl::FRAGMENT_SHADER, "res/show_texture.fs.glsl".to_string()),
         ])
         .unwrap();
 
+        let mut fb = Framebuffer::new(w, h);
+
         event_loop.run(move |evt, _, control_flow| {
             *control_flow = glutin::event_loop::ControlFlow::Poll;
 
@@ -99,32 +110,43 @@ impl Visualizer {
                         *control_flow = glutin::event_loop::ControlFlow::Exit
                     }
                     glutin::event::WindowEvent::RedrawRequested => {
-                        Visualizer::show_image(vao, &tex, &shader, &mut receiver);
+                        Visualizer::show_image(
+                            vao,
+                            &tex,
+                            &shader,
+                            &tonemap,
+                            &mut receiver,
+                            &mut fb,
+                        );
                         ctx.swap_buffers().unwrap();
                     }
-                    _ => *control_flow = { glutin::event_loop::ControlFlow::Poll },
+                    _ => *control_flow = glutin::event_loop::ControlFlow::Poll,
                 },
                 _ => {
-                    Visualizer::show_image(vao, &tex, &shader, &mut receiver);
+                    Visualizer::show_image(vao, &tex, &shader, &tonemap, &mut receiver, &mut fb);
                     ctx.swap_buffers().unwrap();
                     *control_flow = glutin::event_loop::ControlFlow::Poll;
                 }
             }
-        });
 
-        Visualizer {
-            receiver,
-            w,
-            h,
-            event_loop,
-        }
+            if fb.is_complete() {
+                if let Some((path, options)) = output.take() {
+                    match fb.save(&path, &options) {
+                        Ok(()) => println!("Saved {}", path),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+            }
+        })
     }
 
     fn show_image(
         vao: gl::types::GLuint,
         tex: &GlTexture,
         shader: &GlShader,
+        tonemap: &ToneMap,
         receiver: &mut Receiver,
+        fb: &mut Framebuffer,
     ) {
         unsafe {
             gl::Clear(gl::COLOR_BUFFER_BIT);
@@ -136,10 +158,14 @@ impl Visualizer {
 
         receiver.try_iter().for_each(|(pos, rgb)| {
             tex.set_pixel((pos.x, pos.y), rgb);
+            fb.set_pixel(pos, rgb);
         });
 
         shader.bind();
         shader.uniform_texture("_Tex".to_string(), tex, 0);
+        shader.uniform_float("_Exposure".to_string(), tonemap.exposure);
+        shader.uniform_int("_Operator".to_string(), tonemap.operator.shader_id());
+        shader.uniform_int("_Srgb".to_string(), tonemap.srgb as i32);
 
         unsafe {
             gl::BindVertexArray(vao);
@@ -0,0 +1,160 @@
+use vek::vec::Vec3;
+
+use crate::tracer::bvh::Aabb;
+use crate::tracer::grid::Grid;
+
+/// The size of the header of a grid file, in bytes.
+const HEADER: usize = 48;
+
+/// Loads a grid from a file in the `.vol` format of Mitsuba,
+/// as exported from simulations by Blender and other tools.
+/// All numbers are little endian:
+///
+/// - the bytes `VOL` and the version, 3
+/// - an `i32` encoding: 1 for `f32` values, 3 for `u8`
+///   values, mapped from 0..255 to 0..1
+/// - the number of values along x, y and z, each an `i32`
+/// - the number of channels, an `i32`, which must be 1
+/// - the bounding box, the minimum then the maximum corner,
+///   as six `f32`
+/// - the values, with x varying fastest, then y, then z
+///
+/// The values are at the corners of the cells that split
+/// the bounding box, so the grid extends half a cell past it.
+pub fn load(path: &str) -> Result<Grid, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Couldnt open {}: {}", path, e))?;
+    parse(&bytes, path)
+}
+
+/// Decodes the contents of a grid file, see `load`. Errors
+/// name the file by `path`.
+fn parse(bytes: &[u8], path: &str) -> Result<Grid, String> {
+    let err = |msg: &str| format!("{}: {}", path, msg);
+
+    if bytes.len() < HEADER || &bytes[..3] != b"VOL" {
+        return Err(err("not a .vol grid"));
+    }
+    if bytes[3] != 3 {
+        return Err(err(&format!("unsupported version {}", bytes[3])));
+    }
+
+    let word = |i: usize| {
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&bytes[4 + 4 * i..8 + 4 * i]);
+        word
+    };
+    let int = |i: usize| i32::from_le_bytes(word(i));
+    let float = |i: usize| f32::from_le_bytes(word(i));
+
+    let encoding = int(0);
+    let size = Vec3::new(int(1), int(2), int(3));
+    if size.iter().any(|&s| s <= 0) {
+        return Err(err("the grid is empty"));
+    }
+    let size = size.map(|s| s as usize);
+    if int(4) != 1 {
+        return Err(err(&format!("densities have one channel, not {}", int(4))));
+    }
+    let min = Vec3::new(float(5), float(6), float(7));
+    let max = Vec3::new(float(8), float(9), float(10));
+
+    let data = &bytes[HEADER..];
+    let count = size.product();
+    let values: Vec<f32> = match encoding {
+        1 if data.len() >= count * 4 => data
+            .chunks_exact(4)
+            .take(count)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]).max(0.0))
+            .collect(),
+        3 if data.len() >= count => data[..count]
+            .iter()
+            .map(|&b| f32::from(b) / 255.0)
+            .collect(),
+        1 | 3 => return Err(err("the file is truncated")),
+        other => return Err(err(&format!("unsupported encoding {}", other))),
+    };
+
+    let cell = (max - min) / size.map(|s| (s.max(2) - 1) as f32);
+    let bounds = Aabb::new(min - cell * 0.5, max + cell * 0.5);
+
+    Ok(Grid::from_values(bounds, size, &values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The values at the corners of a 3x2x2 grid over [0, 2]x[0, 1]x[0, 1].
+    const VALUES: [u8; 12] = [0, 51, 102, 153, 204, 255, 255, 0, 51, 0, 102, 0];
+
+    fn file(version: u8, encoding: i32, channels: i32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(version);
+        for &int in &[encoding, 3, 2, 2, channels] {
+            bytes.extend_from_slice(&int.to_le_bytes());
+        }
+        for &float in &[0f32, 0.0, 0.0, 2.0, 1.0, 1.0] {
+            bytes.extend_from_slice(&float.to_le_bytes());
+        }
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn floats() -> Vec<u8> {
+        VALUES
+            .iter()
+            .flat_map(|&v| (f32::from(v) / 255.0).to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn assert_values(grid: &Grid) {
+        for (i, &value) in VALUES.iter().enumerate() {
+            let corner = Vec3::new(i % 3, i / 3 % 2, i / 6).map(|c| c as f32);
+            let found = grid.lookup(corner);
+            let expected = f32::from(value) / 255.0;
+            assert!((found - expected).abs() < 1e-5, "{} at {:?}", found, corner);
+        }
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match parse(bytes, "smoke.vol") {
+            Ok(_) => panic!("the grid should not load"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn reads_bytes() {
+        assert_values(&parse(&file(3, 3, 1, &VALUES), "smoke.vol").unwrap());
+    }
+
+    #[test]
+    fn reads_floats() {
+        assert_values(&parse(&file(3, 1, 1, &floats()), "smoke.vol").unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert_eq!(error(b"VOL"), "smoke.vol: not a .vol grid");
+        assert_eq!(
+            error(&file(3, 3, 1, &VALUES[..11])),
+            "smoke.vol: the file is truncated"
+        );
+        assert_eq!(
+            error(&file(3, 1, 1, &floats()[..44])),
+            "smoke.vol: the file is truncated"
+        );
+        assert_eq!(
+            error(&file(2, 3, 1, &VALUES)),
+            "smoke.vol: unsupported version 2"
+        );
+        assert_eq!(
+            error(&file(3, 3, 3, &VALUES)),
+            "smoke.vol: densities have one channel, not 3"
+        );
+        assert_eq!(
+            error(&file(3, 2, 1, &VALUES)),
+            "smoke.vol: unsupported encoding 2"
+        );
+    }
+}